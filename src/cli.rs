@@ -46,7 +46,7 @@ fn parse_path(path: &str) -> Result<PathBuf, std::io::Error> {
     PathBuf::from(path).canonicalize()
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, author, about)]
 pub struct Cli {
     /// Logging output filters; comma-separated
@@ -86,6 +86,8 @@ pub struct Cli {
     )]
     pub create_dirs: DirectoryCreation,
     /// Path to the configuration file; must exist if specified.
+    ///
+    /// Defaults to `${config_dir}/config.toml`, if it exists; otherwise, default settings are used.
     #[arg(short, long, value_parser = parse_path, env = "MELIA_CONFIG")]
     pub config: Option<PathBuf>,
     /// Subcommand
@@ -97,7 +99,7 @@ pub enum AddressInput {
     Url(url::Url),
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 #[command()]
 pub enum Command {
     /// Run the web server daemon [default]
@@ -130,6 +132,13 @@ pub enum Command {
         #[command(subcommand)]
         command: Option<CtlCommand>,
     },
+    /// Manage configuration files
+    #[command()]
+    Config {
+        /// Subcommand
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
 }
 
 impl Default for Command {
@@ -140,13 +149,51 @@ impl Default for Command {
     }
 }
 
-#[derive(Subcommand, Debug, Default)]
+#[derive(Subcommand, Debug, Default, Clone)]
 #[command()]
 pub enum CtlCommand {
     /// Print the current configuration settings
     #[command()]
     #[default]
     PrintCfg,
+    /// Reload the configuration from disk; if the new configuration is invalid, the current
+    /// configuration is kept
+    #[command()]
+    Reload,
+    /// Print the health of each upstream group's members
+    #[command()]
+    Upstreams,
+    /// Publish an event to a server-sent event channel, printing its ID
+    #[command()]
+    Publish {
+        /// The channel's name, as in `[channels.<name>]`
+        channel: String,
+        /// The event's data; read from stdin if omitted
+        data: Option<String>,
+        /// The event's type, if not `message`
+        #[arg(short, long)]
+        event: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[command()]
+pub enum ConfigCommand {
+    /// Check the configuration for problems, such as unreachable routes
+    #[command()]
+    Check,
+    /// Upgrade the configuration file (and any included fragments) to the current layout, in
+    /// place; the originals are backed up to `${state_dir}/config-backups`
+    #[command()]
+    Migrate {
+        /// Print the migrated files instead of writing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Hash a password, read from the first line of stdin, for use as an IndieAuth route's
+    /// `password_hash`
+    #[command()]
+    HashPassword,
 }
 
 impl Cli {
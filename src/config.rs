@@ -1,13 +1,20 @@
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     net::{IpAddr, SocketAddr},
     os::unix,
     path::{Path, PathBuf},
 };
 use url::Url;
 
+pub mod channels;
 mod consts;
 pub use consts::*;
+pub mod hosts;
+pub mod migrate;
+pub mod routes;
+pub mod rules;
+pub mod upstreams;
 
 #[derive(Debug, thiserror::Error)]
 pub enum ConfigErrorVariant {
@@ -15,10 +22,35 @@ pub enum ConfigErrorVariant {
     AccessFailed(#[from] std::io::Error),
     #[error("TOML error in config file : {0:?}")]
     Toml(#[from] toml::de::Error),
+    #[error("TOML syntax error in config file : {0:?}")]
+    TomlSyntax(#[from] toml_edit::TomlError),
     #[error("failed to parse URL: {0:?}")]
     Url(#[from] url::ParseError),
     #[error("URL host is invalid")]
     InvalidUrlHost,
+    #[error("unsupported URL scheme: {0}")]
+    UnsupportedScheme(String),
+    #[error("unrecognized listener option: {0}")]
+    UnsupportedListenOption(String),
+    #[error("invalid unix socket address: {0}")]
+    InvalidUnixSocket(&'static str),
+    #[error("included configuration fragments may not include other files")]
+    NestedInclude,
+    #[error("`version` must be a non-negative integer")]
+    InvalidVersion,
+    #[error(
+        "configuration version {0} is newer than this version of melia supports ({current})",
+        current = migrate::CURRENT_VERSION
+    )]
+    UnsupportedVersion(u32),
+    #[error("`version` may only be set in the root configuration file")]
+    FragmentVersion,
+    #[error("failed to read PEM file: {0}")]
+    Pem(#[from] rustls::pki_types::pem::Error),
+    #[error("invalid certificate or key: {0}")]
+    Tls(#[from] rustls::Error),
+    #[error("invalid redirects file: {0}")]
+    Redirects(#[from] rules::RedirectsError),
 }
 
 #[derive(thiserror::Error)]
@@ -65,40 +97,339 @@ impl<'data> ConfigError<'data> {
     }
 }
 
-#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
+    /// The layout version of this configuration; see [migrate].
+    pub version: u32,
+    /// Additional configuration fragments to merge into this one, relative to the directory
+    /// containing the including file.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
     pub directories: Directories,
     pub listen: Listen,
     pub server: Server,
+    pub compression: Compression,
+    pub https_redirect: HttpsRedirect,
+    pub gemini: Gemini,
+    pub gopher: Gopher,
+    pub finger: Finger,
+    pub routes: routes::RouteTable,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<hosts::Host>,
+    /// Named groups of upstream servers, for proxy routes.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub upstreams: BTreeMap<String, upstreams::UpstreamGroup>,
+    /// Named server-sent event channels, for events routes.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub channels: BTreeMap<String, channels::Channel>,
+    /// Variables available to templates as `site`; see [routes::Templates].
+    #[serde(skip_serializing_if = "toml::Table::is_empty")]
+    pub site: toml::Table,
+    /// Redirect & rewrite rules, applied before routing; see [rules].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<rules::Rule>,
+    /// A Netlify-style `_redirects` file, relative to the directory containing the root
+    /// configuration file, whose rules apply after those in `rules`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirects: Option<PathBuf>,
+    /// The rules loaded from `redirects`.
+    #[serde(skip)]
+    pub redirect_rules: Vec<rules::Rule>,
+    /// Every file read while loading this configuration, starting with the root file.
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: migrate::CURRENT_VERSION,
+            include: Vec::new(),
+            directories: Directories::default(),
+            listen: Listen::default(),
+            server: Server::default(),
+            compression: Compression::default(),
+            https_redirect: HttpsRedirect::default(),
+            gemini: Gemini::default(),
+            gopher: Gopher::default(),
+            finger: Finger::default(),
+            routes: routes::RouteTable::default(),
+            hosts: Vec::new(),
+            upstreams: BTreeMap::new(),
+            channels: BTreeMap::new(),
+            site: toml::Table::new(),
+            rules: Vec::new(),
+            redirects: None,
+            redirect_rules: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
 }
 
 impl Config {
+    /// Load the configuration file at `path`, along with any fragments it includes, upgrading them
+    /// to the current layout if necessary.
     pub fn from_path<'path>(
         path: impl AsRef<Path> + Clone + 'path,
     ) -> Result<Self, ConfigError<'path>> {
-        toml::from_str::<Self>(
-            &std::fs::read_to_string(path.as_ref())
+        fn into_table(doc: toml_edit::DocumentMut) -> Result<toml::Table, ConfigErrorVariant> {
+            Ok(toml::from_str::<toml::Table>(&doc.to_string())?)
+        }
+        fn log_warnings(path: &Path, warnings: Vec<String>) {
+            for warning in warnings {
+                tracing::warn!(?path, "{warning}");
+            }
+        }
+
+        let (mut table, version) = migrate::read_document(path.as_ref())
+            .and_then(|mut doc| {
+                let (version, warnings) = migrate::migrate_root(&mut doc)?;
+                log_warnings(path.as_ref(), warnings);
+                Ok((into_table(doc)?, version))
+            })
+            .map_err(|e| ConfigError::new(path.clone(), e))?;
+        let mut sources = vec![path.as_ref().to_owned()];
+
+        let includes = match table.get("include") {
+            Some(include) => include
+                .clone()
+                .try_into::<Vec<PathBuf>>()
                 .map_err(|e| ConfigError::new(path.clone(), e))?,
-        )
-        .map_err(|e| ConfigError::new(path, e))
+            None => Vec::new(),
+        };
+        let base = path.as_ref().parent().unwrap_or(Path::new(".")).to_owned();
+        for include in includes {
+            let include = base.join(include);
+            let fragment = migrate::read_document(&include)
+                .and_then(|mut doc| {
+                    log_warnings(&include, migrate::migrate_fragment(&mut doc, version)?);
+                    into_table(doc)
+                })
+                .map_err(|e| ConfigError::new(include.clone(), e))?;
+            if fragment.contains_key("include") {
+                return Err(ConfigError::new(include, ConfigErrorVariant::NestedInclude));
+            }
+            merge_tables(&mut table, fragment);
+            sources.push(include);
+        }
+
+        let mut res = table
+            .try_into::<Self>()
+            .map_err(|e| ConfigError::new(path, e))?;
+        if let Some(redirects) = &res.redirects {
+            let redirects = base.join(redirects);
+            res.redirect_rules = std::fs::read_to_string(&redirects)
+                .map_err(ConfigErrorVariant::from)
+                .and_then(|text| Ok(rules::parse_redirects(&text)?))
+                .map_err(|e| ConfigError::new(redirects.clone(), e))?;
+            sources.push(redirects);
+        }
+        res.sources = sources;
+        Ok(res)
     }
 
-    pub fn from_args(args: &crate::cli::Cli) -> Result<Self, ConfigError> {
-        let cfg_path = args
-            .config
-            .clone()
-            .or_else(|| args.config_dir.as_ref().map(|d| d.join("config.toml")))
-            .unwrap();
+    /// Every redirect & rewrite rule, in the order they're applied.
+    pub fn rules(&self) -> impl Iterator<Item = &rules::Rule> {
+        self.rules.iter().chain(&self.redirect_rules)
+    }
 
-        let mut res = Self::from_path(cfg_path)?;
+    /// Load the configuration file specified by `args`, then apply overrides from `args`.
+    ///
+    /// If no file was explicitly specified and `${config_dir}/config.toml` doesn't exist, the
+    /// default configuration is used instead.
+    pub fn from_args(args: &crate::cli::Cli) -> Result<Self, ConfigError<'static>> {
+        let mut res = match args.config.clone() {
+            Some(cfg_path) => Self::from_path(cfg_path)?,
+            None => {
+                let cfg_path = args
+                    .config_dir
+                    .clone()
+                    .unwrap_or_else(|| Directories::default().configuration)
+                    .join("config.toml");
+                if cfg_path
+                    .try_exists()
+                    .map_err(|e| ConfigError::new(cfg_path.clone(), e))?
+                {
+                    Self::from_path(cfg_path)?
+                } else {
+                    tracing::info!(path = ?cfg_path, "no configuration file found; using defaults");
+                    Self {
+                        // so that we notice if it's created later
+                        sources: vec![cfg_path],
+                        ..Default::default()
+                    }
+                }
+            }
+        };
         res.directories.overwrite_with_cli(args);
         if let Some(crate::cli::Command::Daemon { addresses }) = args.command.as_ref() {
-            res.listen.extend_from_urls(addresses.iter().cloned());
+            res.listen.extend_from_urls(addresses.iter().cloned())?;
         }
 
         Ok(res)
     }
+
+    /// Find problems which don't prevent this configuration from loading, but probably aren't
+    /// intended.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = unreachable_routes(&self.routes, "");
+        for (i, host) in self.hosts.iter().enumerate() {
+            if host.names.is_empty() {
+                problems.push(format!("host {i} has no names"));
+            }
+            if !host.aliases.is_empty() && host.canonical_name().is_none() {
+                problems.push(format!(
+                    "host {i} has aliases, but no non-wildcard name to redirect them to"
+                ));
+            }
+            for name in host.names.iter().chain(&host.aliases) {
+                if let Some((j, _)) = hosts::find(&self.hosts, name).filter(|&(j, _)| j != i) {
+                    problems.push(format!(
+                        "host {i} name `{name}` is unreachable; it's served by host {j}"
+                    ));
+                }
+            }
+            if let Some(routes) = &host.routes {
+                problems.extend(unreachable_routes(routes, &format!("host {i} ")));
+            }
+        }
+        if !self.listen.gopher.is_empty() && self.gopher.root.is_none() {
+            problems.push("gopher listeners are configured, but `gopher.root` isn't".to_owned());
+        }
+        for user in self.finger.users.keys() {
+            if user.is_empty() || user.contains(|c: char| c.is_whitespace() || c == '@') {
+                problems.push(format!("finger user `{user}` can't be queried"));
+            }
+        }
+        for (name, group) in &self.upstreams {
+            if group.members.is_empty() {
+                problems.push(format!("upstream group `{name}` has no members"));
+            }
+            if group
+                .health_check
+                .as_ref()
+                .is_some_and(|h| h.interval_ms == 0)
+            {
+                problems.push(format!(
+                    "upstream group `{name}` has a health check interval of 0; it won't be checked"
+                ));
+            }
+        }
+        let tables = std::iter::once(&self.routes)
+            .chain(self.hosts.iter().filter_map(|host| host.routes.as_ref()));
+        for route in tables.flat_map(routes::RouteTable::iter_declared) {
+            if let routes::Handler::WebSocket(ws) = &route.handler {
+                if ws.endpoint.is_some() == ws.upstream.is_some() {
+                    problems.push(format!(
+                        "websocket route `{}` must have exactly one of `endpoint` or `upstream`",
+                        route.path.source()
+                    ));
+                }
+            }
+            if let routes::Handler::IndieAuth(auth) = &route.handler {
+                if !matches!(route.path, routes::PathPattern::Prefix(_)) {
+                    problems.push(format!(
+                        "indieauth route `{}` must have a prefix path pattern",
+                        route.path.source()
+                    ));
+                }
+                if auth.password_hash.is_empty() {
+                    problems.push(format!(
+                        "indieauth route `{}` has no `password_hash`, so no one may sign in",
+                        route.path.source()
+                    ));
+                } else if let Err(e) = argon2::PasswordHash::new(&auth.password_hash) {
+                    problems.push(format!(
+                        "indieauth route `{}` has an invalid `password_hash`: {e}",
+                        route.path.source()
+                    ));
+                }
+            }
+            let target = match &route.handler {
+                routes::Handler::Proxy(routes::Proxy { upstream, .. })
+                | routes::Handler::FastCgi(routes::Gateway { upstream, .. })
+                | routes::Handler::Scgi(routes::Gateway { upstream, .. })
+                | routes::Handler::Uwsgi(routes::Gateway { upstream, .. }) => upstream,
+                routes::Handler::WebSocket(routes::WebSocket {
+                    upstream: Some(upstream),
+                    ..
+                }) => upstream,
+                routes::Handler::Events(routes::Events {
+                    channel: Some(channel),
+                }) => {
+                    if !self.channels.contains_key(channel) {
+                        problems.push(format!(
+                            "route `{}` subscribes to undefined channel `{channel}`",
+                            route.path.source()
+                        ));
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if let upstreams::ProxyTarget::Group(group) = target {
+                if !self.upstreams.contains_key(group) {
+                    problems.push(format!(
+                        "route `{}` proxies to undefined upstream group `{group}`",
+                        route.path.source()
+                    ));
+                }
+            }
+        }
+        let error_pages = std::iter::once(&self.server.error_pages).chain(
+            self.hosts
+                .iter()
+                .filter_map(|host| host.error_pages.as_ref()),
+        );
+        for key in error_pages.flat_map(BTreeMap::keys) {
+            if !hosts::is_error_page_key(key) {
+                problems.push(format!(
+                    "error page key `{key}` isn't an error status (ex. `404`) or class (`4xx` or `5xx`)"
+                ));
+            }
+        }
+        for rule in self.rules() {
+            if rule.status.0 == hyper::StatusCode::OK && !rule.to.starts_with('/') {
+                problems.push(format!(
+                    "rule `{}` rewrites to `{}`, which isn't a path",
+                    rule.path.source(),
+                    rule.to
+                ));
+            }
+        }
+        problems
+    }
+}
+
+fn unreachable_routes(table: &routes::RouteTable, prefix: &str) -> Vec<String> {
+    let routes = table.iter_declared().collect::<Vec<_>>();
+    table
+        .unreachable()
+        .into_iter()
+        .map(|(shadowed, shadowing)| {
+            format!(
+                "{prefix}route {shadowed} (`{}`) is unreachable; every request it matches is matched first by route {shadowing} (`{}`)",
+                routes[shadowed].path.source(),
+                routes[shadowing].path.source()
+            )
+        })
+        .collect()
+}
+
+/// Merge `from` into `into`; tables are merged recursively, arrays are concatenated, and any other
+/// values in `from` replace those in `into`.
+fn merge_tables(into: &mut toml::Table, from: toml::Table) {
+    use toml::Value;
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(Value::Table(into)), Value::Table(from)) => merge_tables(into, from),
+            (Some(Value::Array(into)), Value::Array(from)) => into.extend(from),
+            (_, value) => {
+                into.insert(key, value);
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -119,7 +450,7 @@ struct ListenToml {
     // pub unix: Vec<unix::net::SocketAddr>,
 }
 
-#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(try_from = "Url", into = "Url")]
 pub struct UnixSocket {
     pub path: PathBuf,
@@ -128,6 +459,17 @@ pub struct UnixSocket {
     pub mode: u32,
 }
 
+impl Default for UnixSocket {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::new(),
+            user: None,
+            group: None,
+            mode: 0o660,
+        }
+    }
+}
+
 impl TryFrom<Url> for UnixSocket {
     type Error = &'static str;
 
@@ -135,69 +477,323 @@ impl TryFrom<Url> for UnixSocket {
         if addr.scheme() != "unix" {
             return Err("incorrect scheme");
         }
-        todo!()
+        if addr.path().is_empty() {
+            return Err("missing socket path");
+        }
+        let mut res = Self {
+            path: PathBuf::from(addr.path()),
+            ..Default::default()
+        };
+        for component in addr.query().unwrap_or_default().split(',') {
+            match component.split_once('=') {
+                Some(("user", user)) => res.user = Some(user.to_owned()),
+                Some(("group", group)) => res.group = Some(group.to_owned()),
+                Some(("mode", mode)) => {
+                    res.mode = u32::from_str_radix(mode, 8).map_err(|_| "invalid socket mode")?
+                }
+                None if component.is_empty() => {}
+                _ => return Err("unrecognized query component"),
+            }
+        }
+        Ok(res)
     }
 }
 
 impl From<UnixSocket> for Url {
     fn from(sock: UnixSocket) -> Self {
-        todo!()
+        let mut query = Vec::with_capacity(3);
+        if let Some(user) = sock.user {
+            query.push(format!("user={user}"));
+        }
+        if let Some(group) = sock.group {
+            query.push(format!("group={group}"));
+        }
+        query.push(format!("mode={:04o}", sock.mode));
+        Url::parse(&format!("unix:{}?{}", sock.path.display(), query.join(","))).unwrap()
     }
 }
 
 impl UnixSocket {
-    pub fn open(&self) -> unix::net::SocketAddr {
-        todo!()
+    /// Bind a listener at this socket's path, resolved relative to `runtime_dir`, and apply its
+    /// ownership & permissions.
+    pub fn bind(&self, runtime_dir: &Path) -> std::io::Result<tokio::net::UnixListener> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+        let path = runtime_dir.join(&self.path);
+        // clean up a socket left behind by a previous run
+        if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(&path)?;
+        }
+        let listener = unix::net::UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(self.mode))?;
+        if self.user.is_some() || self.group.is_some() {
+            unix::fs::chown(
+                &path,
+                self.user.as_deref().map(lookup_user).transpose()?,
+                self.group.as_deref().map(lookup_group).transpose()?,
+            )?;
+        }
+        tokio::net::UnixListener::from_std(listener)
     }
 }
 
-#[derive(Debug, Default, Clone, Deserialize, Serialize)]
-#[serde(from = "ListenToml", into = "ListenToml")]
+fn lookup_user(name: &str) -> std::io::Result<u32> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+    let c_name = std::ffi::CString::new(name)?;
+    match unsafe { libc::getpwnam(c_name.as_ptr()).as_ref() } {
+        Some(passwd) => Ok(passwd.pw_uid),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no such user: {name}"),
+        )),
+    }
+}
+
+fn lookup_group(name: &str) -> std::io::Result<u32> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let c_name = std::ffi::CString::new(name)?;
+    match unsafe { libc::getgrnam(c_name.as_ptr()).as_ref() } {
+        Some(group) => Ok(group.gr_gid),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no such group: {name}"),
+        )),
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "ListenToml", into = "ListenToml")]
 pub struct Listen {
     pub http: Vec<SocketAddr>,
     pub https: Vec<SocketAddr>,
+    /// Plain HTTP listeners which redirect every request to HTTPS, written as
+    /// `http://<address>?redirect`; see [HttpsRedirect].
+    pub redirect: Vec<SocketAddr>,
     pub unix: Vec<UnixSocket>,
+    /// Gemini listeners, written as `gemini://<address>`; see [Gemini].
+    pub gemini: Vec<SocketAddr>,
+    /// Gopher listeners, written as `gopher://<address>`; see [Gopher].
+    pub gopher: Vec<SocketAddr>,
+    /// Finger listeners, written as `finger://<address>`; see [Finger].
+    pub finger: Vec<SocketAddr>,
 }
 
-#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default)]
 pub struct Server {
+    /// The name of the default host, which serves requests for names not matched by any
+    /// `[[hosts]]` entry; see [hosts].
     pub domain: String,
+    /// The directory from which files are served by default.
+    pub root: Option<PathBuf>,
+    /// The certificate presented to clients which don't match any `[[hosts]]` entry with its own.
+    pub tls: Option<hosts::Tls>,
+    /// The `Strict-Transport-Security` policy for hosts which don't set their own.
+    pub hsts: Option<hosts::Hsts>,
+    /// Error pages for hosts which don't set their own; see [hosts::ErrorPages].
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub error_pages: hosts::ErrorPages,
+    /// Whether to watch the configuration file (and any included fragments) for changes, reloading
+    /// when they occur. Only read at startup.
+    pub watch_config: bool,
+    /// How long to wait for further changes before reloading, in milliseconds.
+    pub watch_debounce_ms: u64,
+    /// Development mode, in which templates & their data files are reloaded as soon as they
+    /// change, and template errors are shown in responses rather than only logged.
+    pub development: bool,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            domain: String::new(),
+            root: None,
+            tls: None,
+            hsts: None,
+            error_pages: BTreeMap::new(),
+            watch_config: false,
+            watch_debounce_ms: 250,
+            development: false,
+        }
+    }
+}
+
+/// On-the-fly response compression, with brotli, zstd, or gzip (whichever the client prefers).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Compression {
+    pub enabled: bool,
+    /// Only compress responses with one of these content types; `type/*` matches any subtype.
+    pub types: Vec<String>,
+    /// Don't compress responses shorter than this many bytes.
+    pub min_size: u64,
+    /// Whether to keep compressed copies of static files in `${cache}/compressed`, rather than
+    /// serving them uncompressed. The first request for a file is served uncompressed while its
+    /// copy is made.
+    pub cache: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            types: [
+                "text/*",
+                "application/javascript",
+                "application/json",
+                "application/ld+json",
+                "application/manifest+json",
+                "application/xml",
+                "application/atom+xml",
+                "application/rss+xml",
+                "application/wasm",
+                "image/svg+xml",
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+            min_size: 256,
+            cache: true,
+        }
+    }
+}
+
+/// How `http://<address>?redirect` listeners redirect requests to HTTPS.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpsRedirect {
+    /// The port to redirect to; defaults to that of the first `https` listener, or 443.
+    pub port: Option<u16>,
+    /// A directory from which to serve ACME HTTP-01 challenges
+    /// (`/.well-known/acme-challenge/<token>`) instead of redirecting them, for use with clients
+    /// like certbot's `--webroot` mode.
+    pub acme_challenges: Option<PathBuf>,
+    /// A path which is answered with `200 OK` instead of being redirected, for load balancers'
+    /// health checks.
+    pub health_path: Option<String>,
+}
+
+/// How `gemini://<address>` listeners serve capsules: `.gmi` files (and Markdown files,
+/// converted to gemtext) from each host's `capsule` directory. Certificates are self-signed, as
+/// is usual for Gemini's trust-on-first-use model, and kept in `${state_dir}/gemini`, so that they
+/// don't change between restarts.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Gemini {
+    /// The capsule served for hosts which don't set their own `capsule`.
+    pub root: Option<PathBuf>,
+    /// Whether to serve Markdown files as gemtext; `/post` is served by `post.gmi` or `post.md`.
+    pub markdown: bool,
+    /// Whether to list directories without an `index.gmi` (or `index.md`).
+    pub autoindex: bool,
+}
+
+impl Default for Gemini {
+    fn default() -> Self {
+        Self {
+            root: None,
+            markdown: true,
+            autoindex: false,
+        }
+    }
+}
+
+/// How `gopher://<address>` listeners serve a hole. Directories are served as menus, from their
+/// `gophermap` if they have one, or else listing their (non-hidden) entries.
+///
+/// In a `gophermap`, lines containing a tab are menu items (`<type><display>\t<selector>\t<host>\t<port>`);
+/// a missing host or port is filled in with this server's, and if the host is missing, a selector
+/// not starting with `/` (or `URL:`) is relative to the directory. A line which is just `*` lists the directory's entries,
+/// `.` ends the menu, and other lines are shown as text.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Gopher {
+    /// The directory served.
+    pub root: Option<PathBuf>,
+    /// The host name written in menus; defaults to `server.domain`, or else the address the
+    /// client connected to.
+    pub host: Option<String>,
+    /// The port written in menus, if clients connect through a different port than the listener's
+    /// (ex. behind NAT).
+    pub port: Option<u16>,
+}
+
+/// How `finger://<address>` listeners answer queries (ex. `/W alice`), with the plan file of the
+/// named user. Forwarding queries (`alice@example.com`) are refused.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Finger {
+    /// Plan files, by user name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub users: BTreeMap<String, PathBuf>,
+    /// Whether to answer a query without a user name with the list of users.
+    pub list_users: bool,
 }
 
-impl From<ListenToml> for Listen {
-    fn from(value: ListenToml) -> Self {
+impl TryFrom<ListenToml> for Listen {
+    type Error = ConfigErrorVariant;
+
+    fn try_from(value: ListenToml) -> Result<Self, Self::Error> {
         let mut res = Self {
             http: Vec::new(),
             https: Vec::new(),
+            redirect: Vec::new(),
             unix: Vec::new(),
+            gemini: Vec::new(),
+            gopher: Vec::new(),
+            finger: Vec::new(),
         };
-        res.extend_from_urls(value.addresses);
-        res
+        res.extend_from_urls(value.addresses)?;
+        Ok(res)
     }
 }
 
 impl Listen {
-    pub fn extend_from_urls(&mut self, urls: impl IntoIterator<Item = Url>) {
+    pub fn extend_from_urls(
+        &mut self,
+        urls: impl IntoIterator<Item = Url>,
+    ) -> Result<(), ConfigErrorVariant> {
         use url::Host;
-        fn addr_from_url(addr: &Url, default_port: u16) -> SocketAddr {
+        fn addr_from_url(addr: &Url, default_port: u16) -> Result<SocketAddr, ConfigErrorVariant> {
             let ip = match addr.host() {
                 Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
                 Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
-                Some(Host::Domain(_)) => panic!("http listener host must be an ip address"),
-                None => panic!("no address specified for http listener"),
+                // hosts in URLs with non-special schemes (ex. `gemini`) aren't parsed as IPv4
+                Some(Host::Domain(host)) => IpAddr::V4(
+                    host.parse()
+                        .map_err(|_| ConfigErrorVariant::InvalidUrlHost)?,
+                ),
+                // listener hosts must be ip addresses
+                None => return Err(ConfigErrorVariant::InvalidUrlHost),
             };
             let port = addr.port().unwrap_or(default_port);
-            SocketAddr::new(ip, port)
+            Ok(SocketAddr::new(ip, port))
         }
         for addr in urls {
             match addr.scheme() {
-                "http" => self.http.push(addr_from_url(&addr, 80)),
-                "https" => self.https.push(addr_from_url(&addr, 443)),
-                "unix" => self.unix.push(UnixSocket::try_from(addr).unwrap()),
-                _ => panic!("incorrect url scheme"),
+                "http" => match addr.query() {
+                    None => self.http.push(addr_from_url(&addr, 80)?),
+                    Some("redirect") => self.redirect.push(addr_from_url(&addr, 80)?),
+                    Some(query) => {
+                        return Err(ConfigErrorVariant::UnsupportedListenOption(
+                            query.to_owned(),
+                        ))
+                    }
+                },
+                "https" => self.https.push(addr_from_url(&addr, 443)?),
+                "gemini" => self.gemini.push(addr_from_url(&addr, 1965)?),
+                "gopher" => self.gopher.push(addr_from_url(&addr, 70)?),
+                "finger" => self.finger.push(addr_from_url(&addr, 79)?),
+                "unix" => self.unix.push(
+                    UnixSocket::try_from(addr).map_err(ConfigErrorVariant::InvalidUnixSocket)?,
+                ),
+                scheme => return Err(ConfigErrorVariant::UnsupportedScheme(scheme.to_owned())),
             }
         }
+        Ok(())
     }
 }
 
@@ -211,7 +807,15 @@ impl From<Listen> for ListenToml {
             Url::parse(&format!("{scheme}://{host}:{port}")).unwrap()
         }
         let mut res = ListenToml {
-            addresses: Vec::with_capacity(val.http.len() + val.https.len() + val.unix.len()),
+            addresses: Vec::with_capacity(
+                val.http.len()
+                    + val.https.len()
+                    + val.redirect.len()
+                    + val.unix.len()
+                    + val.gemini.len()
+                    + val.gopher.len()
+                    + val.finger.len(),
+            ),
         };
         for http in val.http {
             res.addresses.push(url_from_socketaddr("http", http));
@@ -219,9 +823,23 @@ impl From<Listen> for ListenToml {
         for https in val.https {
             res.addresses.push(url_from_socketaddr("https", https));
         }
+        for redirect in val.redirect {
+            let mut url = url_from_socketaddr("http", redirect);
+            url.set_query(Some("redirect"));
+            res.addresses.push(url);
+        }
         for unix in val.unix {
             res.addresses.push(unix.into());
         }
+        for gemini in val.gemini {
+            res.addresses.push(url_from_socketaddr("gemini", gemini));
+        }
+        for gopher in val.gopher {
+            res.addresses.push(url_from_socketaddr("gopher", gopher));
+        }
+        for finger in val.finger {
+            res.addresses.push(url_from_socketaddr("finger", finger));
+        }
         res
     }
 }
@@ -0,0 +1,27 @@
+//! Server-sent event channels, which clients subscribe to through `events` routes.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Channel {
+    /// How many recent events to keep for clients which reconnect with `Last-Event-ID`.
+    pub replay: usize,
+    /// How often to send idle subscribers a comment, in milliseconds, so that they (and any
+    /// proxies between us) don't give up on the connection. `0` disables heartbeats.
+    pub heartbeat_ms: u64,
+    /// Tokens which may publish events by `POST`ing them to the channel's route, with
+    /// `Authorization: Bearer <token>`. Events may always be published through the control API.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub publish_tokens: Vec<String>,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            replay: 100,
+            heartbeat_ms: 15_000,
+            publish_tokens: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Name-based virtual hosts.
+//!
+//! A request is served by the `[[hosts]]` entry with a name or alias exactly matching its host;
+//! failing that, by the entry with the most specific matching wildcard (`*.example.com`); and
+//! failing that, by the default host, which is the entry with a name equal to `server.domain`, or
+//! the top-level `root` & `routes` if there is no such entry.
+
+use super::{
+    routes::{host_matches, RouteTable},
+    Config,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Host {
+    /// Names served by this host; a leading `*.` matches any subdomain.
+    pub names: Vec<String>,
+    /// Names which are permanently redirected to this host's canonical name (the first of `names`
+    /// without a wildcard).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// The directory from which files are served by default; defaults to `server.root`.
+    pub root: Option<PathBuf>,
+    /// The directory served to Gemini clients; defaults to `gemini.root`.
+    pub capsule: Option<PathBuf>,
+    /// Defaults to the top-level routes.
+    pub routes: Option<RouteTable>,
+    pub tls: Option<Tls>,
+    /// Defaults to `server.hsts`.
+    pub hsts: Option<Hsts>,
+    /// Defaults to `server.error_pages`.
+    pub error_pages: Option<ErrorPages>,
+}
+
+/// Pages sent with error responses to clients which don't ask for JSON, keyed by status (`404`)
+/// or class (`4xx`, `5xx`); relative paths are resolved against the host's root. Within a page,
+/// `{{status}}`, `{{reason}}`, and `{{path}}` are replaced with the status code, its reason
+/// phrase, and the requested path.
+pub type ErrorPages = BTreeMap<String, PathBuf>;
+
+/// A certificate chain & private key, in PEM format.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A `Strict-Transport-Security` policy, sent with every response over HTTPS.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hsts {
+    /// How long clients should remember to only use HTTPS, in seconds.
+    pub max_age: u64,
+    pub include_subdomains: bool,
+    /// Consent to inclusion in browsers' preload lists, which requires `include_subdomains` and a
+    /// `max_age` of at least a year.
+    pub preload: bool,
+}
+
+impl Default for Hsts {
+    fn default() -> Self {
+        Self {
+            max_age: 31_536_000,
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+}
+
+impl Hsts {
+    pub fn header_value(&self) -> String {
+        let mut res = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            res.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            res.push_str("; preload");
+        }
+        res
+    }
+}
+
+impl Host {
+    /// The name to which aliases redirect.
+    pub fn canonical_name(&self) -> Option<&str> {
+        self.names
+            .iter()
+            .map(String::as_str)
+            .find(|n| !n.starts_with("*."))
+    }
+}
+
+/// Find the entry in `hosts` serving `name`, returning its index and whether `name` is one of its
+/// aliases.
+pub fn find(hosts: &[Host], name: &str) -> Option<(usize, bool)> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let patterns = || {
+        hosts.iter().enumerate().flat_map(|(i, host)| {
+            host.names
+                .iter()
+                .map(move |n| (i, false, n))
+                .chain(host.aliases.iter().map(move |n| (i, true, n)))
+        })
+    };
+    patterns()
+        .find(|(_, _, pattern)| !pattern.starts_with("*.") && pattern.eq_ignore_ascii_case(name))
+        .or_else(|| {
+            patterns()
+                .filter(|(_, _, pattern)| pattern.starts_with("*.") && host_matches(pattern, name))
+                // earlier entries win ties
+                .max_by(|(ia, _, a), (ib, _, b)| a.len().cmp(&b.len()).then(ib.cmp(ia)))
+        })
+        .map(|(i, alias, _)| (i, alias))
+}
+
+/// The site serving a request.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualHost<'cfg> {
+    /// The index of the `[[hosts]]` entry, or `None` for the top-level configuration.
+    pub index: Option<usize>,
+    pub root: Option<&'cfg Path>,
+    pub capsule: Option<&'cfg Path>,
+    pub routes: &'cfg RouteTable,
+    /// If the request named an alias, the name to redirect it to.
+    pub redirect_to: Option<&'cfg str>,
+    pub hsts: Option<&'cfg Hsts>,
+    pub error_pages: &'cfg ErrorPages,
+}
+
+impl VirtualHost<'_> {
+    /// The page configured for `status`, or else for its class.
+    pub fn error_page(&self, status: StatusCode) -> Option<PathBuf> {
+        let class = format!("{}xx", status.as_u16() / 100);
+        let page = self
+            .error_pages
+            .get(status.as_str())
+            .or_else(|| self.error_pages.get(&class))?;
+        Some(match self.root {
+            Some(root) => root.join(page),
+            None => page.clone(),
+        })
+    }
+}
+
+/// Whether `key` is a valid key for [ErrorPages].
+pub fn is_error_page_key(key: &str) -> bool {
+    matches!(key, "4xx" | "5xx")
+        || key
+            .parse::<u16>()
+            .is_ok_and(|status| (400..600).contains(&status))
+}
+
+impl Config {
+    /// The index of the default `[[hosts]]` entry, if there is one.
+    pub fn default_host(&self) -> Option<usize> {
+        self.hosts.iter().position(|host| {
+            host.names
+                .iter()
+                .any(|n| n.eq_ignore_ascii_case(&self.server.domain))
+        })
+    }
+
+    /// The site serving requests for `name` (or for no name at all).
+    pub fn virtual_host(&self, name: Option<&str>) -> VirtualHost<'_> {
+        let (index, alias) = match name.and_then(|name| find(&self.hosts, name)) {
+            Some((index, alias)) => (Some(index), alias),
+            None => (self.default_host(), false),
+        };
+        match index.map(|i| &self.hosts[i]) {
+            Some(host) => VirtualHost {
+                index,
+                root: host.root.as_deref().or(self.server.root.as_deref()),
+                capsule: host.capsule.as_deref().or(self.gemini.root.as_deref()),
+                routes: host.routes.as_ref().unwrap_or(&self.routes),
+                redirect_to: alias.then(|| host.canonical_name()).flatten(),
+                hsts: host.hsts.as_ref().or(self.server.hsts.as_ref()),
+                error_pages: host
+                    .error_pages
+                    .as_ref()
+                    .unwrap_or(&self.server.error_pages),
+            },
+            None => VirtualHost {
+                index: None,
+                root: self.server.root.as_deref(),
+                capsule: self.gemini.root.as_deref(),
+                routes: &self.routes,
+                redirect_to: None,
+                hsts: self.server.hsts.as_ref(),
+                error_pages: &self.server.error_pages,
+            },
+        }
+    }
+}
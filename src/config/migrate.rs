@@ -0,0 +1,299 @@
+//! Upgrades older configuration layouts to the current one.
+//!
+//! Migrations operate on [toml_edit] documents rather than deserialized values, so that files can be
+//! rewritten in place without losing comments or formatting.
+
+use super::{Config, ConfigError, ConfigErrorVariant};
+use std::path::Path;
+use toml_edit::DocumentMut;
+
+/// The configuration layout version understood by this build.
+pub const CURRENT_VERSION: u32 = 1;
+
+/// A single step in upgrading a configuration layout from one version to the next.
+struct Migration {
+    /// The version this migration upgrades from; it produces `from + 1`.
+    from: u32,
+    /// Rewrite the document, adding a deprecation warning for each change made.
+    apply: fn(&mut DocumentMut, &mut Vec<String>),
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // version 1 only introduced the `version` key
+    Migration {
+        from: 0,
+        apply: |_, _| {},
+    },
+];
+
+pub(super) fn read_document(path: &Path) -> Result<DocumentMut, ConfigErrorVariant> {
+    Ok(std::fs::read_to_string(path)?.parse::<DocumentMut>()?)
+}
+
+/// Apply every one of `migrations` from `version` onwards to `doc`.
+fn upgrade(
+    doc: &mut DocumentMut,
+    migrations: &[Migration],
+    version: u32,
+    warnings: &mut Vec<String>,
+) {
+    for migration in migrations.iter().filter(|m| m.from >= version) {
+        (migration.apply)(doc, warnings);
+    }
+}
+
+/// Upgrade a root configuration document to [CURRENT_VERSION], returning the version it was
+/// originally written for along with any deprecation warnings.
+pub fn migrate_root(doc: &mut DocumentMut) -> Result<(u32, Vec<String>), ConfigErrorVariant> {
+    upgrade_root(doc, MIGRATIONS, CURRENT_VERSION)
+}
+
+fn upgrade_root(
+    doc: &mut DocumentMut,
+    migrations: &[Migration],
+    current: u32,
+) -> Result<(u32, Vec<String>), ConfigErrorVariant> {
+    let mut warnings = Vec::new();
+    let version = match doc.get("version") {
+        Some(v) => v
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or(ConfigErrorVariant::InvalidVersion)?,
+        None => {
+            warnings.push("configuration has no `version` key; assuming version 0".to_owned());
+            0
+        }
+    };
+    if version > current {
+        return Err(ConfigErrorVariant::UnsupportedVersion(version));
+    }
+    if version < current {
+        warnings.push(format!(
+            "configuration version {version} is deprecated; run `melia config migrate` to upgrade to version {current}"
+        ));
+        upgrade(doc, migrations, version, &mut warnings);
+        doc["version"] = toml_edit::value(i64::from(current));
+    }
+    Ok((version, warnings))
+}
+
+/// Upgrade an included configuration fragment, which shares the version of the file including it.
+pub fn migrate_fragment(
+    doc: &mut DocumentMut,
+    root_version: u32,
+) -> Result<Vec<String>, ConfigErrorVariant> {
+    upgrade_fragment(doc, MIGRATIONS, root_version)
+}
+
+fn upgrade_fragment(
+    doc: &mut DocumentMut,
+    migrations: &[Migration],
+    root_version: u32,
+) -> Result<Vec<String>, ConfigErrorVariant> {
+    if doc.contains_key("version") {
+        return Err(ConfigErrorVariant::FragmentVersion);
+    }
+    let mut warnings = Vec::new();
+    upgrade(doc, migrations, root_version, &mut warnings);
+    Ok(warnings)
+}
+
+/// Rewrite the files `cfg` was loaded from using the current layout, backing up the originals to
+/// `${state}/config-backups`. If `dry_run`, the migrated files are printed instead.
+pub fn migrate_files(cfg: &Config, dry_run: bool) -> Result<(), ConfigError<'static>> {
+    let Some((root, fragments)) = cfg.sources.split_first() else {
+        return Ok(());
+    };
+    if !root.exists() {
+        println!("no configuration file found at {}", root.display());
+        return Ok(());
+    }
+
+    let mut root_doc = read_document(root).map_err(|e| ConfigError::new(root.clone(), e))?;
+    let original = root_doc.to_string();
+    let (version, _) =
+        migrate_root(&mut root_doc).map_err(|e| ConfigError::new(root.clone(), e))?;
+    let mut files = vec![(root, original, root_doc)];
+    for fragment in fragments {
+        let mut doc = read_document(fragment).map_err(|e| ConfigError::new(fragment.clone(), e))?;
+        let original = doc.to_string();
+        migrate_fragment(&mut doc, version).map_err(|e| ConfigError::new(fragment.clone(), e))?;
+        files.push((fragment, original, doc));
+    }
+
+    let backups = cfg.directories.state.join("config-backups");
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    for (path, original, doc) in files {
+        let migrated = doc.to_string();
+        if migrated == original {
+            println!("{}: already up to date", path.display());
+        } else if dry_run {
+            println!("# {}\n{migrated}", path.display());
+        } else {
+            let backup = backups.join(format!(
+                "{}.{stamp}",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ));
+            std::fs::create_dir_all(&backups)
+                .and_then(|_| std::fs::copy(path, &backup))
+                .map_err(|e| ConfigError::new(backup.clone(), e))?;
+            std::fs::write(path, migrated).map_err(|e| ConfigError::new(path.clone(), e))?;
+            println!(
+                "{}: migrated to version {CURRENT_VERSION} (backup at {})",
+                path.display(),
+                backup.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for a real layout change: version 1's `[server] name` becomes `domain` in 2.
+    const RENAME: &[Migration] = &[
+        Migration {
+            from: 0,
+            apply: |_, _| {},
+        },
+        Migration {
+            from: 1,
+            apply: |doc, warnings| {
+                let Some(server) = doc.get_mut("server").and_then(|s| s.as_table_like_mut()) else {
+                    return;
+                };
+                if let Some(name) = server.remove("name") {
+                    server.insert("domain", name);
+                    warnings.push("`server.name` is now `server.domain`".to_owned());
+                }
+            },
+        },
+    ];
+
+    fn doc(text: &str) -> DocumentMut {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn current_root_is_untouched() {
+        let text = "version = 1\n[server]\ndomain = \"a.test\" # ours\n";
+        let mut root = doc(text);
+        let (version, warnings) = migrate_root(&mut root).unwrap();
+        assert_eq!(version, CURRENT_VERSION);
+        assert!(warnings.is_empty());
+        assert_eq!(root.to_string(), text);
+    }
+
+    #[test]
+    fn unversioned_root_is_upgraded() {
+        let mut root = doc("[server]\ndomain = \"a.test\"\n");
+        let (version, warnings) = migrate_root(&mut root).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("no `version` key"));
+        assert!(warnings[1].contains("deprecated"));
+        assert_eq!(root["version"].as_integer(), Some(CURRENT_VERSION.into()));
+        assert_eq!(root["server"]["domain"].as_str(), Some("a.test"));
+    }
+
+    #[test]
+    fn root_versions_are_checked() {
+        let newer = format!("version = {}", CURRENT_VERSION + 1);
+        assert!(matches!(
+            migrate_root(&mut doc(&newer)),
+            Err(ConfigErrorVariant::UnsupportedVersion(v)) if v == CURRENT_VERSION + 1
+        ));
+        for invalid in ["version = \"1\"", "version = -1"] {
+            assert!(matches!(
+                migrate_root(&mut doc(invalid)),
+                Err(ConfigErrorVariant::InvalidVersion)
+            ));
+        }
+    }
+
+    #[test]
+    fn migrations_rewrite_and_warn() {
+        let mut root = doc("version = 1\n\n# the site\n[server]\nname = \"a.test\"\n");
+        let (version, warnings) = upgrade_root(&mut root, RENAME, 2).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[1], "`server.name` is now `server.domain`");
+        assert_eq!(root["version"].as_integer(), Some(2));
+        assert!(root["server"].get("name").is_none());
+        assert_eq!(root["server"]["domain"].as_str(), Some("a.test"));
+        // comments are kept
+        assert!(root.to_string().contains("# the site"));
+
+        // already at version 2, so it's left alone
+        let mut root = doc("version = 2\n[server]\nname = \"a.test\"\n");
+        assert!(upgrade_root(&mut root, RENAME, 2).unwrap().1.is_empty());
+        assert_eq!(root["server"]["name"].as_str(), Some("a.test"));
+    }
+
+    #[test]
+    fn fragments_follow_their_root() {
+        let mut fragment = doc("[server]\nname = \"a.test\"\n");
+        let warnings = upgrade_fragment(&mut fragment, RENAME, 1).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(fragment["server"]["domain"].as_str(), Some("a.test"));
+
+        let mut fragment = doc("[server]\nname = \"a.test\"\n");
+        assert!(upgrade_fragment(&mut fragment, RENAME, 2)
+            .unwrap()
+            .is_empty());
+        assert_eq!(fragment["server"]["name"].as_str(), Some("a.test"));
+
+        assert!(matches!(
+            migrate_fragment(&mut doc("version = 1"), 1),
+            Err(ConfigErrorVariant::FragmentVersion)
+        ));
+    }
+
+    #[test]
+    fn files_are_backed_up_and_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("config.toml");
+        let fragment = dir.path().join("hosts.toml");
+        let original = "# ours\ninclude = [\"hosts.toml\"]\n";
+        std::fs::write(&root, original).unwrap();
+        std::fs::write(&fragment, "[[hosts]]\nnames = [\"a.test\"]\n").unwrap();
+        let mut cfg = Config::default();
+        cfg.directories.state = dir.path().join("state");
+        cfg.sources = vec![root.clone(), fragment.clone()];
+        let backups = cfg.directories.state.join("config-backups");
+
+        migrate_files(&cfg, true).unwrap();
+        assert_eq!(std::fs::read_to_string(&root).unwrap(), original);
+        assert!(!backups.exists());
+
+        migrate_files(&cfg, false).unwrap();
+        let migrated = std::fs::read_to_string(&root).unwrap();
+        assert!(migrated.starts_with("# ours\n"));
+        assert_eq!(
+            migrated.parse::<DocumentMut>().unwrap()["version"].as_integer(),
+            Some(CURRENT_VERSION.into())
+        );
+        // only the changed file is backed up
+        let saved = std::fs::read_dir(&backups)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(saved.len(), 1);
+        assert!(saved[0]
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("config.toml."));
+        assert_eq!(std::fs::read_to_string(&saved[0]).unwrap(), original);
+
+        // and migrating again changes nothing
+        migrate_files(&cfg, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&root).unwrap(), migrated);
+        assert_eq!(std::fs::read_dir(&backups).unwrap().count(), 1);
+    }
+}
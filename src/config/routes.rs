@@ -0,0 +1,1199 @@
+//! The route table, which maps requests to [Handler]s.
+//!
+//! Routes are tried in a fixed order, regardless of how they're declared:
+//! 1. higher `priority` first;
+//! 2. then by path pattern kind: exact, glob, regex, then prefix;
+//! 3. then longer patterns first;
+//! 4. then routes with more conditions (methods, host, headers) first;
+//! 5. then in declaration order.
+
+use super::upstreams::ProxyTarget;
+use hyper::{Method, Request, StatusCode};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::BTreeMap, collections::HashMap, path::PathBuf};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Only match requests using one of these methods; if empty, any method matches. `GET` implies
+    /// `HEAD`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<HttpMethod>,
+    pub path: PathPattern,
+    /// Only match requests for this host; a leading `*.` matches any subdomain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Only match requests which have each of these headers, with exactly these values.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Routes with higher priority are tried first.
+    #[serde(default)]
+    pub priority: i32,
+    /// Whether responses may be compressed, per `[compression]`; set to `false` for responses which
+    /// must not be, such as streams.
+    #[serde(default = "compress_default")]
+    pub compress: bool,
+    pub handler: Handler,
+}
+
+fn compress_default() -> bool {
+    true
+}
+
+/// What to do with a request matched by a [Route].
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Handler {
+    /// Serve files from a directory.
+    Static(StaticFiles),
+    /// Render Markdown files from a directory as HTML pages.
+    Markdown(Markdown),
+    /// Render pages from a directory of templates.
+    Templates(Templates),
+    /// Generate an RSS or Atom feed, or a sitemap, of the pages in a directory.
+    Feed(Feed),
+    /// Receive Webmentions, and list them for each page.
+    Webmention(Webmention),
+    /// An IndieAuth server, through which clients sign in as the site's owner.
+    #[serde(rename = "indieauth")]
+    IndieAuth(IndieAuth),
+    /// Write posts from Micropub clients as Markdown files.
+    Micropub(Micropub),
+    /// Accept files uploaded by Micropub clients.
+    Media(Media),
+    /// Redirect to `location`, in which `$n`/`${n}`/`$name`/`${name}` are replaced with captures
+    /// from the path pattern.
+    Redirect {
+        location: String,
+        #[serde(default)]
+        status: RedirectStatus,
+    },
+    /// Respond with a fixed status, headers, and body.
+    Respond {
+        #[serde(default)]
+        status: Status,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+        #[serde(default)]
+        body: String,
+    },
+    /// Forward requests to another server.
+    Proxy(Proxy),
+    /// Run a CGI script.
+    Cgi(Cgi),
+    /// Forward requests to a FastCGI responder, such as php-fpm.
+    #[serde(rename = "fastcgi")]
+    FastCgi(Gateway),
+    /// Forward requests to an SCGI server.
+    Scgi(Gateway),
+    /// Forward requests to a uwsgi server.
+    Uwsgi(Gateway),
+    /// Accept WebSocket connections, for a built-in endpoint or to relay to an upstream.
+    #[serde(rename = "websocket")]
+    WebSocket(WebSocket),
+    /// Stream a `[channels.<name>]` channel's events to clients as server-sent events, and accept
+    /// events `POST`ed by holders of its publish tokens.
+    Events(Events),
+    /// One of melia's built-in handlers.
+    Builtin { name: Builtin },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Proxy {
+    pub upstream: ProxyTarget,
+    /// Forward only the part of the path captured by the pattern (as for [StaticFiles::root]),
+    /// rather than the whole path.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Whether to forward the client's `Host` header, rather than the upstream's authority.
+    #[serde(default = "preserve_host_default")]
+    pub preserve_host: bool,
+}
+
+fn preserve_host_default() -> bool {
+    true
+}
+
+/// Runs a script for each request, per RFC 3875.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cgi {
+    /// The script to run, in its own directory. With a `prefix` path pattern (or a `regex` pattern
+    /// with a `path` group), the captured remainder of the path is passed as `PATH_INFO`.
+    pub script: PathBuf,
+    /// Additional environment variables for the script.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// How long the script may run, in milliseconds, before it's killed.
+    #[serde(default = "gateway_timeout_default")]
+    pub timeout_ms: u64,
+    /// The largest request body without a `Content-Length` that's read into memory (to find its
+    /// length), in bytes; larger bodies are refused with `413`.
+    #[serde(default = "max_buffered_body_default")]
+    pub max_buffered_body: usize,
+}
+
+/// An application server speaking a gateway protocol (FastCGI, SCGI, or uwsgi), to which requests
+/// are described by CGI variables.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Gateway {
+    /// Where to send requests: `unix:/path/to/socket`, `tcp://host:port`, or the name of an
+    /// `[upstreams.<name>]` group.
+    pub upstream: ProxyTarget,
+    /// The script the server should run, passed as `SCRIPT_FILENAME`, with the rest of the path
+    /// passed as for [Cgi::script].
+    ///
+    /// For FastCGI, this defaults to the file at the request path (or its captured remainder, as
+    /// for [StaticFiles::root]) within the document root. Otherwise, it's omitted, and the path is
+    /// split into `SCRIPT_NAME` and `PATH_INFO` as if it were given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<PathBuf>,
+    /// Additional variables to send with each request.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// How long the server has to finish its response, in milliseconds.
+    #[serde(default = "gateway_timeout_default")]
+    pub timeout_ms: u64,
+    /// As for [Cgi::max_buffered_body].
+    #[serde(default = "max_buffered_body_default")]
+    pub max_buffered_body: usize,
+}
+
+fn gateway_timeout_default() -> u64 {
+    30_000
+}
+
+fn max_buffered_body_default() -> usize {
+    8 * 1024 * 1024
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Events {
+    /// The channel to serve; defaults to the captured remainder of the path (as for
+    /// [StaticFiles::root]), so that a single route (ex. with the prefix `/events/`) may serve
+    /// every channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+}
+
+/// Exactly one of `endpoint` or `upstream` must be set.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebSocket {
+    /// A built-in endpoint to connect clients to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<Endpoint>,
+    /// Where to relay connections: a URL, socket, or group, as for [Proxy::upstream]. The request
+    /// path and query are forwarded as they are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<ProxyTarget>,
+    /// Origins from which browsers may connect (ex. `https://example.com`), or `*` for any; if
+    /// empty, only pages from the requested host may connect. Clients which send no `Origin` are
+    /// always allowed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub origins: Vec<String>,
+    /// The largest message (and frame) accepted from either side, in bytes; larger messages close
+    /// the connection.
+    #[serde(default = "max_message_size_default")]
+    pub max_message_size: usize,
+    /// How often to ping clients, in milliseconds; clients which send nothing between two pings,
+    /// not even a pong, are disconnected. `0` disables pings.
+    #[serde(default = "ping_interval_default")]
+    pub ping_interval_ms: u64,
+}
+
+fn max_message_size_default() -> usize {
+    64 * 1024
+}
+
+fn ping_interval_default() -> u64 {
+    30_000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Endpoint {
+    /// Sends `{"visitors":n}` whenever the number of clients connected to the same path changes.
+    Counter,
+    /// Relays each text message to every client connected to the same path.
+    Chat,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StaticFiles {
+    /// The directory to serve files from; defaults to the document root.
+    ///
+    /// With a `prefix` path pattern, the prefix is stripped from request paths before they're
+    /// mapped to files, as is everything outside of a `path` capture group with a `regex` pattern.
+    pub root: Option<PathBuf>,
+    /// Files to serve when a directory is requested, in order of preference.
+    pub index: Vec<String>,
+    /// Whether to serve precompressed siblings of requested files (`.br`, `.zst`, or `.gz`) to
+    /// clients which accept them.
+    pub precompressed: bool,
+    /// Directory listings, for directories without an index file.
+    pub autoindex: Autoindex,
+}
+
+impl Default for StaticFiles {
+    fn default() -> Self {
+        Self {
+            root: None,
+            index: vec!["index.html".to_owned()],
+            precompressed: true,
+            autoindex: Autoindex::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Markdown {
+    /// The directory to find Markdown files in, mapped as for [StaticFiles::root]; defaults to the
+    /// document root. `/post` is served by `post.md` (or `post/index.md`), and `/dir/` by
+    /// `dir/index.md`. Other files (ex. images alongside posts) are served as they are.
+    pub root: Option<PathBuf>,
+    /// An HTML file to wrap each page in, in which `{{content}}` is replaced with the rendered
+    /// page, and `{{title}}`, `{{path}}`, & `{{meta.<key>}}` with the (escaped) title, request
+    /// path, & front matter values. The title is taken from the front matter's `title`, or else
+    /// the first heading.
+    pub template: Option<PathBuf>,
+    /// The theme used to highlight fenced code blocks, ex. `InspiredGitHub`,
+    /// `Solarized (light)`, or `base16-ocean.dark`; if empty, code isn't highlighted.
+    pub theme: String,
+}
+
+impl Default for Markdown {
+    fn default() -> Self {
+        Self {
+            root: None,
+            template: None,
+            theme: "InspiredGitHub".to_owned(),
+        }
+    }
+}
+
+/// Pages rendered from [MiniJinja](https://docs.rs/minijinja) templates, which may extend &
+/// include each other. Templates are given:
+///
+/// - `request`: the request's `method`, `path`, `query` (as a string), `params` (the query's
+///   values, by name), `host`, and `headers` (by lowercase name);
+/// - `site`: the `[site]` variables from the configuration;
+/// - `data`: the contents of each TOML/JSON file in `data`, by file stem (ex. `data.authors` for
+///   `authors.toml`).
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Templates {
+    /// The directory containing templates, mapped as for [StaticFiles::root]; defaults to the
+    /// document root. `/about` is rendered from `about.html` (or `about/index.html`), `/dir/` from
+    /// `dir/index.html`, and `/feed.xml` from `feed.xml`. Templates in directories, or with names,
+    /// starting with `_` (ex. `_layouts/base.html`) may only be used by other templates. Only
+    /// `.html`, `.htm`, and `.xml` files are rendered; other files are served as static files.
+    pub root: Option<PathBuf>,
+    /// A directory of data files.
+    pub data: Option<PathBuf>,
+}
+
+/// Pages are Markdown files (at the paths [Markdown] routes serve them from), with a `title` &
+/// `date` (and optionally `updated`, `summary`, `author`, & `draft`) in their front matter, and HTML
+/// files, with a `<title>` and `<meta name="date" content="...">` (and optionally
+/// `<meta name="description" content="...">`). Dates are RFC 3339 date-times, or plain dates (ex.
+/// `2026-10-19`). Feeds only include pages with dates, and drafts are always left out.
+///
+/// Results are cached until a file within the directory changes.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Feed {
+    pub format: FeedFormat,
+    /// The directory to scan for pages; defaults to the document root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    /// The path at which `root` is served.
+    #[serde(default = "feed_prefix_default")]
+    pub prefix: String,
+    /// The site's URL (ex. `https://example.com`), to which page paths are appended; defaults to
+    /// the host's canonical name (or `server.domain`, or else the requested host), with `https` if
+    /// there are any HTTPS listeners.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    /// The feed's title; defaults to the host's name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// The author of pages which don't name their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// The most entries in a feed, newest first.
+    #[serde(default = "feed_limit_default")]
+    pub limit: usize,
+}
+
+fn feed_prefix_default() -> String {
+    "/".to_owned()
+}
+
+fn feed_limit_default() -> usize {
+    20
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FeedFormat {
+    /// RSS 2.0, ex. for `/feed.xml`.
+    Rss,
+    /// Atom, ex. for `/feed.atom`.
+    Atom,
+    /// A sitemap of every page, ex. for `/sitemap.xml`.
+    Sitemap,
+}
+
+/// Receives [Webmentions](https://www.w3.org/TR/webmention/). Each `POST`ed `source` & `target`
+/// is verified in the background, by fetching the source and looking for a link to the target;
+/// verified mentions are stored under `${state_dir}/webmentions`, and sources which have gone
+/// away are removed. `GET ?target=<url>` lists a page's mentions as JSON, as does the
+/// `webmentions(url)` function in templates.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Webmention {
+    /// Whether to advertise this endpoint with a `Link` header on the host's HTML pages; only
+    /// routes with exact path patterns are advertised.
+    pub advertise: bool,
+    /// Whether sources may be on loopback or private networks, ex. for testing.
+    pub allow_private_sources: bool,
+    /// How long to wait for a source, in milliseconds.
+    pub timeout_ms: u64,
+    /// The largest source fetched, in bytes.
+    pub max_source_size: usize,
+}
+
+impl Default for Webmention {
+    fn default() -> Self {
+        Self {
+            advertise: true,
+            allow_private_sources: false,
+            timeout_ms: 10_000,
+            max_source_size: 1024 * 1024,
+        }
+    }
+}
+
+/// An [IndieAuth](https://indieauth.spec.indieweb.org/) server, for signing in to Micropub (and
+/// other) clients with a password. The path pattern should be a prefix (ex. `/indieauth/`), under
+/// which the `metadata`, `auth` (authorization), & `token` endpoints are served. Clients must use
+/// PKCE, and redirect back to the same origin as their `client_id`.
+///
+/// Authorization codes & access tokens are stored (hashed) under `${state_dir}/indieauth`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndieAuth {
+    /// The URL identifying the site's owner, as entered into clients; defaults to the host's root
+    /// URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub me: Option<String>,
+    /// A PHC-format hash of the password (or passphrase) required to sign in, as printed by `melia
+    /// config hash-password`; if empty, no one may sign in.
+    pub password_hash: String,
+    /// How long authorization codes may be redeemed for, in seconds.
+    pub code_lifetime_secs: u64,
+    /// How long access tokens last, in days; if `0`, they last until revoked.
+    pub token_lifetime_days: u64,
+    /// Whether to advertise these endpoints with `Link` headers on the host's HTML pages; only
+    /// routes with prefix path patterns are advertised.
+    pub advertise: bool,
+}
+
+impl Default for IndieAuth {
+    fn default() -> Self {
+        Self {
+            me: None,
+            password_hash: String::new(),
+            code_lifetime_secs: 600,
+            token_lifetime_days: 0,
+            advertise: true,
+        }
+    }
+}
+
+/// A [Micropub](https://micropub.spec.indieweb.org/) endpoint, which writes posts as Markdown
+/// files with TOML front matter (as read by [Markdown] & [Feed] routes), given an access token
+/// with the `create` scope from an [IndieAuth] route. Posts are never overwritten; files should be
+/// uploaded to a [Media] route instead of being sent with posts.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Micropub {
+    /// The directory containing `dir`; defaults to the document root.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    /// The directory, within `root`, in which posts are written; a post `<dir>/<slug>.md` is
+    /// served at `<prefix><dir>/<slug>`.
+    pub dir: String,
+    /// The path at which `root` is served.
+    pub prefix: String,
+    /// The path (or URL) of the media endpoint reported to clients, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_endpoint: Option<String>,
+    /// Whether to advertise this endpoint with a `Link` header on the host's HTML pages; only
+    /// routes with exact path patterns are advertised.
+    pub advertise: bool,
+}
+
+impl Default for Micropub {
+    fn default() -> Self {
+        Self {
+            root: None,
+            dir: "notes".to_owned(),
+            prefix: "/".to_owned(),
+            media_endpoint: None,
+            advertise: true,
+        }
+    }
+}
+
+/// A Micropub media endpoint, which stores `file`s `POST`ed as `multipart/form-data`, given an
+/// access token with the `media` or `create` scope, sent either in an `Authorization` header or
+/// as an `access_token` field before the file. Files are named by a hash of their contents.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Media {
+    /// The directory containing `dir`; defaults to the document root.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    /// The directory, within `root`, in which files are stored.
+    pub dir: String,
+    /// The path at which `root` is served.
+    pub prefix: String,
+    /// The largest file accepted, in bytes.
+    pub max_size: u64,
+    /// The (lowercase) extensions of files accepted, by name or else by content type; by default,
+    /// common image, audio, & video formats. Files which browsers might run as pages or scripts
+    /// (ex. HTML & SVG) are best left out, since they're served from the site's origin.
+    pub extensions: Vec<String>,
+}
+
+impl Default for Media {
+    fn default() -> Self {
+        Self {
+            root: None,
+            dir: "media".to_owned(),
+            prefix: "/".to_owned(),
+            max_size: 16 * 1024 * 1024,
+            extensions: [
+                "jpg", "jpeg", "png", "gif", "webp", "avif", "mp3", "m4a", "ogg", "oga", "opus",
+                "flac", "wav", "mp4", "m4v", "webm", "ogv", "mov",
+            ]
+            .into_iter()
+            .map(str::to_owned)
+            .collect(),
+        }
+    }
+}
+
+/// Directory listings, served as HTML, or as JSON to clients which accept `application/json`.
+/// Listings may be sorted with `?sort=name|size|modified&order=asc|desc`.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Autoindex {
+    pub enabled: bool,
+    /// Whether to list files whose names start with `.`.
+    pub show_hidden: bool,
+    /// A stylesheet to link from HTML listings, in place of the built-in style.
+    pub stylesheet: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Builtin {
+    /// Respond with a short usage message.
+    Hello,
+    /// Respond with the request body.
+    Echo,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct HttpMethod(pub Method);
+
+impl TryFrom<String> for HttpMethod {
+    type Error = hyper::http::method::InvalidMethod;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Method::from_bytes(value.to_ascii_uppercase().as_bytes()).map(Self)
+    }
+}
+
+impl From<HttpMethod> for String {
+    fn from(value: HttpMethod) -> Self {
+        value.0.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub struct Status(pub StatusCode);
+
+impl Default for Status {
+    fn default() -> Self {
+        Self(StatusCode::OK)
+    }
+}
+
+impl TryFrom<u16> for Status {
+    type Error = hyper::http::status::InvalidStatusCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        StatusCode::from_u16(value).map(Self)
+    }
+}
+
+impl From<Status> for u16 {
+    fn from(value: Status) -> Self {
+        value.0.as_u16()
+    }
+}
+
+/// One of `301`, `302`, `303`, `307`, or `308`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub struct RedirectStatus(pub StatusCode);
+
+impl Default for RedirectStatus {
+    fn default() -> Self {
+        Self(StatusCode::FOUND)
+    }
+}
+
+impl TryFrom<u16> for RedirectStatus {
+    type Error = &'static str;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            301 | 302 | 303 | 307 | 308 => Ok(Self(StatusCode::from_u16(value).unwrap())),
+            _ => Err("redirect status must be one of 301, 302, 303, 307, or 308"),
+        }
+    }
+}
+
+impl From<RedirectStatus> for u16 {
+    fn from(value: RedirectStatus) -> Self {
+        value.0.as_u16()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "PathPatternToml", into = "PathPatternToml")]
+pub enum PathPattern {
+    /// Matches only this path.
+    Exact(String),
+    /// Matches any path starting with this; the remainder is captured as `$1`.
+    Prefix(String),
+    /// Matches paths against a glob, in which `*` matches within a path segment, `**` matches
+    /// across segments, and `?` matches a single character; each wildcard is captured in order.
+    Glob { glob: String, regex: Regex },
+    /// Matches paths against a regular expression (unanchored, unless it uses `^`/`$`).
+    Regex(Regex),
+}
+
+/// A path pattern is either a string (matched exactly) or a table with one of `exact`, `prefix`,
+/// `glob`, or `regex`.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum PathPatternToml {
+    Exact(String),
+    Table(PathPatternTable),
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+enum PathPatternTable {
+    Exact(String),
+    Prefix(String),
+    Glob(String),
+    Regex(String),
+}
+
+impl TryFrom<PathPatternToml> for PathPattern {
+    type Error = regex::Error;
+
+    fn try_from(value: PathPatternToml) -> Result<Self, Self::Error> {
+        Ok(match value {
+            PathPatternToml::Exact(path)
+            | PathPatternToml::Table(PathPatternTable::Exact(path)) => Self::Exact(path),
+            PathPatternToml::Table(PathPatternTable::Prefix(prefix)) => Self::Prefix(prefix),
+            PathPatternToml::Table(PathPatternTable::Glob(glob)) => Self::Glob {
+                regex: glob_to_regex(&glob)?,
+                glob,
+            },
+            PathPatternToml::Table(PathPatternTable::Regex(regex)) => {
+                Self::Regex(Regex::new(&regex)?)
+            }
+        })
+    }
+}
+
+impl From<PathPattern> for PathPatternToml {
+    fn from(value: PathPattern) -> Self {
+        match value {
+            PathPattern::Exact(path) => Self::Exact(path),
+            PathPattern::Prefix(prefix) => Self::Table(PathPatternTable::Prefix(prefix)),
+            PathPattern::Glob { glob, .. } => Self::Table(PathPatternTable::Glob(glob)),
+            PathPattern::Regex(regex) => {
+                Self::Table(PathPatternTable::Regex(regex.as_str().to_owned()))
+            }
+        }
+    }
+}
+
+fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut res = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                res.push_str("(.*)");
+            }
+            '*' => res.push_str("([^/]*)"),
+            '?' => res.push_str("([^/])"),
+            c => res.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    res.push('$');
+    Regex::new(&res)
+}
+
+/// Values captured from a request path by a [PathPattern].
+#[derive(Debug, Clone, Default)]
+pub struct Captures {
+    /// Captures referred to as `$1`, `$2`, ...
+    pub positional: Vec<String>,
+    pub named: HashMap<String, String>,
+    /// The part of the path handlers should treat as their own: the remainder after a prefix, or a
+    /// regex group named `path`.
+    pub subpath: Option<String>,
+}
+
+impl Captures {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match name.parse::<usize>() {
+            Ok(0) => None,
+            Ok(i) => self.positional.get(i - 1).map(String::as_str),
+            Err(_) => self.named.get(name).map(String::as_str),
+        }
+    }
+
+    /// Replace `$n`, `${n}`, `$name`, and `${name}` in `template` with the corresponding captures;
+    /// missing captures are replaced with nothing, and `$$` produces a literal `$`.
+    pub fn expand(&self, template: &str) -> String {
+        let mut res = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(i) = rest.find('$') {
+            res.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            if let Some(r) = rest.strip_prefix('$') {
+                res.push('$');
+                rest = r;
+            } else if let Some((name, r)) = rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+                res.push_str(self.get(name).unwrap_or_default());
+                rest = r;
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                if end == 0 {
+                    res.push('$');
+                } else {
+                    res.push_str(self.get(&rest[..end]).unwrap_or_default());
+                }
+                rest = &rest[end..];
+            }
+        }
+        res.push_str(rest);
+        res
+    }
+
+    fn from_regex(regex: &Regex, path: &str) -> Option<Self> {
+        let caps = regex.captures(path)?;
+        let mut res = Self {
+            positional: caps
+                .iter()
+                .skip(1)
+                .map(|c| c.map(|c| c.as_str().to_owned()).unwrap_or_default())
+                .collect(),
+            ..Default::default()
+        };
+        for name in regex.capture_names().flatten() {
+            if let Some(value) = caps.name(name) {
+                res.named.insert(name.to_owned(), value.as_str().to_owned());
+            }
+        }
+        res.subpath = res.named.get("path").cloned();
+        Some(res)
+    }
+}
+
+impl PathPattern {
+    pub fn matches(&self, path: &str) -> Option<Captures> {
+        match self {
+            Self::Exact(p) => (p == path).then(Captures::default),
+            Self::Prefix(p) => path.strip_prefix(p.as_str()).map(|rest| Captures {
+                positional: vec![rest.to_owned()],
+                subpath: Some(rest.to_owned()),
+                ..Default::default()
+            }),
+            Self::Glob { regex, .. } | Self::Regex(regex) => Captures::from_regex(regex, path),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Exact(_) => 0,
+            Self::Glob { .. } => 1,
+            Self::Regex(_) => 2,
+            Self::Prefix(_) => 3,
+        }
+    }
+
+    /// The pattern as written in the configuration.
+    pub fn source(&self) -> &str {
+        match self {
+            Self::Exact(p) | Self::Prefix(p) | Self::Glob { glob: p, .. } => p,
+            Self::Regex(r) => r.as_str(),
+        }
+    }
+
+    /// Whether every path matched by `other` is also matched by this.
+    ///
+    /// This is conservative; it may return `false` for some patterns which do cover `other`.
+    fn covers(&self, other: &Self) -> bool {
+        // the literal portion at the start of a glob
+        fn glob_prefix(glob: &str) -> &str {
+            &glob[..glob.find(['*', '?']).unwrap_or(glob.len())]
+        }
+        match (self, other) {
+            (Self::Prefix(a), Self::Exact(b) | Self::Prefix(b)) => b.starts_with(a.as_str()),
+            (Self::Prefix(a), Self::Glob { glob, .. }) => glob_prefix(glob).starts_with(a.as_str()),
+            (Self::Prefix(a), Self::Regex(_)) => a.is_empty(),
+            (a, Self::Exact(b)) => a.matches(b).is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// Get the host a request was sent to (without its port), from either the request URI (as with
+/// HTTP/2) or the `Host` header.
+pub fn request_host<B>(req: &Request<B>) -> Option<&str> {
+    let host = req.uri().host().or_else(|| {
+        req.headers()
+            .get(hyper::header::HOST)
+            .and_then(|h| h.to_str().ok())
+    })?;
+    // strip the port, taking care not to mangle bracketed IPv6 addresses
+    Some(match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    })
+}
+
+/// Whether `host` matches `pattern`, where a leading `*.` in `pattern` matches any subdomain.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host.len().checked_sub(suffix.len() + 1).is_some_and(|i| {
+            host.as_bytes()[i] == b'.' && host[i + 1..].eq_ignore_ascii_case(suffix)
+        }),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+impl Route {
+    pub fn matches<B>(&self, req: &Request<B>) -> Option<Captures> {
+        if !self.methods.is_empty()
+            && !self.methods.iter().any(|m| {
+                m.0 == req.method() || (m.0 == Method::GET && req.method() == Method::HEAD)
+            })
+        {
+            return None;
+        }
+        if let Some(host) = &self.host {
+            if !request_host(req).is_some_and(|h| host_matches(host, h)) {
+                return None;
+            }
+        }
+        for (name, value) in &self.headers {
+            if req.headers().get(name.as_str()).map(|v| v.as_bytes()) != Some(value.as_bytes()) {
+                return None;
+            }
+        }
+        self.path.matches(req.uri().path())
+    }
+
+    fn conditions(&self) -> usize {
+        self.methods.len().min(1) + usize::from(self.host.is_some()) + self.headers.len()
+    }
+
+    /// Whether every request matched by `other` is also matched by this.
+    fn covers(&self, other: &Self) -> bool {
+        let methods = self.methods.is_empty()
+            || (!other.methods.is_empty()
+                && other.methods.iter().all(|m| self.methods.contains(m)));
+        let host = match (&self.host, &other.host) {
+            (None, _) => true,
+            (Some(a), Some(b)) => {
+                a.eq_ignore_ascii_case(b) || (!b.starts_with("*.") && host_matches(a, b))
+            }
+            (Some(_), None) => false,
+        };
+        let headers = self
+            .headers
+            .iter()
+            .all(|(name, value)| other.headers.get(name) == Some(value));
+        methods && host && headers && self.path.covers(&other.path)
+    }
+}
+
+/// Routes, along with the order in which they're tried.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(from = "Vec<Route>", into = "Vec<Route>")]
+pub struct RouteTable {
+    /// In declaration order.
+    routes: Vec<Route>,
+    /// Indices into `routes`, in the order in which they're tried.
+    order: Vec<usize>,
+}
+
+impl From<Vec<Route>> for RouteTable {
+    fn from(routes: Vec<Route>) -> Self {
+        let mut order = (0..routes.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| {
+            let route = &routes[i];
+            (
+                Reverse(route.priority),
+                route.path.rank(),
+                Reverse(route.path.source().len()),
+                Reverse(route.conditions()),
+                i,
+            )
+        });
+        Self { routes, order }
+    }
+}
+
+impl From<RouteTable> for Vec<Route> {
+    fn from(value: RouteTable) -> Self {
+        value.routes
+    }
+}
+
+impl Default for RouteTable {
+    /// A `GET /` usage message, and `POST /echo`.
+    fn default() -> Self {
+        Self::from(vec![
+            Route {
+                methods: vec![HttpMethod(Method::GET)],
+                path: PathPattern::Exact("/".to_owned()),
+                host: None,
+                headers: BTreeMap::new(),
+                priority: 0,
+                compress: true,
+                handler: Handler::Builtin {
+                    name: Builtin::Hello,
+                },
+            },
+            Route {
+                methods: vec![HttpMethod(Method::POST)],
+                path: PathPattern::Exact("/echo".to_owned()),
+                host: None,
+                headers: BTreeMap::new(),
+                priority: 0,
+                compress: true,
+                handler: Handler::Builtin {
+                    name: Builtin::Echo,
+                },
+            },
+        ])
+    }
+}
+
+impl RouteTable {
+    /// Iterate over routes in the order in which they're tried.
+    pub fn iter(&self) -> impl Iterator<Item = &Route> {
+        self.order.iter().map(|&i| &self.routes[i])
+    }
+
+    /// Iterate over routes in declaration order.
+    pub fn iter_declared(&self) -> impl Iterator<Item = &Route> {
+        self.routes.iter()
+    }
+
+    /// Find the first route matching `req`.
+    pub fn find<B>(&self, req: &Request<B>) -> Option<(&Route, Captures)> {
+        self.iter()
+            .find_map(|route| route.matches(req).map(|captures| (route, captures)))
+    }
+
+    /// Find routes which can never match, because a route tried before them matches every request
+    /// they would; returns `(shadowed, shadowing)` pairs of declaration indices.
+    pub fn unreachable(&self) -> Vec<(usize, usize)> {
+        let mut res = Vec::new();
+        for (pos, &i) in self.order.iter().enumerate() {
+            if let Some(&j) = self.order[..pos]
+                .iter()
+                .find(|&&j| self.routes[j].covers(&self.routes[i]))
+            {
+                res.push((i, j));
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(toml: &str) -> RouteTable {
+        #[derive(Deserialize)]
+        struct Routes {
+            routes: RouteTable,
+        }
+        toml::from_str::<Routes>(toml).unwrap().routes
+    }
+
+    fn request(method: Method, uri: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(hyper::header::HOST, "a.test")
+            .body(())
+            .unwrap()
+    }
+
+    /// The declaration index of the route matching `GET uri`.
+    fn found(table: &RouteTable, req: &Request<()>) -> Option<usize> {
+        let (route, _) = table.find(req)?;
+        table.iter_declared().position(|r| std::ptr::eq(r, route))
+    }
+
+    fn get(table: &RouteTable, uri: &str) -> Option<usize> {
+        found(table, &request(Method::GET, uri))
+    }
+
+    fn pattern(toml: &str) -> PathPattern {
+        #[derive(Deserialize)]
+        struct Pattern {
+            path: PathPattern,
+        }
+        toml::from_str::<Pattern>(toml).unwrap().path
+    }
+
+    #[test]
+    fn precedence_by_priority_then_kind() {
+        let routes = table(
+            r#"
+            [[routes]]
+            path = { prefix = "/" }
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = { regex = "^/a/(.*)$" }
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = { glob = "/a/*" }
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = "/a/b"
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = { prefix = "/p/" }
+            priority = 1
+            handler = { type = "builtin", name = "hello" }
+            "#,
+        );
+        assert_eq!(get(&routes, "/a/b"), Some(3));
+        assert_eq!(get(&routes, "/a/c"), Some(2));
+        assert_eq!(get(&routes, "/a/c/d"), Some(1));
+        assert_eq!(get(&routes, "/b"), Some(0));
+        let order = routes.iter().map(|r| r.path.source()).collect::<Vec<_>>();
+        assert_eq!(order, ["/p/", "/a/b", "/a/*", "^/a/(.*)$", "/"]);
+    }
+
+    #[test]
+    fn precedence_by_length_conditions_then_order() {
+        let routes = table(
+            r#"
+            [[routes]]
+            path = { prefix = "/a/" }
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = { prefix = "/a/b/" }
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = { prefix = "/a/b/" }
+            methods = ["POST"]
+            host = "a.test"
+            handler = { type = "builtin", name = "echo" }
+            [[routes]]
+            path = { prefix = "/a/b/" }
+            handler = { type = "builtin", name = "echo" }
+            "#,
+        );
+        assert_eq!(get(&routes, "/a/x"), Some(0));
+        // longer first, even though it's declared later
+        assert_eq!(get(&routes, "/a/b/c"), Some(1));
+        // more conditions first
+        assert_eq!(found(&routes, &request(Method::POST, "/a/b/c")), Some(2));
+        // otherwise, the first declared
+        assert_eq!(
+            routes.iter().map(|r| r.path.source()).collect::<Vec<_>>(),
+            ["/a/b/", "/a/b/", "/a/b/", "/a/"]
+        );
+        assert_eq!(routes.unreachable(), [(3, 1)]);
+    }
+
+    #[test]
+    fn route_conditions() {
+        let routes = table(
+            r#"
+            [[routes]]
+            path = "/"
+            methods = ["GET"]
+            host = "*.b.test"
+            headers = { "x-test" = "yes" }
+            handler = { type = "builtin", name = "hello" }
+            "#,
+        );
+        let route = routes.iter().next().unwrap();
+        let req = |method, host, header: Option<&str>| {
+            let mut req = Request::builder()
+                .method(method)
+                .uri("/")
+                .header(hyper::header::HOST, host);
+            if let Some(value) = header {
+                req = req.header("x-test", value);
+            }
+            req.body(()).unwrap()
+        };
+        assert!(route
+            .matches(&req(Method::GET, "c.b.test:8080", Some("yes")))
+            .is_some());
+        // `GET` implies `HEAD`
+        assert!(route
+            .matches(&req(Method::HEAD, "c.B.test", Some("yes")))
+            .is_some());
+        assert!(route
+            .matches(&req(Method::POST, "c.b.test", Some("yes")))
+            .is_none());
+        assert!(route
+            .matches(&req(Method::GET, "b.test", Some("yes")))
+            .is_none());
+        assert!(route
+            .matches(&req(Method::GET, "c.b.test", Some("no")))
+            .is_none());
+        assert!(route.matches(&req(Method::GET, "c.b.test", None)).is_none());
+    }
+
+    #[test]
+    fn unreachable_routes() {
+        let routes = table(
+            r#"
+            [[routes]]
+            path = "/a"
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = { prefix = "/a" }
+            priority = 1
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = { glob = "/docs/*.html" }
+            methods = ["GET"]
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = { prefix = "/docs/" }
+            methods = ["GET"]
+            priority = 2
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = { glob = "/docs/**" }
+            methods = ["GET", "POST"]
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = { regex = "^/x" }
+            host = "*.a.test"
+            priority = 2
+            handler = { type = "builtin", name = "hello" }
+            "#,
+        );
+        let mut unreachable = routes.unreachable();
+        unreachable.sort();
+        // route 4 also accepts `POST`, and the regex has no prefix to compare, so they're kept
+        assert_eq!(unreachable, [(0, 1), (2, 3)]);
+
+        // a host condition only covers the same, or more specific, hosts
+        let hosts = table(
+            r#"
+            [[routes]]
+            path = "/"
+            host = "*.a.test"
+            priority = 1
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = "/"
+            host = "b.a.test"
+            handler = { type = "builtin", name = "hello" }
+            [[routes]]
+            path = "/"
+            handler = { type = "builtin", name = "hello" }
+            "#,
+        );
+        assert_eq!(hosts.unreachable(), [(1, 0)]);
+    }
+
+    #[test]
+    fn glob_captures() {
+        let glob = pattern(r#"path = { glob = "/docs/*/**.htm?" }"#);
+        let caps = glob.matches("/docs/en/a/b.html").unwrap();
+        assert_eq!(caps.positional, ["en", "a/b", "l"]);
+        assert_eq!(caps.subpath, None);
+        // `*` stays within a segment, `.` is literal, and the whole path must match
+        assert!(glob.matches("/docs/en.html").is_none());
+        assert!(glob.matches("/docs/en/bxhtml").is_none());
+        assert!(glob.matches("/x/docs/en/b.html").is_none());
+        assert!(glob.matches("/docs/en/b.html/").is_none());
+    }
+
+    #[test]
+    fn regex_and_prefix_captures() {
+        let regex = pattern(r#"path = { regex = "/(?P<user>[a-z]+)/(?P<path>.*)" }"#);
+        // unanchored
+        let caps = regex.matches("/~/alice/notes/1").unwrap();
+        assert_eq!(caps.get("user"), Some("alice"));
+        assert_eq!(caps.get("1"), Some("alice"));
+        assert_eq!(caps.get("2"), Some("notes/1"));
+        assert_eq!(caps.get("0"), None);
+        assert_eq!(caps.subpath.as_deref(), Some("notes/1"));
+
+        let prefix = pattern(r#"path = { prefix = "/static/" }"#);
+        let caps = prefix.matches("/static/a/b.css").unwrap();
+        assert_eq!(caps.get("1"), Some("a/b.css"));
+        assert_eq!(caps.subpath.as_deref(), Some("a/b.css"));
+        assert!(prefix.matches("/static").is_none());
+    }
+
+    #[test]
+    fn expand_captures() {
+        let caps = Captures {
+            positional: vec!["a".to_owned(), "b".to_owned()],
+            named: HashMap::from([("name".to_owned(), "n".to_owned())]),
+            subpath: None,
+        };
+        assert_eq!(caps.expand("/$1/${2}x/$name/${name}"), "/a/bx/n/n");
+        assert_eq!(caps.expand("$3$missing${nope}."), ".");
+        assert_eq!(caps.expand("$$1 costs $5$"), "$1 costs $");
+        assert_eq!(caps.expand("$-/$"), "$-/$");
+        assert_eq!(caps.expand("${unclosed"), "${unclosed");
+    }
+}
@@ -0,0 +1,325 @@
+//! Redirect & rewrite rules, which are applied in order before routing; the first matching rule
+//! wins.
+//!
+//! A rule with status `200` rewrites the request internally, after which it's routed (but not
+//! matched against rules again) by its new path. Any other status redirects the client.
+
+use super::routes::{host_matches, request_host, Captures, PathPattern};
+use hyper::{Request, StatusCode};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub path: PathPattern,
+    /// Where to send the request, in which `$n`/`${n}`/`$name`/`${name}` are replaced with
+    /// captures from the path pattern and query. If this has no query, the request's is kept.
+    pub to: String,
+    #[serde(default)]
+    pub status: RuleStatus,
+    /// Only apply to requests for this host; a leading `*.` matches any subdomain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<Scheme>,
+    /// Only apply to requests with each of these query parameters. A value of the form `:name`
+    /// matches any value, capturing it as `$name`; any other value must match exactly.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+/// `200` for an internal rewrite, or one of `301`, `302`, `303`, `307`, or `308` for a redirect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub struct RuleStatus(pub StatusCode);
+
+impl Default for RuleStatus {
+    fn default() -> Self {
+        Self(StatusCode::MOVED_PERMANENTLY)
+    }
+}
+
+impl TryFrom<u16> for RuleStatus {
+    type Error = &'static str;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            200 | 301 | 302 | 303 | 307 | 308 => Ok(Self(StatusCode::from_u16(value).unwrap())),
+            _ => Err("rule status must be 200 (rewrite), 301, 302, 303, 307, or 308"),
+        }
+    }
+}
+
+impl From<RuleStatus> for u16 {
+    fn from(value: RuleStatus) -> Self {
+        value.0.as_u16()
+    }
+}
+
+/// What to do with a request matched by a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Redirect(StatusCode, String),
+    /// Continue with this path & query instead.
+    Rewrite(String),
+}
+
+impl Rule {
+    pub fn apply<B>(&self, req: &Request<B>, tls: bool) -> Option<Action> {
+        match self.scheme {
+            Some(Scheme::Http) if tls => return None,
+            Some(Scheme::Https) if !tls => return None,
+            _ => {}
+        }
+        if let Some(host) = &self.host {
+            if !request_host(req).is_some_and(|h| host_matches(host, h)) {
+                return None;
+            }
+        }
+        let mut captures = self.path.matches(req.uri().path())?;
+        self.match_query(req.uri().query().unwrap_or_default(), &mut captures)?;
+
+        let mut to = captures.expand(&self.to);
+        if !to.contains('?') {
+            if let Some(query) = req.uri().query() {
+                to = format!("{to}?{query}");
+            }
+        }
+        Some(match self.status.0 {
+            StatusCode::OK => Action::Rewrite(to),
+            status => Action::Redirect(status, to),
+        })
+    }
+
+    fn match_query(&self, query: &str, captures: &mut Captures) -> Option<()> {
+        let params = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| p.split_once('=').unwrap_or((p, "")))
+            .collect::<Vec<_>>();
+        for (name, expected) in &self.query {
+            let (_, value) = params.iter().find(|(n, _)| n == name)?;
+            match expected.strip_prefix(':') {
+                Some(capture) => {
+                    captures
+                        .named
+                        .insert(capture.to_owned(), (*value).to_owned());
+                }
+                None if expected == value => {}
+                None => return None,
+            }
+        }
+        Some(())
+    }
+}
+
+/// Apply the first of `rules` which matches `req`.
+pub fn apply<'r, B>(
+    rules: impl IntoIterator<Item = &'r Rule>,
+    req: &Request<B>,
+    tls: bool,
+) -> Option<Action> {
+    rules.into_iter().find_map(|rule| rule.apply(req, tls))
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct RedirectsError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Parse rules from a Netlify-style `_redirects` file, in which each line is
+/// `from [param=value...] to [status][!]`.
+///
+/// In `from`, `:name` matches a path segment and a trailing `*` matches the rest of the path; they
+/// may be used in `to` as `:name` and `:splat` respectively. The status defaults to `301`, and `!`
+/// is accepted but has no effect, since rules always apply before routing.
+pub fn parse_redirects(text: &str) -> Result<Vec<Rule>, RedirectsError> {
+    let mut rules = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |message: &str| RedirectsError {
+            line: i + 1,
+            message: message.to_owned(),
+        };
+
+        let mut fields = line.split_whitespace().collect::<Vec<_>>();
+        let status = match fields
+            .last()
+            .map(|f| f.trim_end_matches('!').parse::<u16>())
+        {
+            Some(Ok(status)) => {
+                fields.pop();
+                RuleStatus::try_from(status).map_err(err)?
+            }
+            _ => RuleStatus::default(),
+        };
+        let [from, params @ .., to] = fields.as_slice() else {
+            return Err(err("expected a source path and a destination"));
+        };
+        let query = params
+            .iter()
+            .map(|p| {
+                p.split_once('=')
+                    .map(|(n, v)| (n.to_owned(), v.to_owned()))
+                    .ok_or_else(|| err("expected a query parameter of the form `name=value`"))
+            })
+            .collect::<Result<_, _>>()?;
+        rules.push(Rule {
+            path: redirects_pattern(from).map_err(|e| err(&e.to_string()))?,
+            to: redirects_template(to),
+            status,
+            host: None,
+            scheme: None,
+            query,
+        });
+    }
+    Ok(rules)
+}
+
+fn redirects_pattern(from: &str) -> Result<PathPattern, regex::Error> {
+    if !from.contains([':', '*']) {
+        return Ok(PathPattern::Exact(from.to_owned()));
+    }
+    let mut res = String::from("^");
+    for (i, segment) in from.split('/').enumerate() {
+        if i > 0 {
+            res.push('/');
+        }
+        match segment {
+            "*" => res.push_str("(?P<splat>.*)"),
+            s if s.starts_with(':') => res.push_str(&format!("(?P<{}>[^/]+)", &s[1..])),
+            s => res.push_str(&regex::escape(s)),
+        }
+    }
+    res.push('$');
+    Ok(PathPattern::Regex(Regex::new(&res)?))
+}
+
+/// Convert `:name` placeholders to `${name}`.
+fn redirects_template(to: &str) -> String {
+    let mut res = String::with_capacity(to.len());
+    let mut rest = to;
+    while let Some(i) = rest.find(':') {
+        res.push_str(&rest[..i].replace('$', "$$"));
+        let name = &rest[i + 1..];
+        let name_len = match name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            true => name
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(name.len()),
+            false => 0,
+        };
+        match name_len {
+            // not a placeholder (ex. the colons in `https://` or `localhost:8080`)
+            0 => res.push(':'),
+            _ => res.push_str(&format!("${{{}}}", &rest[i + 1..i + 1 + name_len])),
+        }
+        rest = &rest[i + 1 + name_len..];
+    }
+    res.push_str(&rest.replace('$', "$$"));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str) -> Request<()> {
+        Request::builder()
+            .uri(uri)
+            .header(hyper::header::HOST, "example.com")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn template_placeholders() {
+        assert_eq!(redirects_template("/a/:name"), "/a/${name}");
+        assert_eq!(redirects_template("/:a/:b_2.html"), "/${a}/${b_2}.html");
+        assert_eq!(redirects_template("/:splat"), "/${splat}");
+        assert_eq!(redirects_template("/:_x"), "/${_x}");
+    }
+
+    #[test]
+    fn template_keeps_other_colons() {
+        assert_eq!(
+            redirects_template("https://example.com/:splat"),
+            "https://example.com/${splat}"
+        );
+        assert_eq!(
+            redirects_template("http://localhost:8080/new"),
+            "http://localhost:8080/new"
+        );
+        assert_eq!(redirects_template("/a:/b::c"), "/a:/b:${c}");
+        assert_eq!(redirects_template("/trailing:"), "/trailing:");
+        assert_eq!(redirects_template("/cost/$5"), "/cost/$$5");
+    }
+
+    #[test]
+    fn parse_redirects_lines() {
+        let rules = parse_redirects(
+            "# comment\n\
+             /old /new\n\
+             \n\
+             /blog/:year/:slug /posts/:year-:slug 302 # trailing comment\n\
+             /docs/* /manual/:splat 200!\n\
+             /search q=:term /find/:term\n",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 4);
+        assert_eq!(rules[0].status, RuleStatus::default());
+        assert_eq!(rules[1].status.0, StatusCode::FOUND);
+        assert_eq!(rules[2].status.0, StatusCode::OK);
+        assert_eq!(rules[3].query.get("q").map(String::as_str), Some(":term"));
+
+        assert_eq!(
+            rules[0].apply(&request("/old?x=1"), false),
+            Some(Action::Redirect(
+                StatusCode::MOVED_PERMANENTLY,
+                "/new?x=1".to_owned()
+            ))
+        );
+        assert_eq!(
+            rules[1].apply(&request("/blog/2024/hello"), false),
+            Some(Action::Redirect(
+                StatusCode::FOUND,
+                "/posts/2024-hello".to_owned()
+            ))
+        );
+        assert_eq!(rules[1].apply(&request("/blog/2024"), false), None);
+        assert_eq!(
+            rules[2].apply(&request("/docs/a/b"), false),
+            Some(Action::Rewrite("/manual/a/b".to_owned()))
+        );
+        assert_eq!(
+            rules[3].apply(&request("/search?q=cats"), false),
+            Some(Action::Redirect(
+                StatusCode::MOVED_PERMANENTLY,
+                "/find/cats?q=cats".to_owned()
+            ))
+        );
+        assert_eq!(rules[3].apply(&request("/search"), false), None);
+    }
+
+    #[test]
+    fn parse_redirects_errors() {
+        let line = |text| parse_redirects(text).unwrap_err().line;
+        assert_eq!(line("/a /b\n/lonely\n"), 2);
+        assert_eq!(line("/a /b 404\n"), 1);
+        assert_eq!(line("/a\n"), 1);
+        assert_eq!(line("\n/a q /b\n"), 2);
+    }
+}
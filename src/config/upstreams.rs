@@ -0,0 +1,174 @@
+//! Servers to which requests may be proxied, and groups of them among which requests are balanced.
+
+use super::ConfigErrorVariant;
+use hyper::http::uri::Authority;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use url::Url;
+
+/// Where a proxy route sends requests: either a single upstream URL, or the name of an
+/// `[upstreams.<name>]` group (which may not contain `:`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ProxyTarget {
+    Upstream(Upstream),
+    Group(String),
+}
+
+impl TryFrom<String> for ProxyTarget {
+    type Error = ConfigErrorVariant;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.contains(':') {
+            Ok(Self::Upstream(Url::parse(&value)?.try_into()?))
+        } else {
+            Ok(Self::Group(value))
+        }
+    }
+}
+
+impl From<ProxyTarget> for String {
+    fn from(value: ProxyTarget) -> Self {
+        match value {
+            ProxyTarget::Upstream(upstream) => upstream.to_string(),
+            ProxyTarget::Group(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamGroup {
+    pub members: Vec<Upstream>,
+    pub policy: Policy,
+    /// Periodically probe each member, taking failing members out of rotation.
+    pub health_check: Option<HealthCheck>,
+    pub passive: PassiveHealth,
+}
+
+/// How a member of an [UpstreamGroup] is chosen for each request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    #[default]
+    RoundRobin,
+    /// The member with the fewest requests in flight.
+    LeastConnections,
+    /// The same member for each value of a header or cookie, for as long as it's available.
+    /// Requests without the header or cookie are distributed round-robin.
+    ConsistentHash(HashKey),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashKey {
+    Header(String),
+    Cookie(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheck {
+    /// Path to `GET` from each member; any 2xx or 3xx response is healthy.
+    pub path: String,
+    /// How often to probe; 0 disables probing.
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+    /// How many consecutive failed probes take a member out of rotation; one successful probe
+    /// puts it back.
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: "/".to_owned(),
+            interval_ms: 10_000,
+            timeout_ms: 2_000,
+            unhealthy_threshold: 2,
+        }
+    }
+}
+
+/// Taking members out of rotation when proxied requests to them fail.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PassiveHealth {
+    /// How many consecutive failures eject a member; `0` disables passive ejection.
+    pub max_fails: u32,
+    /// How long an ejected member stays out of rotation, in milliseconds.
+    pub eject_ms: u64,
+}
+
+impl Default for PassiveHealth {
+    fn default() -> Self {
+        Self {
+            max_fails: 3,
+            eject_ms: 30_000,
+        }
+    }
+}
+
+/// An upstream server, written as `http://host:port[/base/path]`, `tcp://host:port`, or
+/// `unix:/path/to/socket`. `tcp://` is for gateway protocols like FastCGI; proxy routes treat it
+/// like `http://` without a base path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "Url", into = "Url")]
+pub enum Upstream {
+    Http {
+        authority: Authority,
+        /// Prepended to the paths of proxied requests; never ends with `/`.
+        base: String,
+    },
+    Tcp(Authority),
+    Unix(PathBuf),
+}
+
+impl TryFrom<Url> for Upstream {
+    type Error = ConfigErrorVariant;
+
+    fn try_from(url: Url) -> Result<Self, Self::Error> {
+        match url.scheme() {
+            "http" => {
+                let host = url.host_str().ok_or(ConfigErrorVariant::InvalidUrlHost)?;
+                let authority = match url.port() {
+                    Some(port) => format!("{host}:{port}"),
+                    None => host.to_owned(),
+                };
+                Ok(Self::Http {
+                    authority: authority
+                        .parse()
+                        .map_err(|_| ConfigErrorVariant::InvalidUrlHost)?,
+                    base: url.path().trim_end_matches('/').to_owned(),
+                })
+            }
+            "tcp" => {
+                let host = url.host_str().ok_or(ConfigErrorVariant::InvalidUrlHost)?;
+                let port = url.port().ok_or(ConfigErrorVariant::InvalidUrlHost)?;
+                Ok(Self::Tcp(
+                    format!("{host}:{port}")
+                        .parse()
+                        .map_err(|_| ConfigErrorVariant::InvalidUrlHost)?,
+                ))
+            }
+            "unix" => Ok(Self::Unix(PathBuf::from(url.path()))),
+            scheme => Err(ConfigErrorVariant::UnsupportedScheme(scheme.to_owned())),
+        }
+    }
+}
+
+impl From<Upstream> for Url {
+    fn from(value: Upstream) -> Self {
+        value.to_string().parse().unwrap()
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http { authority, base } => write!(f, "http://{authority}{base}"),
+            Self::Tcp(authority) => write!(f, "tcp://{authority}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
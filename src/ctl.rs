@@ -1,56 +1,128 @@
 use crate::cli::CtlCommand;
+use crate::daemon::upstreams::{MemberState, MemberStatus};
 use bytes::{Buf, Bytes};
-use http_body_util::{BodyExt, Empty};
+use http_body_util::{BodyExt, Full};
 use hyper::{
     client::conn::http1::{Connection, SendRequest},
-    rt::Executor,
-    Request,
+    Method, Request, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
 };
-use std::path::PathBuf;
 use tokio::net::UnixStream;
 
-pub async fn run(
-    cfg: crate::config::Config,
-    socket: PathBuf,
-    cmd: CtlCommand,
-) -> Result<(), std::io::Error> {
-    let stream = UnixStream::connect(&socket).await?;
+#[derive(Debug, thiserror::Error)]
+pub enum CtlError {
+    #[error("could not connect to daemon: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Hyper(#[from] hyper::Error),
+    #[error(transparent)]
+    Http(#[from] hyper::http::Error),
+    #[error("daemon responded with {0}: {1}")]
+    Status(StatusCode, String),
+    #[error("could not parse daemon response: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("could not format configuration: {0}")]
+    Toml(#[from] toml::ser::Error),
+}
+
+pub async fn run(socket: PathBuf, cmd: CtlCommand) -> Result<(), CtlError> {
     match cmd {
         CtlCommand::PrintCfg => {
-            todo!();
-            //let rem_cfg = get_cfg(cfg, socket, stream).await.unwrap();
-            //println!("{}", toml::to_string_pretty(&rem_cfg).unwrap());
+            let rem_cfg = get_cfg(&socket).await?;
+            println!("{}", toml::to_string_pretty(&rem_cfg)?);
+        }
+        CtlCommand::Reload => {
+            let msg = request(&socket, Method::POST, "reload", Bytes::new()).await?;
+            println!("{}", String::from_utf8_lossy(&msg));
+        }
+        CtlCommand::Upstreams => {
+            let body = request(&socket, Method::GET, "upstreams", Bytes::new()).await?;
+            let groups: BTreeMap<String, Vec<MemberStatus>> = serde_json::from_slice(&body)?;
+            for (name, members) in groups {
+                println!("{name}");
+                for member in members {
+                    let state = match (member.state, member.ejected_for_ms) {
+                        (MemberState::Up, _) => "up".to_owned(),
+                        (MemberState::Down, _) => "down (failing health checks)".to_owned(),
+                        (MemberState::Ejected, Some(ms)) => {
+                            format!("ejected ({}s left)", ms.div_ceil(1000))
+                        }
+                        (MemberState::Ejected, None) => "ejected".to_owned(),
+                    };
+                    println!(
+                        "  {}\t{state}\tactive: {}\tfailures: {}",
+                        member.upstream, member.active, member.failures
+                    );
+                }
+            }
+        }
+        CtlCommand::Publish {
+            channel,
+            data,
+            event,
+        } => {
+            let data = match data {
+                Some(data) => data,
+                None => tokio::task::spawn_blocking(|| std::io::read_to_string(std::io::stdin()))
+                    .await
+                    .map_err(std::io::Error::other)??,
+            };
+            let mut query = url::form_urlencoded::Serializer::new("publish".to_owned());
+            query.append_pair("channel", &channel);
+            if let Some(event) = &event {
+                query.append_pair("event", event);
+            }
+            let id = request(&socket, Method::POST, &query.finish(), data.into()).await?;
+            println!("{}", String::from_utf8_lossy(&id));
         }
-        cmd => todo!("{cmd:?}"),
     }
     Ok(())
 }
 
-//pub async fn get_cfg(
-//    cfg: crate::config::Config,
-//    socket: PathBuf,
-//    stream: UnixStream,
-//) -> hyper::Result<crate::config::Config> {
-//    let (mut sender, conn): (SendRequest<_>, Connection<_, _>) =
-//        hyper::client::conn::http1::handshake(/* TokioExecutor, */ stream).await?;
-//    tokio::task::spawn(async move {
-//        if let Err(e) = conn.await {
-//            tracing::error!(error = ?e, "connection failed")
-//        }
-//    });
-//
-//    let response = sender
-//        .send_request(
-//            Request::builder()
-//                .uri("/ctl?config")
-//                .header(hyper::header::HOST, socket.as_os_str().to_str().unwrap())
-//                .method("GET")
-//                .body(Empty::<Bytes>::new())
-//                .unwrap(),
-//        )
-//        .await?;
-//
-//    let body = response.collect().await?.aggregate();
-//
-//    Ok(serde_json::from_reader::<_, crate::config::Config>(body.reader()).unwrap())
-//}
+/// Send a request to the daemon's control API, returning the response body if successful.
+pub async fn request(
+    socket: &Path,
+    method: Method,
+    query: &str,
+    body: Bytes,
+) -> Result<Bytes, CtlError> {
+    let stream = UnixStream::connect(socket).await?;
+    let (mut sender, conn): (SendRequest<Full<Bytes>>, Connection<_, _>) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::task::spawn(async move {
+        if let Err(e) = conn.await {
+            tracing::error!(error = ?e, "connection failed")
+        }
+    });
+
+    let response = sender
+        .send_request(
+            Request::builder()
+                .uri(format!("/api?{query}"))
+                .header(hyper::header::HOST, "localhost")
+                .method(method)
+                .body(Full::new(body))?,
+        )
+        .await?;
+
+    let status = response.status();
+    let body = response.collect().await?.to_bytes();
+    if !status.is_success() {
+        return Err(CtlError::Status(
+            status,
+            String::from_utf8_lossy(&body).into_owned(),
+        ));
+    }
+    Ok(body)
+}
+
+pub async fn get_cfg(socket: &Path) -> Result<crate::config::Config, CtlError> {
+    let body = request(socket, Method::GET, "config", Bytes::new()).await?;
+    Ok(serde_json::from_reader::<_, crate::config::Config>(
+        body.reader(),
+    )?)
+}
@@ -1,10 +1,12 @@
 use crate::{
+    cli::Cli,
     config::Config,
     io::{SocketFormat, SystemdSocket, SystemdSocketType},
 };
 use crossbeam::sync::ShardedLock;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
 use std::{net::SocketAddr, os::fd::FromRawFd};
 use std::{os::unix, sync::Arc};
 use tokio::{
@@ -12,21 +14,62 @@ use tokio::{
     task::JoinSet,
 };
 use tokio_util::net::Listener;
+use tower::Layer;
+use tower_http::compression::CompressionLayer;
 
-use self::service::ServiceConfig;
+use self::{reload::Reloader, service::ServiceConfig};
 
+pub mod channels;
+pub mod compression;
+pub mod encoding;
+pub mod errors;
+pub mod finger;
+pub mod gemini;
+pub mod gopher;
+pub mod handler;
+pub mod https_redirect;
+pub mod indieauth;
+pub mod reload;
 pub mod service;
+pub mod templates;
+pub mod tls;
+pub mod upstreams;
+pub mod watch;
+pub mod webmentions;
 
-#[tracing::instrument(skip(cfg))]
-pub async fn run(cfg: Config) -> std::io::Result<()> {
+#[tracing::instrument(skip(cfg, args))]
+pub async fn run(cfg: Config, args: Arc<Cli>) -> std::io::Result<()> {
     tracing::debug!("initializing daemon...");
+    for problem in cfg.check() {
+        tracing::warn!("{problem}");
+    }
+    let certs = tls::Certificates::load(&cfg)
+        .map_err(|e| std::io::Error::other(format!("failed to load TLS certificates: {e}")))?;
     let cfg = Arc::new(ShardedLock::new(cfg));
+    let gemini_certs = Arc::new(gemini::Certificates::new(cfg.clone()));
+    let reloader = Reloader::new(
+        args,
+        cfg.clone(),
+        Arc::new(tls::CertResolver::new(certs)),
+        gemini_certs.clone(),
+    );
 
     let systemd_sockets = crate::io::collect_systemd_fds().unwrap();
+    if !cfg.read().unwrap().listen.gemini.is_empty()
+        || systemd_sockets.iter().any(|sock| sock.name == "gemini")
+    {
+        gemini_certs.refresh_blocking().await.map_err(|e| {
+            std::io::Error::other(format!("failed to load gemini certificates: {e}"))
+        })?;
+    }
     // let mut servers = JoinSet::new();
 
     let mut added_http = Vec::<SocketAddr>::new();
     let mut added_https = Vec::<SocketAddr>::new();
+    let mut added_redirect = Vec::<SocketAddr>::new();
+    let mut added_gemini = Vec::<SocketAddr>::new();
+    let mut added_gopher = Vec::<SocketAddr>::new();
+    let mut added_finger = Vec::<SocketAddr>::new();
     let mut added_unix = Vec::<unix::net::SocketAddr>::new();
 
     let mut tasks: JoinSet<Result<(), std::io::Error>> = JoinSet::new();
@@ -37,7 +80,8 @@ pub async fn run(cfg: Config) -> std::io::Result<()> {
                 fd,
                 ty: SystemdSocketType::Unix,
                 format: SocketFormat::Stream { listening: true },
-                name,
+                // unix sockets always serve HTTP with the control API, whatever their name
+                name: _,
             } => {
                 let listener = tokio::net::UnixListener::from_std(unsafe {
                     let unix = unix::net::UnixListener::from_raw_fd(fd);
@@ -45,7 +89,7 @@ pub async fn run(cfg: Config) -> std::io::Result<()> {
                     added_unix.push(unix.local_addr().unwrap());
                     unix
                 })?;
-                tasks.spawn(accept(cfg.clone(), ServiceConfig::UNIX, listener));
+                tasks.spawn(accept(reloader.clone(), ServiceConfig::UNIX, listener));
                 // let task = tokio::task::spawn(accept(stream));
                 // tasks.push(task);
             }
@@ -55,27 +99,39 @@ pub async fn run(cfg: Config) -> std::io::Result<()> {
                 format: SocketFormat::Stream { listening: true },
                 name,
             } => {
-                let https = matches!(name.as_str(), "https");
                 let listener = tokio::net::TcpListener::from_std(unsafe {
                     let tcp = std::net::TcpListener::from_raw_fd(fd);
                     tcp.set_nonblocking(true)?;
-                    if https {
-                        &mut added_https
-                    } else {
-                        &mut added_http
-                    }
-                    .push(tcp.local_addr().unwrap());
                     tcp
                 })?;
-                tasks.spawn(accept(
-                    cfg.clone(),
-                    if https {
-                        ServiceConfig::HTTPS
-                    } else {
-                        ServiceConfig::HTTP
-                    },
-                    listener,
-                ));
+                let addr = listener.local_addr()?;
+                match name.as_str() {
+                    "gemini" => {
+                        added_gemini.push(addr);
+                        tasks.spawn(gemini::accept(
+                            reloader.clone(),
+                            gemini_certs.clone(),
+                            listener,
+                        ));
+                    }
+                    "gopher" => {
+                        added_gopher.push(addr);
+                        tasks.spawn(gopher::accept(reloader.clone(), listener));
+                    }
+                    "finger" => {
+                        added_finger.push(addr);
+                        tasks.spawn(finger::accept(reloader.clone(), listener));
+                    }
+                    name => {
+                        let (svc_cfg, added) = match name {
+                            "https" => (ServiceConfig::HTTPS, &mut added_https),
+                            "http-redirect" => (ServiceConfig::HTTP_REDIRECT, &mut added_redirect),
+                            _ => (ServiceConfig::HTTP, &mut added_http),
+                        };
+                        added.push(addr);
+                        tasks.spawn(accept(reloader.clone(), svc_cfg, listener));
+                    }
+                }
                 // tasks.join_next().await;
 
                 // let _ = accept(stream).await;
@@ -90,33 +146,80 @@ pub async fn run(cfg: Config) -> std::io::Result<()> {
 
     for addr in &cfg.read().unwrap().listen.http {
         tasks.spawn(accept(
-            cfg.clone(),
+            reloader.clone(),
             ServiceConfig::HTTP,
             tokio::net::TcpListener::bind(addr).await?,
         ));
     }
     for addr in &cfg.read().unwrap().listen.https {
         tasks.spawn(accept(
-            cfg.clone(),
+            reloader.clone(),
             ServiceConfig::HTTPS,
             tokio::net::TcpListener::bind(addr).await?,
         ));
     }
-    for addr in &cfg.read().unwrap().listen.unix {
-        todo!()
-        // tasks.spawn(accept(
-        //     cfg.clone(),
-        //     tokio::net::UnixListener::bind(todo!())?,
-        // ));
+    for addr in &cfg.read().unwrap().listen.redirect {
+        tasks.spawn(accept(
+            reloader.clone(),
+            ServiceConfig::HTTP_REDIRECT,
+            tokio::net::TcpListener::bind(addr).await?,
+        ));
+    }
+    for addr in &cfg.read().unwrap().listen.gemini {
+        tasks.spawn(gemini::accept(
+            reloader.clone(),
+            gemini_certs.clone(),
+            tokio::net::TcpListener::bind(addr).await?,
+        ));
+    }
+    for addr in &cfg.read().unwrap().listen.gopher {
+        tasks.spawn(gopher::accept(
+            reloader.clone(),
+            tokio::net::TcpListener::bind(addr).await?,
+        ));
+    }
+    for addr in &cfg.read().unwrap().listen.finger {
+        tasks.spawn(finger::accept(
+            reloader.clone(),
+            tokio::net::TcpListener::bind(addr).await?,
+        ));
+    }
+    {
+        let cfg_r = cfg.read().unwrap();
+        for sock in &cfg_r.listen.unix {
+            tasks.spawn(accept(
+                reloader.clone(),
+                ServiceConfig::UNIX,
+                sock.bind(&cfg_r.directories.runtime)?,
+            ));
+        }
     }
 
     {
         let mut cfg = cfg.write().unwrap();
         cfg.listen.http.append(&mut added_http);
         cfg.listen.https.append(&mut added_https);
+        cfg.listen.redirect.append(&mut added_redirect);
+        cfg.listen.gemini.append(&mut added_gemini);
+        cfg.listen.gopher.append(&mut added_gopher);
+        cfg.listen.finger.append(&mut added_finger);
         // cfg.listen.unix.append(&mut added_unix);
     }
 
+    tasks.spawn(reload::on_hangup(reloader.clone()));
+    {
+        let server = &cfg.read().unwrap().server;
+        if server.watch_config {
+            let debounce = std::time::Duration::from_millis(server.watch_debounce_ms);
+            let reloader = reloader.clone();
+            tokio::task::spawn(async move {
+                if let Err(e) = watch::watch_config(reloader, debounce).await {
+                    tracing::error!(error = ?e, "failed to watch configuration");
+                }
+            });
+        }
+    }
+
     tracing::trace!("awaiting server results");
 
     while let Some(task) = tasks.join_next().await {
@@ -137,13 +240,30 @@ pub async fn run(cfg: Config) -> std::io::Result<()> {
     Ok(())
 }
 
+/// Listener addresses, which may identify a remote peer.
+trait PeerAddr {
+    fn peer(&self) -> Option<SocketAddr>;
+}
+
+impl PeerAddr for SocketAddr {
+    fn peer(&self) -> Option<SocketAddr> {
+        Some(*self)
+    }
+}
+
+impl PeerAddr for tokio::net::unix::SocketAddr {
+    fn peer(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
 #[allow(unreachable_code)]
-#[tracing::instrument(level = "info")]
+#[tracing::instrument(level = "info", skip(reloader))]
 async fn accept<
     Conn: AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug + 'static,
-    Addr: std::fmt::Debug,
+    Addr: PeerAddr + std::fmt::Debug,
 >(
-    cfg: Arc<ShardedLock<Config>>,
+    reloader: Reloader,
     svc_cfg: ServiceConfig,
     mut listener: impl Listener<Io = Conn, Addr = Addr> + std::fmt::Debug,
 ) -> Result<(), std::io::Error> {
@@ -151,9 +271,18 @@ async fn accept<
     // TODO :: Axum
     // let router = Router::<()>::new().route("/", routing::get(|| async { "Hello, world!" }));
 
-    let svc = tower::ServiceBuilder::new()
-        // .layer(TraceLayer::new_for_http())
-        .service(service::Service::new(cfg, &svc_cfg));
+    let compression = CompressionLayer::new()
+        .compress_when(compression::Predicate::new(reloader.config().clone()));
+    let tls = match svc_cfg.tls {
+        true => Some(
+            reloader
+                .certificates()
+                .acceptor()
+                .map_err(std::io::Error::other)?,
+        ),
+        false => None,
+    };
+    let svc = service::Service::new(reloader, &svc_cfg);
 
     let conn_builder = Arc::new(auto::Builder::new(TokioExecutor::new()));
 
@@ -167,10 +296,45 @@ async fn accept<
         };
         tracing::debug!(connection = ?conn, address = ?addr, "new connection");
         let conn_builder = conn_builder.clone();
+        let compression = compression.clone();
         let svc = svc.clone();
+        let tls = tls.clone();
+        let peer = addr.peer();
         tokio::task::spawn(async move {
-            let conn = conn_builder.serve_connection_with_upgrades(TokioIo::new(conn), svc);
-            if let Err(err) = conn.await {
+            let res = match tls {
+                Some(tls) => {
+                    let conn = match tls.accept(conn).await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            tracing::debug!(error = ?e, "TLS handshake failed");
+                            return;
+                        }
+                    };
+                    let info = service::Connection {
+                        peer,
+                        tls: true,
+                        sni: conn.get_ref().1.server_name().map(Arc::from),
+                    };
+                    let svc =
+                        TowerToHyperService::new(compression.layer(svc.with_connection(info)));
+                    conn_builder
+                        .serve_connection_with_upgrades(TokioIo::new(conn), svc)
+                        .await
+                }
+                None => {
+                    let info = service::Connection {
+                        peer,
+                        tls: false,
+                        sni: None,
+                    };
+                    let svc =
+                        TowerToHyperService::new(compression.layer(svc.with_connection(info)));
+                    conn_builder
+                        .serve_connection_with_upgrades(TokioIo::new(conn), svc)
+                        .await
+                }
+            };
+            if let Err(err) = res {
                 tracing::error!(error = err);
             }
         });
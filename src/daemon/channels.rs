@@ -0,0 +1,193 @@
+//! The running state of server-sent event channels: recent events, and their subscribers.
+
+use crate::config::{channels, Config};
+use bytes::Bytes;
+use crossbeam::sync::ShardedLock;
+use futures::{Stream, StreamExt};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+#[derive(Debug)]
+pub struct Event {
+    pub id: u64,
+    /// The event type, if not `message`.
+    pub event: Option<String>,
+    pub data: String,
+}
+
+impl Event {
+    /// This event in the `text/event-stream` format.
+    fn encode(&self) -> Bytes {
+        let mut res = format!("id: {}\n", self.id);
+        if let Some(event) = &self.event {
+            res.push_str(&format!("event: {event}\n"));
+        }
+        // each line break (in any style) separates `data` fields, which clients rejoin with `\n`
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            res.push_str(&format!("data: {line}\n"));
+        }
+        res.push('\n');
+        res.into()
+    }
+}
+
+#[derive(Debug)]
+struct History {
+    next_id: u64,
+    events: VecDeque<Arc<Event>>,
+}
+
+#[derive(Debug)]
+pub struct Channel {
+    cfg: parking_lot::Mutex<channels::Channel>,
+    history: parking_lot::Mutex<History>,
+    sender: broadcast::Sender<Arc<Event>>,
+}
+
+/// Events buffered for each subscriber; subscribers which fall further behind are disconnected,
+/// so that they reconnect and catch up from the replay buffer.
+const SUBSCRIBER_CAPACITY: usize = 64;
+
+impl Channel {
+    fn new(cfg: channels::Channel) -> Self {
+        // IDs start from the time the channel was created, so that they keep increasing across
+        // restarts, and clients don't mistake new events for ones they've already seen
+        let next_id = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(1, |d| d.as_millis() as u64);
+        Self {
+            cfg: parking_lot::Mutex::new(cfg),
+            history: parking_lot::Mutex::new(History {
+                next_id,
+                events: VecDeque::new(),
+            }),
+            sender: broadcast::channel(SUBSCRIBER_CAPACITY).0,
+        }
+    }
+
+    /// Send an event to every subscriber, returning its ID.
+    pub fn publish(&self, event: Option<String>, data: String) -> u64 {
+        let replay = self.cfg.lock().replay;
+        let mut history = self.history.lock();
+        let event = Arc::new(Event {
+            id: history.next_id,
+            event,
+            data,
+        });
+        history.next_id += 1;
+        history.events.push_back(event.clone());
+        while history.events.len() > replay {
+            history.events.pop_front();
+        }
+        // there may be no subscribers
+        let _ = self.sender.send(event.clone());
+        event.id
+    }
+
+    /// Whether `token` may publish to this channel.
+    pub fn authorizes(&self, token: &str) -> bool {
+        self.cfg
+            .lock()
+            .publish_tokens
+            .iter()
+            .any(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+    }
+
+    /// The encoded events after `last_id` (or none, if it's `None`), followed by each event
+    /// published from now on, interspersed with heartbeats. The stream ends if the channel is
+    /// removed, or if the subscriber falls too far behind.
+    pub fn subscribe(&self, last_id: Option<u64>) -> impl Stream<Item = Bytes> + Send + 'static {
+        let heartbeat = match self.cfg.lock().heartbeat_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        // subscribe while holding the history lock, so that no event is missed or repeated
+        let history = self.history.lock();
+        let receiver = self.sender.subscribe();
+        let replay = match last_id {
+            Some(last_id) => history
+                .events
+                .iter()
+                .filter(|e| e.id > last_id)
+                .map(|e| e.encode())
+                .collect(),
+            None => Vec::new(),
+        };
+        drop(history);
+
+        let heartbeats = heartbeat
+            .map(|every| tokio::time::interval_at(tokio::time::Instant::now() + every, every));
+        let live = futures::stream::unfold(
+            (receiver, heartbeats),
+            |(mut receiver, mut heartbeats)| async move {
+                let item = tokio::select! {
+                    event = receiver.recv() => match event {
+                        Ok(event) => event.encode(),
+                        Err(RecvError::Lagged(missed)) => {
+                            tracing::debug!(missed, "event subscriber fell behind; disconnecting");
+                            return None;
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                    _ = tick(&mut heartbeats) => Bytes::from_static(b":\n\n"),
+                };
+                Some((item, (receiver, heartbeats)))
+            },
+        );
+        futures::stream::iter(replay).chain(live)
+    }
+}
+
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Whether `event` may be used as an event type.
+pub fn is_event_type(event: &str) -> bool {
+    !event.is_empty() && !event.contains(['\r', '\n'])
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Every configured channel.
+#[derive(Debug, Default)]
+pub struct Channels {
+    channels: ShardedLock<HashMap<String, Arc<Channel>>>,
+}
+
+impl Channels {
+    /// Replace the running channels with those in `cfg`. Channels which remain keep their recent
+    /// events and subscribers; the subscribers of removed channels are disconnected.
+    pub fn update(&self, cfg: &Config) {
+        let mut channels = self.channels.write().unwrap();
+        *channels = cfg
+            .channels
+            .iter()
+            .map(|(name, channel_cfg)| {
+                let channel = match channels.get(name) {
+                    Some(channel) => {
+                        *channel.cfg.lock() = channel_cfg.clone();
+                        channel.clone()
+                    }
+                    None => Arc::new(Channel::new(channel_cfg.clone())),
+                };
+                (name.clone(), channel)
+            })
+            .collect();
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Channel>> {
+        self.channels.read().unwrap().get(name).cloned()
+    }
+}
@@ -0,0 +1,206 @@
+//! On-the-fly response compression, and the persistent cache of compressed static files.
+
+use super::encoding::Encoding;
+use crate::config::{self, Config};
+use crossbeam::sync::ShardedLock;
+use hyper::{header, Response};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+/// A response extension which prevents the response from being compressed on the fly, either
+/// because its route opted out, or because it's already been compressed as appropriate.
+#[derive(Debug, Clone, Copy)]
+pub struct NoCompression;
+
+/// Decides which responses are compressed by [tower_http::compression::CompressionLayer], per the
+/// current configuration.
+#[derive(Debug, Clone)]
+pub struct Predicate {
+    cfg: Arc<ShardedLock<Config>>,
+}
+
+impl Predicate {
+    pub fn new(cfg: Arc<ShardedLock<Config>>) -> Self {
+        Self { cfg }
+    }
+}
+
+impl tower_http::compression::Predicate for Predicate {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: http_body::Body,
+    {
+        if response.extensions().get::<NoCompression>().is_some() {
+            return false;
+        }
+        let size = response.body().size_hint().exact().or_else(|| {
+            response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|h| h.to_str().ok()?.parse().ok())
+        });
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok());
+        let cfg = self.cfg.read().unwrap();
+        cfg.compression.enabled && is_compressible(&cfg.compression, content_type, size)
+    }
+}
+
+/// Whether a response with `content_type` and `size` (if known) should be compressed.
+pub fn is_compressible(
+    cfg: &config::Compression,
+    content_type: Option<&str>,
+    size: Option<u64>,
+) -> bool {
+    let Some(content_type) = content_type else {
+        return false;
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    size.is_none_or(|size| size >= cfg.min_size)
+        && cfg.types.iter().any(|ty| match ty.strip_suffix("/*") {
+            Some(top) => essence
+                .split_once('/')
+                .is_some_and(|(t, _)| t.eq_ignore_ascii_case(top)),
+            None => essence.eq_ignore_ascii_case(ty),
+        })
+}
+
+type FileKey = (PathBuf, u64, Option<SystemTime>);
+
+/// Remembers the content hashes of files we've seen, so that we only need to hash each version of a
+/// file once.
+#[derive(Debug, Default)]
+struct Hashes {
+    hashes: HashMap<FileKey, blake3::Hash>,
+    /// Compressions currently running, so that concurrent requests don't duplicate work.
+    pending: HashSet<(FileKey, Encoding)>,
+}
+
+/// The most file hashes to remember before forgetting all of them.
+const MAX_HASHES: usize = 4096;
+
+/// The most files to compress into the cache at once; the rest wait their turn.
+const MAX_COMPRESSIONS: usize = 2;
+
+lazy_static::lazy_static! {
+    static ref HASHES: parking_lot::Mutex<Hashes> = Default::default();
+    static ref COMPRESSIONS: tokio::sync::Semaphore = tokio::sync::Semaphore::new(MAX_COMPRESSIONS);
+}
+
+/// Find the cached copy of the file at `path` compressed with `encoding`, returning its path &
+/// length.
+///
+/// If it isn't cached yet, it's compressed in the background, and `None` is returned.
+pub async fn cached(
+    cache_dir: &Path,
+    path: &Path,
+    meta: &std::fs::Metadata,
+    encoding: Encoding,
+) -> Option<(PathBuf, u64)> {
+    let key = (path.to_owned(), meta.len(), meta.modified().ok());
+    let hash = HASHES.lock().hashes.get(&key).copied();
+    if let Some(hash) = hash {
+        let cached = cache_path(cache_dir, &hash, encoding);
+        if let Ok(meta) = tokio::fs::metadata(&cached).await {
+            return Some((cached, meta.len()));
+        }
+    }
+
+    if !HASHES.lock().pending.insert((key.clone(), encoding)) {
+        return None;
+    }
+    let cache_dir = cache_dir.to_owned();
+    tokio::task::spawn(async move {
+        let _permit = COMPRESSIONS.acquire().await;
+        if let Err(e) = compress_into_cache(&cache_dir, &key, encoding).await {
+            tracing::warn!(path = ?key.0, encoding = encoding.name(), error = ?e, "failed to cache compressed file");
+        }
+        HASHES.lock().pending.remove(&(key, encoding));
+    });
+    None
+}
+
+fn cache_path(cache_dir: &Path, hash: &blake3::Hash, encoding: Encoding) -> PathBuf {
+    cache_dir
+        .join("compressed")
+        .join(format!("{}.{}", hash.to_hex(), encoding.extension()))
+}
+
+#[tracing::instrument(skip(cache_dir))]
+async fn compress_into_cache(
+    cache_dir: &Path,
+    key: &FileKey,
+    encoding: Encoding,
+) -> std::io::Result<()> {
+    let path = key.0.clone();
+    let hash = tokio::task::spawn_blocking(move || {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(std::fs::File::open(path)?)?;
+        std::io::Result::Ok(hasher.finalize())
+    })
+    .await??;
+    {
+        let mut hashes = HASHES.lock();
+        if hashes.hashes.len() >= MAX_HASHES {
+            hashes.hashes.clear();
+        }
+        hashes.hashes.insert(key.clone(), hash);
+    }
+
+    let cached = cache_path(cache_dir, &hash, encoding);
+    if tokio::fs::try_exists(&cached).await? {
+        return Ok(());
+    }
+    tokio::fs::create_dir_all(cached.parent().unwrap()).await?;
+    // write to a temporary file first, so that partially-written files are never served; identical
+    // files at other paths may be compressed at the same time, so each gets its own
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let tmp = cached.with_extension(format!(
+        "{}.{}.tmp",
+        encoding.extension(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let (path, output) = (key.0.clone(), tmp.clone());
+    // the strongest settings are slow, so they're kept off of the async runtime
+    let compressed = tokio::task::spawn_blocking(move || compress_file(&path, &output, encoding))
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|res| res);
+    if let Err(e) = compressed {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&tmp, &cached).await?;
+    tracing::debug!(?cached, "cached compressed file");
+    Ok(())
+}
+
+fn compress_file(input: &Path, output: &Path, encoding: Encoding) -> std::io::Result<()> {
+    let mut input = std::fs::File::open(input)?;
+    let mut output = std::fs::File::create(output)?;
+    match encoding {
+        Encoding::Brotli => {
+            let params = brotli::enc::BrotliEncoderParams {
+                quality: 11,
+                ..Default::default()
+            };
+            brotli::enc::BrotliCompress(&mut input, &mut output, &params)?;
+        }
+        Encoding::Zstd => zstd::stream::copy_encode(&mut input, &mut output, 19)?,
+        Encoding::Gzip => {
+            let mut enc = flate2::write::GzEncoder::new(output, flate2::Compression::best());
+            std::io::copy(&mut input, &mut enc)?;
+            output = enc.finish()?;
+        }
+    }
+    output.sync_all()
+}
@@ -0,0 +1,70 @@
+//! Content-coding negotiation.
+
+use hyper::{header, HeaderMap};
+
+/// Content codings we can serve, in order of our preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub const ALL: [Self; 3] = [Self::Brotli, Self::Zstd, Self::Gzip];
+
+    /// The name used in `Accept-Encoding`/`Content-Encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// The file extension used for files compressed with this coding.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zst",
+            Self::Gzip => "gz",
+        }
+    }
+}
+
+/// The codings accepted by a request, most preferred first; ties in the client's preference are
+/// broken by ours.
+pub fn accepted(headers: &HeaderMap) -> Vec<Encoding> {
+    let mut weights = Vec::<(&str, f32)>::new();
+    for value in headers.get_all(header::ACCEPT_ENCODING) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for item in value.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let Some(coding) = params.next().filter(|c| !c.is_empty()) else {
+                continue;
+            };
+            let q = params
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            weights.push((coding, q));
+        }
+    }
+    let weight = |encoding: Encoding| {
+        weights
+            .iter()
+            .find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.name()))
+            .or_else(|| weights.iter().find(|(coding, _)| *coding == "*"))
+            .map_or(0.0, |(_, q)| *q)
+    };
+    let mut res = Encoding::ALL
+        .into_iter()
+        .map(|e| (e, weight(e)))
+        .filter(|(_, q)| *q > 0.0)
+        .collect::<Vec<_>>();
+    // stable, so our order breaks ties
+    res.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    res.into_iter().map(|(e, _)| e).collect()
+}
@@ -0,0 +1,106 @@
+//! Bodies for error responses: an HTML page, or problem details (RFC 9457) for clients which ask
+//! for JSON.
+
+use super::{
+    handler::{accepts, escape},
+    service::{full, Connection, SvcResponse},
+};
+use crate::config::{routes::request_host, Config};
+use crossbeam::sync::ShardedLock;
+use hyper::{body::Body, header, Request, StatusCode};
+use serde::Serialize;
+
+const DEFAULT_PAGE: &str = "\
+<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>{{status}} {{reason}}</title></head>
+<body><h1>{{status}} {{reason}}</h1></body>
+</html>
+";
+
+/// What error responses need to know about a request, saved before it's consumed.
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    host: Option<String>,
+    path: String,
+    json: bool,
+}
+
+impl RequestInfo {
+    pub fn new<B>(req: &Request<B>, conn: &Connection) -> Self {
+        Self {
+            host: request_host(req).or(conn.sni.as_deref()).map(str::to_owned),
+            path: req.uri().path().to_owned(),
+            json: accepts(req, &["application/json", "application/problem+json"]),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Problem<'r> {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    title: &'r str,
+    status: u16,
+    instance: &'r str,
+}
+
+/// Give `res` a body describing its status, if it's an error without one.
+pub async fn describe(
+    cfg: &ShardedLock<Config>,
+    info: &RequestInfo,
+    res: SvcResponse,
+) -> SvcResponse {
+    let status = res.status();
+    if !(status.is_client_error() || status.is_server_error()) || !res.body().is_end_stream() {
+        return res;
+    }
+    let reason = status.canonical_reason().unwrap_or_default();
+    let (content_type, body) = match info.json {
+        true => (
+            "application/problem+json",
+            serde_json::to_string(&Problem {
+                ty: "about:blank",
+                title: reason,
+                status: status.as_u16(),
+                instance: &info.path,
+            })
+            .unwrap(),
+        ),
+        false => {
+            let page = cfg
+                .read()
+                .unwrap()
+                .virtual_host(info.host.as_deref())
+                .error_page(status);
+            let template = match page {
+                Some(page) => match tokio::fs::read_to_string(&page).await {
+                    Ok(template) => template,
+                    Err(e) => {
+                        tracing::warn!(?page, error = ?e, "failed to read error page");
+                        DEFAULT_PAGE.to_owned()
+                    }
+                },
+                None => DEFAULT_PAGE.to_owned(),
+            };
+            (
+                "text/html; charset=utf-8",
+                render(&template, status, reason, &info.path),
+            )
+        }
+    };
+    let (mut parts, _) = res.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(content_type),
+    );
+    SvcResponse::from_parts(parts, full(body))
+}
+
+fn render(template: &str, status: StatusCode, reason: &str, path: &str) -> String {
+    template
+        .replace("{{status}}", status.as_str())
+        .replace("{{reason}}", &escape(reason))
+        .replace("{{path}}", &escape(path))
+}
@@ -0,0 +1,106 @@
+//! Answering [Finger](https://www.rfc-editor.org/rfc/rfc1288) queries with users' plan files.
+
+use super::reload::Reloader;
+use crate::config::Config;
+use crossbeam::sync::ShardedLock;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// The longest query, excluding its CRLF.
+const MAX_QUERY_SIZE: u64 = 256;
+
+/// How long to wait for a client to send its query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn accept(reloader: Reloader, listener: TcpListener) -> Result<(), std::io::Error> {
+    tracing::debug!(?listener, "accepting finger connections");
+    loop {
+        let (mut conn, peer) = match listener.accept().await {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to initialize stream");
+                continue;
+            }
+        };
+        let cfg = reloader.config().clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = serve(&cfg, &mut conn, peer).await {
+                tracing::debug!(error = ?e, %peer, "failed to serve finger query");
+            }
+            let _ = conn.shutdown().await;
+        });
+    }
+}
+
+async fn serve(
+    cfg: &ShardedLock<Config>,
+    conn: &mut TcpStream,
+    peer: SocketAddr,
+) -> std::io::Result<()> {
+    let mut line = Vec::new();
+    let mut reader = BufReader::new((&mut *conn).take(MAX_QUERY_SIZE + 2));
+    let read = reader.read_until(b'\n', &mut line);
+    match tokio::time::timeout(QUERY_TIMEOUT, read).await {
+        Err(_) => return Ok(()),
+        Ok(read) => read?,
+    };
+    let res = match line.strip_suffix(b"\r\n").map(std::str::from_utf8) {
+        Some(Ok(query)) => {
+            tracing::debug!(query, %peer, "received finger query");
+            respond(cfg, query).await
+        }
+        _ => "Queries must be text, ending with CRLF.\n".to_owned(),
+    };
+    conn.write_all(crlf(&res).as_bytes()).await?;
+    conn.flush().await
+}
+
+async fn respond(cfg: &ShardedLock<Config>, query: &str) -> String {
+    // `/W` asks for verbose output, which is the only kind there is
+    let query = query.trim();
+    let user = match query.strip_prefix("/W") {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => rest.trim_start(),
+        _ => query,
+    };
+    if user.contains('@') {
+        return "Finger forwarding is not supported.\n".to_owned();
+    }
+    let plan = {
+        let cfg = cfg.read().unwrap();
+        if user.is_empty() {
+            return match cfg.finger.list_users {
+                true => cfg
+                    .finger
+                    .users
+                    .keys()
+                    .map(|user| format!("{user}\n"))
+                    .collect(),
+                false => "Finger online user list denied.\n".to_owned(),
+            };
+        }
+        cfg.finger.users.get(user).cloned()
+    };
+    let Some(plan) = plan else {
+        return format!("{user}: no such user.\n");
+    };
+    match tokio::fs::read_to_string(&plan).await {
+        Ok(plan) => plan,
+        Err(e) => {
+            tracing::error!(error = ?e, ?plan, "failed to read plan file");
+            format!("{user}: no plan.\n")
+        }
+    }
+}
+
+/// Responses are lines of ASCII, ending in CRLF, although most clients accept UTF-8.
+fn crlf(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for line in text.lines() {
+        res.push_str(line);
+        res.push_str("\r\n");
+    }
+    res
+}
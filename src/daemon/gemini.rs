@@ -0,0 +1,549 @@
+//! Serving capsules over the [Gemini protocol](https://geminiprotocol.net/docs/protocol-specification.gmi).
+
+use super::{
+    handler::{autoindex::SEGMENT, static_files},
+    reload::Reloader,
+    tls,
+};
+use crate::config::{
+    hosts::{self, Host, Tls},
+    Config,
+};
+use crossbeam::sync::ShardedLock;
+use percent_encoding::utf8_percent_encode;
+use rustls::{
+    crypto::ring,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+use tokio_rustls::TlsAcceptor;
+use url::Url;
+
+pub mod gemtext;
+
+/// The longest request, excluding its CRLF.
+const MAX_REQUEST_SIZE: u64 = 1024;
+
+/// How long to wait for a client to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The files served for a directory, in order of preference.
+const INDEX: &[&str] = &["index.gmi", "index.md"];
+
+const GEMTEXT: &str = "text/gemini; charset=utf-8";
+
+/// Self-signed certificates for each host, kept in `${state_dir}/gemini`. They're loaded (and
+/// generated where missing) by [refresh](Self::refresh), at startup & on reload, so that
+/// handshakes needn't wait on files.
+#[derive(Debug)]
+pub struct Certificates {
+    cfg: Arc<ShardedLock<Config>>,
+    /// By the name they were generated for.
+    keys: ShardedLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl Certificates {
+    pub fn new(cfg: Arc<ShardedLock<Config>>) -> Self {
+        Self {
+            cfg,
+            keys: Default::default(),
+        }
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> Result<TlsAcceptor, rustls::Error> {
+        let cfg = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        Ok(TlsAcceptor::from(Arc::new(cfg)))
+    }
+
+    /// Load the certificate for each host (& the default host), generating any which don't exist
+    /// yet, and replace those loaded before. This reads & writes files, so it shouldn't be called
+    /// from async code.
+    pub fn refresh(&self) -> Result<(), CertificateError> {
+        let (identities, dir) = {
+            let cfg = self.cfg.read().unwrap();
+            let mut identities = cfg
+                .hosts
+                .iter()
+                .map(|host| identity(&cfg, Some(host)))
+                .collect::<Vec<_>>();
+            identities.push(identity(&cfg, None));
+            (identities, cfg.directories.state.join("gemini"))
+        };
+        let mut keys = HashMap::with_capacity(identities.len());
+        for (name, alt_names) in identities {
+            if keys.contains_key(&name) {
+                continue;
+            }
+            let file_name = name.replace('*', "_");
+            let tls = Tls {
+                certificate: dir.join(format!("{file_name}.crt")),
+                key: dir.join(format!("{file_name}.key")),
+            };
+            if !tls.certificate.exists() || !tls.key.exists() {
+                generate(&tls, alt_names)?;
+                tracing::info!(name, certificate = ?tls.certificate, "generated gemini certificate");
+            }
+            let key = tls::load_key(&tls).map_err(|e| CertificateError::Load(e.to_string()))?;
+            keys.insert(name, key);
+        }
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// Like [refresh](Self::refresh), but run on the blocking thread pool.
+    pub async fn refresh_blocking(self: &Arc<Self>) -> Result<(), CertificateError> {
+        let certs = self.clone();
+        tokio::task::spawn_blocking(move || certs.refresh())
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    /// The certificate for the host serving `name`.
+    fn get(&self, name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let name = {
+            let cfg = self.cfg.read().unwrap();
+            let host = cfg.virtual_host(name).index.map(|i| &cfg.hosts[i]);
+            identity(&cfg, host).0
+        };
+        self.keys.read().unwrap().get(&name).cloned()
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = self.get(client_hello.server_name());
+        if key.is_none() {
+            tracing::error!(sni = ?client_hello.server_name(), "no certificate for gemini connection");
+        }
+        key
+    }
+}
+
+/// The name `host`'s certificate is kept under, and the names it's valid for.
+fn identity(cfg: &Config, host: Option<&Host>) -> (String, Vec<String>) {
+    let name = host
+        .and_then(|host| {
+            host.canonical_name()
+                .or(host.names.first().map(String::as_str))
+        })
+        .or(Some(cfg.server.domain.as_str()).filter(|d| !d.is_empty()))
+        .unwrap_or("localhost")
+        .to_owned();
+    let alt_names = match host {
+        Some(host) => host.names.iter().chain(&host.aliases).cloned().collect(),
+        None => vec![name.clone()],
+    };
+    (name, alt_names)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CertificateError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("failed to generate certificate: {0}")]
+    Generate(#[from] rcgen::Error),
+    #[error("failed to load certificate: {0}")]
+    Load(String),
+}
+
+/// Write a new self-signed certificate for `alt_names`.
+fn generate(tls: &Tls, alt_names: Vec<String>) -> Result<(), CertificateError> {
+    use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt};
+    let generated = rcgen::generate_simple_self_signed(alt_names)?;
+    std::fs::create_dir_all(tls.key.parent().unwrap())?;
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tls.key)?
+        .write_all(generated.signing_key.serialize_pem().as_bytes())?;
+    std::fs::write(&tls.certificate, generated.cert.pem())?;
+    Ok(())
+}
+
+pub async fn accept(
+    reloader: Reloader,
+    certs: Arc<Certificates>,
+    listener: TcpListener,
+) -> Result<(), std::io::Error> {
+    tracing::debug!(?listener, "accepting gemini connections");
+    let tls = certs.acceptor().map_err(std::io::Error::other)?;
+    loop {
+        let (conn, peer) = match listener.accept().await {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to initialize stream");
+                continue;
+            }
+        };
+        let (tls, cfg) = (tls.clone(), reloader.config().clone());
+        tokio::task::spawn(async move {
+            let mut conn = match tls.accept(conn).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    tracing::debug!(error = ?e, "TLS handshake failed");
+                    return;
+                }
+            };
+            let sni = conn.get_ref().1.server_name().map(str::to_owned);
+            if let Err(e) = serve(&cfg, &mut conn, peer, sni.as_deref()).await {
+                tracing::debug!(error = ?e, %peer, "failed to serve gemini request");
+            }
+            let _ = conn.shutdown().await;
+        });
+    }
+}
+
+/// A response's status, its meta line (a content type, message, or URL), and its body.
+#[derive(Debug)]
+struct Response {
+    status: u8,
+    meta: String,
+    body: Body,
+}
+
+#[derive(Debug)]
+enum Body {
+    None,
+    Text(String),
+    File(tokio::fs::File),
+}
+
+impl Response {
+    fn status(status: u8, meta: impl Into<String>) -> Self {
+        Self {
+            status,
+            meta: meta.into(),
+            body: Body::None,
+        }
+    }
+
+    fn gemtext(text: String) -> Self {
+        Self {
+            status: 20,
+            meta: GEMTEXT.to_owned(),
+            body: Body::Text(text),
+        }
+    }
+}
+
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    cfg: &ShardedLock<Config>,
+    conn: &mut S,
+    peer: SocketAddr,
+    sni: Option<&str>,
+) -> std::io::Result<()> {
+    let mut line = Vec::new();
+    let mut reader = BufReader::new((&mut *conn).take(MAX_REQUEST_SIZE + 2));
+    let read = reader.read_until(b'\n', &mut line);
+    let res = match tokio::time::timeout(REQUEST_TIMEOUT, read).await {
+        Err(_) => return Ok(()),
+        Ok(read) => {
+            read?;
+            match line.strip_suffix(b"\r\n") {
+                Some(request) => match std::str::from_utf8(request).map(Url::parse) {
+                    Ok(Ok(url)) => {
+                        tracing::debug!(%url, %peer, "received gemini request");
+                        respond(cfg, &url, sni).await
+                    }
+                    _ => Response::status(59, "the request must be a URL"),
+                },
+                None => Response::status(59, "the request must be a URL, ending with CRLF"),
+            }
+        }
+    };
+    tracing::debug!(status = res.status, meta = res.meta, %peer, "responding to gemini request");
+
+    conn.write_all(format!("{} {}\r\n", res.status, res.meta).as_bytes())
+        .await?;
+    match res.body {
+        Body::None => {}
+        Body::Text(text) => conn.write_all(text.as_bytes()).await?,
+        Body::File(mut file) => {
+            tokio::io::copy(&mut file, conn).await?;
+        }
+    }
+    conn.flush().await
+}
+
+async fn respond(cfg: &ShardedLock<Config>, url: &Url, sni: Option<&str>) -> Response {
+    if url.scheme() != "gemini" {
+        return Response::status(53, "only gemini URLs are served here");
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Response::status(59, "URLs must not have user info");
+    }
+    let (root, redirect_to, markdown, autoindex) = {
+        let cfg = cfg.read().unwrap();
+        let name = url.host_str().or(sni);
+        if !name.is_none_or(|name| serves(&cfg, name)) {
+            return Response::status(53, "that host isn't served here");
+        }
+        let host = cfg.virtual_host(name);
+        (
+            host.capsule.map(Path::to_owned),
+            host.redirect_to.map(str::to_owned),
+            cfg.gemini.markdown,
+            cfg.gemini.autoindex,
+        )
+    };
+    if let Some(name) = redirect_to {
+        let mut location = url.clone();
+        return match location.set_host(Some(&name)) {
+            Ok(()) => Response::status(31, location),
+            Err(_) => Response::status(51, "not found"),
+        };
+    }
+    let Some(root) = root else {
+        return Response::status(51, "there's no capsule here");
+    };
+    match find(&root, url.path(), markdown).await {
+        Ok(Found::File(path)) => {
+            let meta = match path.extension().and_then(OsStr::to_str) {
+                Some("gmi" | "gemini") => GEMTEXT.to_owned(),
+                _ => static_files::content_type(&path),
+            };
+            match tokio::fs::File::open(&path).await {
+                Ok(file) => Response {
+                    status: 20,
+                    meta,
+                    body: Body::File(file),
+                },
+                Err(e) => failure(e),
+            }
+        }
+        Ok(Found::Markdown(path)) => match tokio::fs::read_to_string(&path).await {
+            Ok(source) => Response::gemtext(gemtext::from_markdown(&source)),
+            Err(e) => failure(e),
+        },
+        Ok(Found::Directory(path)) if autoindex => match index(&path, url.path()).await {
+            Ok(listing) => Response::gemtext(listing),
+            Err(e) => failure(e),
+        },
+        Ok(Found::Directory(_) | Found::Nothing) => Response::status(51, "not found"),
+        Ok(Found::Redirect(path)) => {
+            let mut location = url.clone();
+            location.set_path(&path);
+            Response::status(31, location)
+        }
+        Err(e) => failure(e),
+    }
+}
+
+/// Whether `name` is one of the configured hosts' names or aliases, or the server's domain;
+/// without either, any name is served.
+fn serves(cfg: &Config, name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    (cfg.hosts.is_empty() && cfg.server.domain.is_empty())
+        || name.eq_ignore_ascii_case(&cfg.server.domain)
+        || hosts::find(&cfg.hosts, name).is_some()
+}
+
+fn failure(e: std::io::Error) -> Response {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => Response::status(51, "not found"),
+        _ => {
+            tracing::error!(error = ?e, "failed to serve gemini request");
+            Response::status(40, "temporary failure")
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Found {
+    File(PathBuf),
+    /// A Markdown file, to convert to gemtext.
+    Markdown(PathBuf),
+    /// A directory without an index.
+    Directory(PathBuf),
+    /// A directory requested without a trailing slash, to redirect to this path.
+    Redirect(String),
+    Nothing,
+}
+
+async fn find(root: &Path, request_path: &str, markdown: bool) -> std::io::Result<Found> {
+    // hidden files (ex. `.git`) aren't served, however their names are encoded
+    let decoded = percent_encoding::percent_decode_str(request_path).decode_utf8_lossy();
+    if decoded.split('/').any(|s| s.starts_with('.')) {
+        return Ok(Found::Nothing);
+    }
+    let root = tokio::fs::canonicalize(root).await?;
+    let resolve = |path: String| {
+        let root = &root;
+        async move {
+            static_files::resolve(root, &path)
+                .await
+                .map_err(std::io::Error::other)
+        }
+    };
+    let is_markdown = |path: &Path| markdown && path.extension() == Some(OsStr::new("md"));
+
+    let last = request_path.rsplit('/').next().unwrap_or_default();
+    if !last.is_empty() && !last.contains('.') {
+        let extensions = match markdown {
+            true => &["gmi", "md"][..],
+            false => &["gmi"][..],
+        };
+        for ext in extensions {
+            if let Some(path) = resolve(format!("{request_path}.{ext}")).await? {
+                if tokio::fs::metadata(&path).await?.is_file() {
+                    return Ok(match is_markdown(&path) {
+                        true => Found::Markdown(path),
+                        false => Found::File(path),
+                    });
+                }
+            }
+        }
+    }
+    let Some(path) = resolve(request_path.to_owned()).await? else {
+        return Ok(Found::Nothing);
+    };
+    if !tokio::fs::metadata(&path).await?.is_dir() {
+        return Ok(match is_markdown(&path) {
+            true => Found::Markdown(path),
+            false => Found::File(path),
+        });
+    }
+    // relative links in index pages only work if the directory path ends with a slash
+    if !request_path.ends_with('/') {
+        return Ok(Found::Redirect(format!("{request_path}/")));
+    }
+    for name in INDEX {
+        let index = path.join(name);
+        if tokio::fs::metadata(&index).await.is_ok_and(|m| m.is_file()) {
+            return Ok(match is_markdown(&index) {
+                true => Found::Markdown(index),
+                false if *name == "index.gmi" => Found::File(index),
+                false => continue,
+            });
+        }
+    }
+    Ok(Found::Directory(path))
+}
+
+/// A gemtext listing of `dir`'s (non-hidden) entries.
+async fn index(dir: &Path, request_path: &str) -> std::io::Result<String> {
+    let mut entries = Vec::new();
+    let mut read = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let is_dir = entry.file_type().await?.is_dir();
+        entries.push((name, is_dir));
+    }
+    entries.sort();
+
+    let mut res = format!("# Index of {request_path}\n\n");
+    if request_path != "/" {
+        res.push_str("=> ../ ../\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        res.push_str(&format!(
+            "=> {}{slash} {name}{slash}\n",
+            utf8_percent_encode(&name, SEGMENT)
+        ));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn served_hosts() {
+        let cfg = config(
+            r#"
+            server.domain = "a.test"
+            [[hosts]]
+            names = ["b.test", "*.b.test"]
+            aliases = ["old.test"]
+            "#,
+        );
+        for name in ["a.test", "A.TEST.", "b.test", "x.b.test", "old.test"] {
+            assert!(serves(&cfg, name), "{name}");
+        }
+        for name in ["c.test", "b.test.evil", "localhost", "127.0.0.1"] {
+            assert!(!serves(&cfg, name), "{name}");
+        }
+        // without any names, there's nothing to go by
+        assert!(serves(&config(""), "c.test"));
+    }
+
+    #[tokio::test]
+    async fn hidden_files_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join(".git/config"), "secret").unwrap();
+        std::fs::write(root.join("dir/.env"), "secret").unwrap();
+        std::fs::write(root.join("dir/index.gmi"), "# Dir").unwrap();
+        std::fs::write(root.join("post.md"), "# Post").unwrap();
+
+        for path in [
+            "/.git/config",
+            "/%2egit/config",
+            "/%2Egit/config",
+            "/dir/%2eenv",
+            "/dir%2f.env",
+            "/dir/%2e%2e/.git/config",
+        ] {
+            let found = find(root, path, true).await.unwrap();
+            assert!(matches!(found, Found::Nothing), "{path}: {found:?}");
+        }
+        let found = |path| async move { find(root, path, true).await.unwrap() };
+        assert!(matches!(found("/dir/").await, Found::File(p) if p.ends_with("dir/index.gmi")));
+        assert!(matches!(found("/dir").await, Found::Redirect(p) if p == "/dir/"));
+        assert!(matches!(found("/post").await, Found::Markdown(p) if p.ends_with("post.md")));
+        assert!(matches!(found("/p%6fst").await, Found::Markdown(_)));
+        let found = find(root, "/post", false).await.unwrap();
+        assert!(matches!(found, Found::Nothing));
+    }
+
+    #[tokio::test]
+    async fn responses() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.gmi"), "# Home").unwrap();
+        let cfg = ShardedLock::new(config(&format!(
+            "server.domain = \"a.test\"\ngemini.root = {:?}\n[[hosts]]\nnames = [\"b.test\"]\naliases = [\"old.test\"]",
+            dir.path()
+        )));
+        let status = |url: &str, sni: Option<&'static str>| {
+            let url = Url::parse(url).unwrap();
+            let cfg = &cfg;
+            async move { respond(cfg, &url, sni).await.status }
+        };
+        assert_eq!(status("gemini://a.test/", None).await, 20);
+        assert_eq!(status("gemini://b.test/", None).await, 20);
+        assert_eq!(status("gemini://old.test/", None).await, 31);
+        assert_eq!(status("gemini://c.test/", Some("a.test")).await, 53);
+        assert_eq!(status("https://a.test/", None).await, 53);
+        assert_eq!(status("gemini://a.test/%2egit/config", None).await, 51);
+        assert_eq!(status("gemini://user@a.test/", None).await, 59);
+    }
+}
@@ -0,0 +1,220 @@
+//! Converting Markdown to gemtext.
+//!
+//! Gemtext has no inline markup, so emphasis is dropped, and links & images are listed after the
+//! block containing them (unless they're the whole block).
+
+use crate::daemon::handler::markdown;
+use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+
+/// Convert a Markdown document (which may have front matter) to gemtext. If the front matter has
+/// a `title`, and the document doesn't start with a top-level heading, it's added as one.
+pub fn from_markdown(source: &str) -> String {
+    let (meta, content) = markdown::front_matter(source);
+    let title = meta
+        .ok()
+        .and_then(|meta| meta.get("title")?.as_str().map(str::to_owned));
+
+    let mut writer = Writer::default();
+    let mut events = Parser::new_ext(content, Options::ENABLE_STRIKETHROUGH).peekable();
+    let starts_with_title = matches!(
+        events.peek(),
+        Some(Event::Start(Tag::Heading {
+            level: HeadingLevel::H1,
+            ..
+        }))
+    );
+    if let (Some(title), false) = (title, starts_with_title) {
+        writer.out.push_str(&format!("# {}\n\n", title.trim()));
+    }
+    for event in events {
+        writer.event(event);
+    }
+    let mut res = writer.out.trim_end().to_owned();
+    res.push('\n');
+    res
+}
+
+#[derive(Debug, Default)]
+struct Writer {
+    out: String,
+    /// The text of the current line.
+    line: String,
+    /// The links in the current block, by URL & text.
+    links: Vec<(String, String)>,
+    /// The link or image being read, by URL & text.
+    link: Option<(String, String)>,
+    /// Whether `link` is an image, whose text isn't part of the line.
+    image: bool,
+    heading: Option<HeadingLevel>,
+    quote_depth: usize,
+    list_depth: usize,
+    code: bool,
+}
+
+impl Writer {
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(Tag::Heading { level, .. }) => self.heading = Some(level),
+            Event::End(TagEnd::Heading(_)) => {
+                let marker = match self.heading.take() {
+                    Some(HeadingLevel::H1) => "#",
+                    Some(HeadingLevel::H2) => "##",
+                    _ => "###",
+                };
+                let text = std::mem::take(&mut self.line);
+                self.out.push_str(&format!("{marker} {}\n", text.trim()));
+                self.flush_links();
+                self.out.push('\n');
+            }
+            Event::End(TagEnd::Paragraph) if self.list_depth == 0 => {
+                self.flush_block();
+                self.out.push('\n');
+            }
+            Event::Start(Tag::BlockQuote(_)) => self.quote_depth += 1,
+            Event::End(TagEnd::BlockQuote(_)) => self.quote_depth -= 1,
+            Event::Start(Tag::List(_)) => self.list_depth += 1,
+            Event::End(TagEnd::List(_)) => {
+                self.list_depth -= 1;
+                if self.list_depth == 0 {
+                    self.out.push('\n');
+                }
+            }
+            // gemtext lists can't nest, so nested items are flattened
+            Event::Start(Tag::Item) => {
+                if !self.line.trim().is_empty() {
+                    self.flush_block();
+                }
+                self.line.clear();
+            }
+            Event::End(TagEnd::Item) => {
+                if !self.line.trim().is_empty() {
+                    self.flush_block();
+                }
+            }
+            Event::Start(Tag::CodeBlock(kind)) => {
+                self.code = true;
+                let lang = match kind {
+                    CodeBlockKind::Fenced(lang) => lang.to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                self.out.push_str(&format!("```{}\n", lang.trim()));
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                self.code = false;
+                if !self.out.ends_with('\n') {
+                    self.out.push('\n');
+                }
+                self.out.push_str("```\n\n");
+            }
+            Event::Start(Tag::Link { dest_url, .. }) => {
+                self.link = Some((dest_url.into_string(), String::new()));
+            }
+            Event::Start(Tag::Image { dest_url, .. }) => {
+                self.link = Some((dest_url.into_string(), String::new()));
+                self.image = true;
+            }
+            Event::End(TagEnd::Link | TagEnd::Image) => {
+                self.image = false;
+                if let Some((url, text)) = self.link.take() {
+                    self.links.push((url, text));
+                }
+            }
+            Event::Text(text) | Event::Code(text) if self.code => self.out.push_str(&text),
+            Event::Text(text) | Event::Code(text) => self.text(&text),
+            Event::SoftBreak => self.text(" "),
+            Event::HardBreak => {
+                let text = std::mem::take(&mut self.line);
+                self.push_line(&text);
+            }
+            Event::Rule => self.out.push_str("-----\n\n"),
+            Event::TaskListMarker(done) => self.text(if done { "[x] " } else { "[ ] " }),
+            _ => {}
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Some((_, link_text)) = &mut self.link {
+            link_text.push_str(text);
+        }
+        if !self.image {
+            self.line.push_str(text);
+        }
+    }
+
+    /// Write the current line as a paragraph (or list item), followed by its links; a block which
+    /// is only a link is written as just the link.
+    fn flush_block(&mut self) {
+        let text = std::mem::take(&mut self.line);
+        let text = text.trim();
+        let only_link = matches!(&self.links[..], [(_, link)] if link.trim() == text);
+        if !text.is_empty() && !only_link {
+            let line = match self.list_depth {
+                0 => text.to_owned(),
+                _ => format!("* {text}"),
+            };
+            self.push_line(&line);
+        }
+        self.flush_links();
+    }
+
+    fn push_line(&mut self, line: &str) {
+        for _ in 0..self.quote_depth {
+            self.out.push_str("> ");
+        }
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn flush_links(&mut self) {
+        for (url, text) in std::mem::take(&mut self.links) {
+            match text.trim() {
+                "" => self.out.push_str(&format!("=> {url}\n")),
+                text => self.out.push_str(&format!("=> {url} {text}\n")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn titles() {
+        let source = "+++\ntitle = \"Hello\"\n+++\n\nSome *emphasis* & `code`.\n";
+        assert_eq!(from_markdown(source), "# Hello\n\nSome emphasis & code.\n");
+        let source = "+++\ntitle = \"Hello\"\n+++\n\n# Goodbye\n\nText\n";
+        assert_eq!(from_markdown(source), "# Goodbye\n\nText\n");
+        assert_eq!(from_markdown("## Two\n#### Four\n"), "## Two\n\n### Four\n");
+    }
+
+    #[test]
+    fn links() {
+        let source = "See [the index](/) or [elsewhere](gemini://b.test/).\n\n\
+            [Home](/)\n\n![A cat](cat.png)\n\n<https://example.com/>\n";
+        assert_eq!(
+            from_markdown(source),
+            "See the index or elsewhere.\n=> / the index\n=> gemini://b.test/ elsewhere\n\n\
+            => / Home\n\n=> cat.png A cat\n\n=> https://example.com/ https://example.com/\n"
+        );
+        assert_eq!(
+            from_markdown("## [Heading](/h)\n"),
+            "## Heading\n=> /h Heading\n"
+        );
+    }
+
+    #[test]
+    fn blocks() {
+        let source = "- one\n- two\n  - three\n- [x] done\n\n\
+            > quoted\n> text\n\n\
+            ```rust\nfn main() {}\n```\n\n\
+            ---\n\nline  \nbreak\n";
+        assert_eq!(
+            from_markdown(source),
+            "* one\n* two\n* three\n* [x] done\n\n\
+            > quoted text\n\n\
+            ```rust\nfn main() {}\n```\n\n\
+            -----\n\nline\nbreak\n"
+        );
+    }
+}
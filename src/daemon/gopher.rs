@@ -0,0 +1,255 @@
+//! Serving a directory over [Gopher](https://www.rfc-editor.org/rfc/rfc1436); see
+//! [Gopher](crate::config::Gopher).
+
+use super::{handler::static_files, reload::Reloader};
+use crate::config::Config;
+use crossbeam::sync::ShardedLock;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// The longest selector (including any search), excluding its CRLF.
+const MAX_SELECTOR_SIZE: u64 = 1024;
+
+/// How long to wait for a client to send its selector.
+const SELECTOR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The file listing a directory's menu items.
+const GOPHERMAP: &str = "gophermap";
+
+pub async fn accept(reloader: Reloader, listener: TcpListener) -> Result<(), std::io::Error> {
+    tracing::debug!(?listener, "accepting gopher connections");
+    loop {
+        let (mut conn, peer) = match listener.accept().await {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to initialize stream");
+                continue;
+            }
+        };
+        let cfg = reloader.config().clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = serve(&cfg, &mut conn, peer).await {
+                tracing::debug!(error = ?e, %peer, "failed to serve gopher request");
+            }
+            let _ = conn.shutdown().await;
+        });
+    }
+}
+
+/// The host & port written in menu items which link to this server.
+#[derive(Debug)]
+struct Server {
+    host: String,
+    port: u16,
+}
+
+#[derive(Debug)]
+enum Response {
+    Menu(String),
+    File(tokio::fs::File),
+}
+
+async fn serve(
+    cfg: &ShardedLock<Config>,
+    conn: &mut TcpStream,
+    peer: SocketAddr,
+) -> std::io::Result<()> {
+    let mut line = Vec::new();
+    let mut reader = BufReader::new((&mut *conn).take(MAX_SELECTOR_SIZE + 2));
+    let read = reader.read_until(b'\n', &mut line);
+    match tokio::time::timeout(SELECTOR_TIMEOUT, read).await {
+        Err(_) => return Ok(()),
+        Ok(read) => read?,
+    };
+
+    let local = conn.local_addr()?;
+    let (root, server) = {
+        let cfg = cfg.read().unwrap();
+        let host = cfg
+            .gopher
+            .host
+            .clone()
+            .or(Some(cfg.server.domain.clone()).filter(|d| !d.is_empty()))
+            .unwrap_or_else(|| local.ip().to_string());
+        let port = cfg.gopher.port.unwrap_or(local.port());
+        (cfg.gopher.root.clone(), Server { host, port })
+    };
+    let res = match std::str::from_utf8(&line) {
+        // anything after a tab is a search, or a Gopher+ request, neither of which are supported
+        Ok(line) => {
+            let selector = line.trim_end_matches(['\r', '\n']);
+            let selector = selector.split('\t').next().unwrap_or_default();
+            tracing::debug!(selector, %peer, "received gopher request");
+            match root {
+                Some(root) => respond(&root, selector, &server).await,
+                None => Response::Menu(error("there's nothing here", &server)),
+            }
+        }
+        Err(_) => Response::Menu(error("selectors must be UTF-8", &server)),
+    };
+
+    match res {
+        Response::Menu(menu) => {
+            conn.write_all(menu.as_bytes()).await?;
+            conn.write_all(b".\r\n").await?;
+        }
+        Response::File(mut file) => {
+            tokio::io::copy(&mut file, conn).await?;
+        }
+    }
+    conn.flush().await
+}
+
+async fn respond(root: &Path, selector: &str, server: &Server) -> Response {
+    let path = match find(root, selector).await {
+        Ok(Some(path)) => path,
+        Ok(None) => return Response::Menu(error("not found", server)),
+        Err(e) => return failure(e, server),
+    };
+    match tokio::fs::metadata(&path).await {
+        Ok(meta) if meta.is_dir() => match menu(&path, selector, server).await {
+            Ok(menu) => Response::Menu(menu),
+            Err(e) => failure(e, server),
+        },
+        Ok(_) => match tokio::fs::File::open(&path).await {
+            Ok(file) => Response::File(file),
+            Err(e) => failure(e, server),
+        },
+        Err(e) => failure(e, server),
+    }
+}
+
+fn failure(e: std::io::Error, server: &Server) -> Response {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => Response::Menu(error("not found", server)),
+        _ => {
+            tracing::error!(error = ?e, "failed to serve gopher request");
+            Response::Menu(error("internal error", server))
+        }
+    }
+}
+
+async fn find(root: &Path, selector: &str) -> std::io::Result<Option<PathBuf>> {
+    // hidden files (ex. `.git`) aren't served
+    if selector.split('/').any(|s| s.starts_with('.')) {
+        return Ok(None);
+    }
+    let root = tokio::fs::canonicalize(root).await?;
+    // selectors aren't percent-encoded, unlike the paths `resolve` expects
+    static_files::resolve(&root, &selector.replace('%', "%25"))
+        .await
+        .map_err(std::io::Error::other)
+}
+
+/// The menu for `dir`, from its gophermap, or else listing its entries.
+async fn menu(dir: &Path, selector: &str, server: &Server) -> std::io::Result<String> {
+    let base = match selector.trim_end_matches('/') {
+        "" => "/".to_owned(),
+        selector if selector.starts_with('/') => format!("{selector}/"),
+        selector => format!("/{selector}/"),
+    };
+    let map = match tokio::fs::read_to_string(dir.join(GOPHERMAP)).await {
+        Ok(map) => map,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return listing(dir, &base, server).await
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut res = String::new();
+    for line in map.lines() {
+        let Some((first, rest)) = line.split_once('\t') else {
+            match line {
+                "." => break,
+                "*" => res.push_str(&listing(dir, &base, server).await?),
+                text => res.push_str(&info(text, server)),
+            }
+            continue;
+        };
+        let mut chars = first.chars();
+        let Some(ty) = chars.next() else {
+            res.push_str(&info("", server));
+            continue;
+        };
+        let display = chars.as_str();
+        let mut fields = rest.split('\t');
+        let selector = fields.next().filter(|s| !s.is_empty()).unwrap_or(display);
+        let host = fields.next().filter(|h| !h.is_empty());
+        let port = fields.next().and_then(|p| p.parse().ok());
+        // selectors for this server may be relative to the directory
+        let selector = match host {
+            None if !selector.starts_with('/') && !selector.starts_with("URL:") => {
+                format!("{base}{selector}")
+            }
+            _ => selector.to_owned(),
+        };
+        res.push_str(&item(
+            ty,
+            display,
+            &selector,
+            host.unwrap_or(&server.host),
+            port.unwrap_or(server.port),
+        ));
+    }
+    Ok(res)
+}
+
+/// Menu items for `dir`'s (non-hidden) entries.
+async fn listing(dir: &Path, base: &str, server: &Server) -> std::io::Result<String> {
+    let mut entries = Vec::new();
+    let mut read = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') || name == GOPHERMAP {
+            continue;
+        }
+        // follow symlinks, so that linked directories are listed as directories
+        let is_dir = tokio::fs::metadata(entry.path())
+            .await
+            .is_ok_and(|m| m.is_dir());
+        entries.push((name, is_dir));
+    }
+    entries.sort();
+
+    let mut res = String::new();
+    for (name, is_dir) in entries {
+        let (ty, selector) = match is_dir {
+            true => ('1', format!("{base}{name}/")),
+            false => (item_type(Path::new(&name)), format!("{base}{name}")),
+        };
+        res.push_str(&item(ty, &name, &selector, &server.host, server.port));
+    }
+    Ok(res)
+}
+
+/// The item type for a file, guessed from its extension.
+fn item_type(path: &Path) -> char {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    match (mime.type_().as_str(), mime.subtype().as_str()) {
+        ("text", "html") => 'h',
+        ("text", _) => '0',
+        ("image", "gif") => 'g',
+        ("image", _) => 'I',
+        ("audio", _) => 's',
+        _ => '9',
+    }
+}
+
+fn item(ty: char, display: &str, selector: &str, host: &str, port: u16) -> String {
+    format!("{ty}{display}\t{selector}\t{host}\t{port}\r\n")
+}
+
+fn info(text: &str, server: &Server) -> String {
+    item('i', text, "", &server.host, server.port)
+}
+
+fn error(message: &str, server: &Server) -> String {
+    item('3', message, "", &server.host, server.port)
+}
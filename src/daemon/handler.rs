@@ -0,0 +1,249 @@
+use super::channels::Channels;
+use super::compression::NoCompression;
+use super::reload::Reloader;
+use super::service::{full, Result, ServiceError, SvcResponse};
+use super::templates::Templates;
+use super::upstreams::Upstreams;
+use crate::config::{
+    self,
+    hosts::VirtualHost,
+    routes::{Builtin, Captures, Handler, Route},
+    Config,
+};
+use bytes::Bytes;
+use crossbeam::sync::ShardedLock;
+use gateway::Protocol;
+use http_body_util::BodyExt;
+use hyper::{body, header, Request, Response};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub mod autoindex;
+pub mod cgi;
+pub mod events;
+pub mod fastcgi;
+pub mod feed;
+pub mod gateway;
+pub mod indieauth;
+pub mod markdown;
+pub mod media;
+pub mod micropub;
+pub mod proxy;
+pub mod static_files;
+pub mod templates;
+pub mod webmention;
+pub mod websocket;
+
+/// Settings handlers need from outside their own configuration, copied out so that the
+/// configuration lock isn't held while responding.
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub document_root: Option<PathBuf>,
+    /// The default host's name, for requests which don't name one.
+    pub server_name: String,
+    /// The matched host's canonical name, or else the default host's, if either is set.
+    pub host_name: Option<String>,
+    /// Whether there are any HTTPS listeners.
+    pub https: bool,
+    pub cache_dir: PathBuf,
+    pub state_dir: PathBuf,
+    pub compression: config::Compression,
+    /// Whether the matched route allows its responses to be compressed.
+    pub compress: bool,
+    pub upstreams: Arc<Upstreams>,
+    pub channels: Arc<Channels>,
+    pub templates: Arc<Templates>,
+    /// The whole configuration, for handlers which need to know about other hosts & routes; its
+    /// lock mustn't be held across awaits.
+    pub config: Arc<ShardedLock<Config>>,
+}
+
+impl Context {
+    pub fn new(cfg: &Config, host: &VirtualHost, route: &Route, reloader: &Reloader) -> Self {
+        Self {
+            document_root: host.root.map(Path::to_owned),
+            server_name: cfg.server.domain.clone(),
+            host_name: host
+                .index
+                .and_then(|i| cfg.hosts[i].canonical_name())
+                .or(Some(cfg.server.domain.as_str()).filter(|d| !d.is_empty()))
+                .map(str::to_owned),
+            https: !cfg.listen.https.is_empty(),
+            cache_dir: cfg.directories.cache.clone(),
+            state_dir: cfg.directories.state.clone(),
+            compression: cfg.compression.clone(),
+            compress: route.compress,
+            upstreams: reloader.upstreams().clone(),
+            channels: reloader.channels().clone(),
+            templates: reloader.templates().clone(),
+            config: reloader.config().clone(),
+        }
+    }
+
+    /// A context for `host_name`, with nothing else configured, for testing handlers.
+    #[cfg(test)]
+    pub fn for_host(host_name: &str, state_dir: &Path) -> Self {
+        let cfg = Config::default();
+        Self {
+            document_root: None,
+            server_name: host_name.to_owned(),
+            host_name: Some(host_name.to_owned()),
+            https: true,
+            cache_dir: state_dir.join("cache"),
+            state_dir: state_dir.to_owned(),
+            compression: cfg.compression.clone(),
+            compress: false,
+            upstreams: Default::default(),
+            channels: Default::default(),
+            templates: Default::default(),
+            config: Arc::new(ShardedLock::new(cfg)),
+        }
+    }
+
+    /// The scheme & host at which the site is reached, ex. `https://example.com`, for making
+    /// absolute URLs; the host is [Self::host_name], or else the requested host (and port).
+    pub fn origin<B>(&self, req: &Request<B>) -> String {
+        let requested = req.uri().authority().map(|a| a.as_str()).or_else(|| {
+            req.headers()
+                .get(header::HOST)
+                .and_then(|h| h.to_str().ok())
+        });
+        let host = self
+            .host_name
+            .as_deref()
+            .or(requested)
+            .unwrap_or(&self.server_name);
+        match self.https {
+            true => format!("https://{host}"),
+            false => format!("http://{host}"),
+        }
+    }
+}
+
+/// Respond to `req` using `handler`.
+pub async fn handle(
+    handler: &Handler,
+    ctx: &Context,
+    captures: Captures,
+    req: Request<body::Incoming>,
+) -> Result<SvcResponse> {
+    let mut res = respond(handler, ctx, captures, req).await?;
+    if !ctx.compress {
+        res.extensions_mut().insert(NoCompression);
+    }
+    Ok(res)
+}
+
+async fn respond(
+    handler: &Handler,
+    ctx: &Context,
+    captures: Captures,
+    req: Request<body::Incoming>,
+) -> Result<SvcResponse> {
+    match handler {
+        Handler::Static(cfg) => return static_files::serve(cfg, ctx, &captures, &req).await,
+        Handler::Markdown(cfg) => return markdown::serve(cfg, ctx, &captures, &req).await,
+        Handler::Templates(cfg) => return templates::serve(cfg, ctx, &captures, &req).await,
+        Handler::Feed(cfg) => return feed::serve(cfg, ctx, &req).await,
+        Handler::Webmention(cfg) => return webmention::serve(cfg, ctx, req).await,
+        Handler::IndieAuth(cfg) => return indieauth::serve(cfg, ctx, &captures, req).await,
+        Handler::Micropub(cfg) => return micropub::serve(cfg, ctx, req).await,
+        Handler::Media(cfg) => return media::serve(cfg, ctx, req).await,
+        Handler::Redirect { location, status } => Response::builder()
+            .status(status.0)
+            .header(header::LOCATION, captures.expand(location))
+            .body(full(Bytes::new())),
+        Handler::Respond {
+            status,
+            headers,
+            body,
+        } => {
+            let mut res = Response::builder().status(status.0);
+            for (name, value) in headers {
+                res = res.header(name, value);
+            }
+            res.body(full(body.clone()))
+        }
+        Handler::Proxy(cfg) => return proxy::serve(cfg, &ctx.upstreams, &captures, req).await,
+        Handler::Cgi(cfg) => return cgi::serve(cfg, ctx, &captures, req).await,
+        Handler::FastCgi(cfg) => return fastcgi::serve(cfg, ctx, &captures, req).await,
+        Handler::Scgi(cfg) => {
+            return gateway::serve(cfg, Protocol::Scgi, ctx, &captures, req).await
+        }
+        Handler::Uwsgi(cfg) => {
+            return gateway::serve(cfg, Protocol::Uwsgi, ctx, &captures, req).await
+        }
+        Handler::WebSocket(cfg) => return websocket::serve(cfg, ctx, req).await,
+        Handler::Events(cfg) => return events::serve(cfg, ctx, &captures, req).await,
+        Handler::Builtin { name } => return builtin(*name, req),
+    }
+    .map_err(ServiceError::from)
+}
+
+fn builtin(name: Builtin, req: Request<body::Incoming>) -> Result<SvcResponse> {
+    match name {
+        Builtin::Hello => Ok(Response::new(full(
+            "Try POSTing data to /echo (ex. `curl localhost:8080/echo -XPOST -d \"Hello, World\"`)",
+        ))),
+        Builtin::Echo => Ok(Response::new(
+            req.into_body().map_err(ServiceError::from).boxed(),
+        )),
+    }
+}
+
+/// Read a request body of at most `limit` bytes, or `None` if it's larger.
+pub async fn collect_body(body: body::Incoming, limit: usize) -> Result<Option<Bytes>> {
+    match http_body_util::Limited::new(body, limit).collect().await {
+        Ok(body) => Ok(Some(body.to_bytes())),
+        Err(e) if e.is::<http_body_util::LengthLimitError>() => Ok(None),
+        Err(e) => match e.downcast::<hyper::Error>() {
+            Ok(e) => Err((*e).into()),
+            Err(e) => Err(std::io::Error::other(e).into()),
+        },
+    }
+}
+
+/// Whether the client accepts any of `types` (ex. `application/json`); wildcards aren't
+/// considered, so that this only holds when a client has specifically asked for one of them.
+pub fn accepts<B>(req: &Request<B>, types: &[&str]) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|a| a.to_str().ok())
+        .flat_map(|a| a.split(','))
+        .any(|ty| {
+            let mut params = ty.split(';').map(str::trim);
+            params
+                .next()
+                .is_some_and(|ty| types.iter().any(|t| ty.eq_ignore_ascii_case(t)))
+                && params.all(|p| p.strip_prefix("q=").is_none_or(|q| q.parse() != Ok(0.0)))
+        })
+}
+
+/// Escape text for use in HTML content or a quoted attribute.
+pub fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+/// Decode the character references [escape] produces, along with `&#x27;`.
+pub fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
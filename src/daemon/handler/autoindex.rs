@@ -0,0 +1,276 @@
+//! Directory listings.
+
+use super::{accepts, escape};
+use crate::config::routes::Autoindex;
+use crate::daemon::service::{full, Result, SvcResponse};
+use hyper::{header, Request, Response};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Serialize;
+use std::{cmp::Ordering, fmt::Write, path::Path, time::SystemTime};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Characters escaped in a single path segment of a link.
+pub const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+const STYLE: &str = "\
+:root { color-scheme: light dark; font-family: sans-serif; }
+body { max-width: 60rem; margin: 2rem auto; padding: 0 1rem; }
+table { width: 100%; border-collapse: collapse; }
+th, td { padding: 0.25rem 0.5rem; text-align: left; }
+th a { color: inherit; }
+tbody tr:nth-child(odd) { background: color-mix(in srgb, currentColor 6%, transparent); }
+td.size, th.size { text-align: right; font-variant-numeric: tabular-nums; }
+a { text-decoration: none; }
+a:hover { text-decoration: underline; }
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Kind {
+    Directory,
+    File,
+    Other,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: Kind,
+    size: u64,
+    #[serde(serialize_with = "serialize_time")]
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Serialize)]
+struct Listing<'path> {
+    path: &'path str,
+    entries: Vec<Entry>,
+}
+
+fn serialize_time<S: serde::Serializer>(
+    time: &Option<SystemTime>,
+    ser: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match time.and_then(|t| format_time(t).ok()) {
+        Some(time) => ser.serialize_some(&time),
+        None => ser.serialize_none(),
+    }
+}
+
+fn format_time(time: SystemTime) -> std::result::Result<String, time::error::Format> {
+    OffsetDateTime::from(time).format(&Rfc3339)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::Size => "size",
+            Self::Modified => "modified",
+        }
+    }
+}
+
+/// How to sort a listing, per the `sort` & `order` query parameters.
+#[derive(Debug, Clone, Copy)]
+struct Sort {
+    key: SortKey,
+    descending: bool,
+}
+
+impl Sort {
+    fn from_query(query: Option<&str>) -> Self {
+        let mut res = Self {
+            key: SortKey::Name,
+            descending: false,
+        };
+        for (key, value) in query
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+        {
+            match (key, value) {
+                ("sort", "name") => res.key = SortKey::Name,
+                ("sort", "size") => res.key = SortKey::Size,
+                ("sort", "modified") => res.key = SortKey::Modified,
+                ("order", "asc") => res.descending = false,
+                ("order", "desc") => res.descending = true,
+                _ => {}
+            }
+        }
+        res
+    }
+
+    /// Directories always come first, regardless of order.
+    fn compare(self, a: &Entry, b: &Entry) -> Ordering {
+        let dirs_first = (b.kind == Kind::Directory).cmp(&(a.kind == Kind::Directory));
+        let ord = match self.key {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        dirs_first.then(if self.descending { ord.reverse() } else { ord })
+    }
+}
+
+/// Respond with a listing of `dir`, which is within `root`.
+pub async fn serve<B>(
+    cfg: &Autoindex,
+    root: &Path,
+    dir: &Path,
+    req: &Request<B>,
+) -> Result<SvcResponse> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !cfg.show_hidden {
+            continue;
+        }
+        // follows symlinks; broken ones aren't listed
+        let Ok(meta) = tokio::fs::metadata(entry.path()).await else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            kind: if meta.is_dir() {
+                Kind::Directory
+            } else if meta.is_file() {
+                Kind::File
+            } else {
+                Kind::Other
+            },
+            size: if meta.is_file() { meta.len() } else { 0 },
+            modified: meta.modified().ok(),
+        });
+    }
+    let sort = Sort::from_query(req.uri().query());
+    entries.sort_by(|a, b| sort.compare(a, b));
+
+    let listing = Listing {
+        path: req.uri().path(),
+        entries,
+    };
+    let res = Response::builder().header(header::VARY, "accept");
+    if accepts(req, &["application/json"]) {
+        return Ok(res
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(full(serde_json::to_vec(&listing).unwrap()))?);
+    }
+    Ok(res
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(full(render_html(cfg, &listing, sort, dir != root)))?)
+}
+
+fn render_html(cfg: &Autoindex, listing: &Listing, sort: Sort, parent: bool) -> String {
+    let title = escape(listing.path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+        <title>Index of {title}</title>\n"
+    );
+    match &cfg.stylesheet {
+        Some(href) => {
+            let _ = writeln!(html, "<link rel=\"stylesheet\" href=\"{}\">", escape(href));
+        }
+        None => {
+            let _ = writeln!(html, "<style>\n{STYLE}</style>");
+        }
+    }
+    let _ = write!(
+        html,
+        "</head>\n<body>\n<h1>Index of {title}</h1>\n<table>\n<thead>\n<tr>"
+    );
+    for key in [SortKey::Name, SortKey::Size, SortKey::Modified] {
+        // clicking the current sort column reverses it
+        let order = if sort.key == key && !sort.descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        let arrow = match (sort.key == key, sort.descending) {
+            (true, false) => " ↑",
+            (true, true) => " ↓",
+            (false, _) => "",
+        };
+        let _ = write!(
+            html,
+            "<th class=\"{name}\"><a href=\"?sort={name}&amp;order={order}\">{}{arrow}</a></th>",
+            capitalize(key.name()),
+            name = key.name(),
+        );
+    }
+    html.push_str("</tr>\n</thead>\n<tbody>\n");
+    if parent {
+        html.push_str(
+            "<tr><td class=\"name\"><a href=\"../\">../</a></td><td class=\"size\"></td><td class=\"modified\"></td></tr>\n",
+        );
+    }
+    for entry in &listing.entries {
+        let slash = if entry.kind == Kind::Directory {
+            "/"
+        } else {
+            ""
+        };
+        let size = match entry.kind {
+            Kind::File => human_size(entry.size),
+            _ => String::new(),
+        };
+        let modified = entry
+            .modified
+            .map(httpdate::fmt_http_date)
+            .unwrap_or_default();
+        let _ = writeln!(
+            html,
+            "<tr><td class=\"name\"><a href=\"./{}{slash}\">{}{slash}</a></td>\
+            <td class=\"size\">{size}</td><td class=\"modified\">{modified}</td></tr>",
+            utf8_percent_encode(&entry.name, SEGMENT),
+            escape(&entry.name),
+        );
+    }
+    html.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+    html
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .map(|c| c.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} {}", UNITS[0])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
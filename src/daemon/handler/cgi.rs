@@ -0,0 +1,438 @@
+//! Runs CGI scripts (RFC 3875); also the request meta-variables and response parsing shared by the
+//! other gateway protocols.
+
+use super::{collect_body, Context};
+use crate::config::routes::{request_host, Captures, Cgi};
+use crate::config::upstreams::{ProxyTarget, Upstream};
+use crate::daemon::{
+    service::{empty, full, Connection, Result, ServiceError, SvcResponse},
+    upstreams::{self, Lease, Socket, Upstreams},
+};
+use bytes::Bytes;
+use futures::TryStreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use hyper::{
+    body::{Frame, Incoming},
+    header, HeaderMap, Request, Response, StatusCode,
+};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, DuplexStream},
+    time::Instant,
+};
+use tokio_util::io::ReaderStream;
+
+/// The longest response header section accepted from a gateway.
+const MAX_HEADER_LEN: usize = 64 * 1024;
+/// How much of a gateway's response may be buffered while the client catches up.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// The script serving a request, and how the request path maps onto it.
+#[derive(Debug, Clone)]
+pub struct Script {
+    /// `SCRIPT_FILENAME`
+    pub filename: Option<PathBuf>,
+    /// `SCRIPT_NAME`: the part of the request path which identifies the script.
+    pub name: String,
+    /// `PATH_INFO`: the rest of the request path, decoded.
+    pub path_info: String,
+}
+
+impl Script {
+    /// A script named by a route, to which the part of `path` captured by the route's pattern (if
+    /// any) is passed as `PATH_INFO`.
+    pub fn fixed(filename: Option<&Path>, path: &str, captures: &Captures) -> Self {
+        let (name, path_info) = match captures.subpath.as_deref() {
+            Some(subpath) if !subpath.is_empty() => (
+                path.strip_suffix(subpath).unwrap_or(path),
+                format!("/{}", subpath.trim_start_matches('/')),
+            ),
+            _ => (path, String::new()),
+        };
+        Self {
+            filename: filename.map(Path::to_owned),
+            name: name.trim_end_matches('/').to_owned(),
+            path_info: percent_encoding::percent_decode_str(&path_info)
+                .decode_utf8_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+/// The CGI meta-variables describing `req`, including its headers as `HTTP_*`, plus some which
+/// are conventional though not standard (ex. `REQUEST_URI`, `SCRIPT_FILENAME`, `HTTPS`).
+pub fn environment<B>(req: &Request<B>, ctx: &Context, script: &Script) -> Vec<(String, String)> {
+    let conn = req
+        .extensions()
+        .get::<Connection>()
+        .cloned()
+        .unwrap_or_default();
+    let authority = req
+        .uri()
+        .authority()
+        .map(|a| a.as_str())
+        .or_else(|| req.headers().get(header::HOST)?.to_str().ok())
+        .unwrap_or_default();
+    let port = match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => authority[i + 1..].to_owned(),
+        _ if conn.tls => "443".to_owned(),
+        _ => "80".to_owned(),
+    };
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_owned()),
+        (
+            "SERVER_SOFTWARE",
+            concat!("melia/", env!("CARGO_PKG_VERSION")).to_owned(),
+        ),
+        ("SERVER_PROTOCOL", format!("{:?}", req.version())),
+        (
+            "SERVER_NAME",
+            request_host(req).unwrap_or(&ctx.server_name).to_owned(),
+        ),
+        ("SERVER_PORT", port),
+        ("REQUEST_METHOD", req.method().to_string()),
+        (
+            "REQUEST_URI",
+            req.uri()
+                .path_and_query()
+                .map_or("/", |p| p.as_str())
+                .to_owned(),
+        ),
+        (
+            "QUERY_STRING",
+            req.uri().query().unwrap_or_default().to_owned(),
+        ),
+        ("SCRIPT_NAME", script.name.clone()),
+        ("PATH_INFO", script.path_info.clone()),
+        // for php-cgi, which refuses to run without it
+        ("REDIRECT_STATUS", "200".to_owned()),
+    ];
+    if let Some(filename) = &script.filename {
+        env.push(("SCRIPT_FILENAME", filename.to_string_lossy().into_owned()));
+    }
+    if let Some(root) = &ctx.document_root {
+        env.push(("DOCUMENT_ROOT", root.to_string_lossy().into_owned()));
+        if !script.path_info.is_empty() {
+            let translated = root.join(script.path_info.trim_start_matches('/'));
+            env.push(("PATH_TRANSLATED", translated.to_string_lossy().into_owned()));
+        }
+    }
+    if let Some(peer) = conn.peer {
+        env.push(("REMOTE_ADDR", peer.ip().to_string()));
+        env.push(("REMOTE_PORT", peer.port().to_string()));
+    }
+    if conn.tls {
+        env.push(("HTTPS", "on".to_owned()));
+    }
+    for (name, var) in [
+        (header::CONTENT_LENGTH, "CONTENT_LENGTH"),
+        (header::CONTENT_TYPE, "CONTENT_TYPE"),
+    ] {
+        if let Some(value) = req.headers().get(name).and_then(|v| v.to_str().ok()) {
+            env.push((var, value.to_owned()));
+        }
+    }
+
+    let mut env = env
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value))
+        .collect::<Vec<_>>();
+    for name in req.headers().keys() {
+        // `Proxy` would become `HTTP_PROXY`, which many clients take as their proxy ("httpoxy")
+        if name == header::CONTENT_LENGTH || name == header::CONTENT_TYPE || name == "proxy" {
+            continue;
+        }
+        let value = req
+            .headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let name = format!(
+            "HTTP_{}",
+            name.as_str().to_ascii_uppercase().replace('-', "_")
+        );
+        env.push((name, value));
+    }
+    env
+}
+
+/// `req`, with a `Content-Length` if it has a body. Gateway protocols need the length up front,
+/// so bodies without one are read into memory, up to `limit` bytes; `None` if the body is longer.
+pub async fn sized(
+    req: Request<Incoming>,
+    limit: usize,
+) -> Result<Option<Request<BoxBody<Bytes, ServiceError>>>> {
+    let (mut parts, body) = req.into_parts();
+    if parts.headers.contains_key(header::CONTENT_LENGTH) {
+        return Ok(Some(Request::from_parts(
+            parts,
+            body.map_err(ServiceError::from).boxed(),
+        )));
+    }
+    let Some(body) = collect_body(body, limit).await? else {
+        return Ok(None);
+    };
+    if !body.is_empty() {
+        parts
+            .headers
+            .insert(header::CONTENT_LENGTH, body.len().into());
+    }
+    Ok(Some(Request::from_parts(parts, full(body))))
+}
+
+/// Read a CGI response from `stdout`: headers, optionally including `Status`, then the body, which
+/// is streamed. An HTTP status line in place of `Status` is also accepted.
+pub async fn read_response<R: AsyncRead + Send + Sync + Unpin + 'static>(
+    stdout: R,
+) -> std::io::Result<SvcResponse> {
+    fn invalid(msg: &str) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_owned())
+    }
+    let mut stdout = BufReader::new(stdout);
+    let mut res = Response::builder();
+    let mut status = None;
+    let mut header_len = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if stdout.read_until(b'\n', &mut line).await? == 0 {
+            return Err(invalid("response ended before its headers"));
+        }
+        header_len += line.len();
+        if header_len > MAX_HEADER_LEN {
+            return Err(invalid("response headers are too long"));
+        }
+        let line = std::str::from_utf8(&line)
+            .map_err(|_| invalid("response headers aren't UTF-8"))?
+            .trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if let Some(status_line) = line.strip_prefix("HTTP/") {
+            status = status_line.split_whitespace().nth(1).map(str::to_owned);
+            continue;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed response header"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("status") {
+            status = value.split_whitespace().next().map(str::to_owned);
+        } else {
+            // a local redirect, with no status, implies 302
+            if name.eq_ignore_ascii_case("location") && status.is_none() {
+                status = Some("302".to_owned());
+            }
+            res = res.header(name, value);
+        }
+    }
+    let status = match status {
+        Some(status) => StatusCode::from_bytes(status.as_bytes())
+            .map_err(|_| invalid("invalid response status"))?,
+        None => StatusCode::OK,
+    };
+    let body = ReaderStream::new(stdout)
+        .map_ok(Frame::data)
+        .map_err(ServiceError::from);
+    res.status(status)
+        .body(BodyExt::boxed(StreamBody::new(body)))
+        .map_err(|e| invalid(&e.to_string()))
+}
+
+/// Connect to the server `target` names for a request with `headers`, returning the socket, the
+/// server, and its lease if it was chosen from a group.
+pub async fn connect(
+    upstreams: &Upstreams,
+    target: &ProxyTarget,
+    headers: &HeaderMap,
+    deadline: Instant,
+) -> Option<(Socket, Upstream, Option<Lease>)> {
+    let (upstream, lease) = upstreams.resolve(target, headers)?;
+    let socket = tokio::time::timeout_at(deadline, upstreams::connect(&upstream))
+        .await
+        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+    match socket {
+        Ok(socket) => {
+            if let Some(lease) = &lease {
+                lease.succeeded();
+            }
+            Some((socket, upstream, lease))
+        }
+        Err(e) => {
+            tracing::warn!(%upstream, error = ?e, "failed to reach upstream");
+            if let Some(lease) = lease {
+                lease.failed();
+            }
+            None
+        }
+    }
+}
+
+/// Run `exchange` in the background until `deadline`, and respond with the CGI response it writes
+/// to the pipe it's given.
+pub async fn respond<F>(
+    source: String,
+    lease: Option<Lease>,
+    deadline: Instant,
+    exchange: impl FnOnce(DuplexStream) -> F,
+) -> Result<SvcResponse>
+where
+    F: Future<Output = std::io::Result<()>> + Send + 'static,
+{
+    let (stdout, writer) = tokio::io::duplex(PIPE_CAPACITY);
+    let exchange = exchange(writer);
+    tokio::task::spawn({
+        let source = source.clone();
+        async move {
+            // the request counts as in flight until the exchange ends
+            let _lease = lease;
+            match tokio::time::timeout_at(deadline, exchange).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    tracing::warn!(upstream = source, error = ?e, "gateway request failed")
+                }
+                Err(_) => tracing::warn!(upstream = source, "gateway request timed out"),
+            }
+        }
+    });
+
+    // the exchange ends at the deadline, which ends the response body
+    match tokio::time::timeout_at(deadline, read_response(stdout)).await {
+        Ok(Ok(res)) => Ok(res),
+        Ok(Err(_)) if Instant::now() >= deadline => status(StatusCode::GATEWAY_TIMEOUT),
+        Ok(Err(e)) => {
+            tracing::error!(upstream = source, error = ?e, "invalid gateway response");
+            status(StatusCode::BAD_GATEWAY)
+        }
+        Err(_) => status(StatusCode::GATEWAY_TIMEOUT),
+    }
+}
+
+/// Run `send` and `receive` concurrently until `receive` finishes, since servers may respond
+/// before they've read the whole request.
+pub async fn send_and_receive(
+    send: impl Future<Output = std::io::Result<()>>,
+    receive: impl Future<Output = std::io::Result<()>>,
+) -> std::io::Result<()> {
+    tokio::pin!(send, receive);
+    let mut sent = false;
+    loop {
+        tokio::select! {
+            res = &mut send, if !sent => {
+                res?;
+                sent = true;
+            }
+            res = &mut receive => return res,
+        }
+    }
+}
+
+/// Log each line a gateway wrote to stderr.
+pub fn log_stderr(source: &str, text: &[u8]) {
+    for line in String::from_utf8_lossy(text).lines() {
+        tracing::warn!(source, "{line}");
+    }
+}
+
+pub async fn serve(
+    cfg: &Cgi,
+    ctx: &Context,
+    captures: &Captures,
+    req: Request<Incoming>,
+) -> Result<SvcResponse> {
+    let script = Script::fixed(Some(&cfg.script), req.uri().path(), captures);
+    let Some(req) = sized(req, cfg.max_buffered_body).await? else {
+        return status(StatusCode::PAYLOAD_TOO_LARGE);
+    };
+    let source = cfg.script.to_string_lossy().into_owned();
+
+    let mut cmd = tokio::process::Command::new(&cfg.script);
+    cmd.env_clear()
+        .envs(environment(&req, ctx, &script))
+        .envs(&cfg.env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // so that scripts can find their interpreters
+    if let Some(path) = std::env::var_os("PATH") {
+        cmd.env("PATH", path);
+    }
+    if let Some(dir) = cfg.script.parent().filter(|d| !d.as_os_str().is_empty()) {
+        cmd.current_dir(dir);
+    }
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            tracing::error!(script = source, error = ?e, "failed to run CGI script");
+            return status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let (Some(mut stdin), Some(stdout), Some(stderr)) =
+        (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        unreachable!("CGI script's stdio is piped");
+    };
+    let mut body = req.into_body();
+    tokio::task::spawn(async move {
+        // the script needn't read its input, so failing to write it isn't an error
+        while let Some(Ok(frame)) = body.frame().await {
+            if let Ok(data) = frame.into_data() {
+                if stdin.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    tokio::task::spawn({
+        let source = source.clone();
+        async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                tracing::warn!(source, "{line}");
+            }
+        }
+    });
+    let deadline = Instant::now() + Duration::from_millis(cfg.timeout_ms);
+    tokio::task::spawn({
+        let source = source.clone();
+        async move {
+            match tokio::time::timeout_at(deadline, child.wait()).await {
+                Ok(Ok(status)) if !status.success() => {
+                    tracing::warn!(script = source, %status, "CGI script failed");
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    tracing::error!(script = source, error = ?e, "failed to wait for CGI script")
+                }
+                Err(_) => {
+                    tracing::warn!(script = source, "CGI script timed out; killing it");
+                    let _ = child.kill().await;
+                }
+            }
+        }
+    });
+
+    // the script is killed at the deadline, which ends the response body
+    match tokio::time::timeout_at(deadline, read_response(stdout)).await {
+        Ok(Ok(res)) => Ok(res),
+        Ok(Err(_)) if Instant::now() >= deadline => status(StatusCode::GATEWAY_TIMEOUT),
+        Ok(Err(e)) => {
+            tracing::error!(script = source, error = ?e, "invalid CGI response");
+            status(StatusCode::BAD_GATEWAY)
+        }
+        Err(_) => status(StatusCode::GATEWAY_TIMEOUT),
+    }
+}
+
+pub fn status(status: StatusCode) -> Result<SvcResponse> {
+    Ok(Response::builder().status(status).body(empty())?)
+}
@@ -0,0 +1,126 @@
+//! Server-sent events: subscribing to channels, and publishing to them.
+
+use super::Context;
+use crate::config::routes::{Captures, Events};
+use crate::daemon::{
+    channels::{self, Channel},
+    compression::NoCompression,
+    service::{empty, full, Result, ServiceError, SvcResponse},
+};
+use futures::StreamExt;
+use http_body_util::{BodyExt, Limited, StreamBody};
+use hyper::{
+    body::{Frame, Incoming},
+    header, Method, Request, Response, StatusCode,
+};
+
+/// The largest event which may be published.
+const MAX_EVENT_SIZE: usize = 64 * 1024;
+
+pub async fn serve(
+    cfg: &Events,
+    ctx: &Context,
+    captures: &Captures,
+    req: Request<Incoming>,
+) -> Result<SvcResponse> {
+    let name = match (&cfg.channel, &captures.subpath) {
+        (Some(name), _) => name.as_str(),
+        (None, Some(subpath)) => subpath.trim_matches('/'),
+        (None, None) => {
+            tracing::error!("events route has no channel, and its path pattern captures none");
+            return status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let Some(channel) = ctx.channels.get(name) else {
+        return status(StatusCode::NOT_FOUND);
+    };
+    match *req.method() {
+        Method::GET | Method::HEAD => subscribe(&channel, &req),
+        Method::POST => {
+            let token = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "));
+            if !token.is_some_and(|token| channel.authorizes(token.trim())) {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(header::WWW_AUTHENTICATE, "Bearer")
+                    .body(empty())?);
+            }
+            let event =
+                url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                    .find_map(|(name, value)| (name == "event").then(|| value.into_owned()));
+            publish(&channel, event, req).await
+        }
+        _ => Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD, POST")
+            .body(empty())?),
+    }
+}
+
+fn subscribe(channel: &Channel, req: &Request<Incoming>) -> Result<SvcResponse> {
+    let last_id = req
+        .headers()
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    let events = channel
+        .subscribe(last_id)
+        .map(|bytes| Ok::<_, ServiceError>(Frame::data(bytes)));
+    let mut res = Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        // ask reverse proxies (ex. nginx) not to buffer events
+        .header("x-accel-buffering", "no")
+        .body(BodyExt::boxed(StreamBody::new(events)))?;
+    // compression would hold events back until enough of them arrive
+    res.extensions_mut().insert(NoCompression);
+    Ok(res)
+}
+
+/// Publish the body of `req` as an event of type `event` (or `message`, if `None`), responding
+/// with its ID.
+pub async fn publish(
+    channel: &Channel,
+    event: Option<String>,
+    req: Request<Incoming>,
+) -> Result<SvcResponse> {
+    if event
+        .as_deref()
+        .is_some_and(|e| !channels::is_event_type(e))
+    {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full(
+                "event types must be non-empty, and may not contain line breaks",
+            ))?);
+    }
+    let body = match Limited::new(req.into_body(), MAX_EVENT_SIZE)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(e) if e.is::<http_body_util::LengthLimitError>() => {
+            return status(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        Err(e) => match e.downcast::<hyper::Error>() {
+            Ok(e) => return Err((*e).into()),
+            Err(e) => return Err(std::io::Error::other(e).into()),
+        },
+    };
+    let Ok(data) = String::from_utf8(body.into()) else {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full("event data must be UTF-8"))?);
+    };
+    let id = channel.publish(event, data);
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(full(id.to_string()))?)
+}
+
+fn status(status: StatusCode) -> Result<SvcResponse> {
+    Ok(Response::builder().status(status).body(empty())?)
+}
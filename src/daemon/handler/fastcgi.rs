@@ -0,0 +1,166 @@
+//! A FastCGI client, for responders like php-fpm.
+
+use super::{
+    cgi::{self, Script},
+    static_files, Context,
+};
+use crate::config::routes::{Captures, Gateway};
+use crate::daemon::{
+    service::{Result, ServiceError, SvcResponse},
+    upstreams::Socket,
+};
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{body::Incoming, Request, StatusCode};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    time::Instant,
+};
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+/// Each connection carries a single request.
+const REQUEST_ID: u16 = 1;
+const MAX_CONTENT_LEN: usize = u16::MAX as usize;
+
+/// A record with `content`, which must be no longer than [MAX_CONTENT_LEN].
+fn record(ty: u8, content: &[u8]) -> Vec<u8> {
+    let padding = (8 - content.len() % 8) % 8;
+    let mut res = Vec::with_capacity(8 + content.len() + padding);
+    res.extend_from_slice(&[VERSION, ty]);
+    res.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    res.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    res.extend_from_slice(&[padding as u8, 0]);
+    res.extend_from_slice(content);
+    res.resize(res.len() + padding, 0);
+    res
+}
+
+/// Records carrying `data` as a stream of type `ty`, including the empty record which ends it.
+fn stream(ty: u8, data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::new();
+    for chunk in data.chunks(MAX_CONTENT_LEN) {
+        res.extend(record(ty, chunk));
+    }
+    res.extend(record(ty, &[]));
+    res
+}
+
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    fn len(res: &mut Vec<u8>, len: usize) {
+        match len {
+            0..=127 => res.push(len as u8),
+            _ => res.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes()),
+        }
+    }
+    let mut res = Vec::new();
+    for (name, value) in params {
+        len(&mut res, name.len());
+        len(&mut res, value.len());
+        res.extend_from_slice(name.as_bytes());
+        res.extend_from_slice(value.as_bytes());
+    }
+    res
+}
+
+pub async fn serve(
+    cfg: &Gateway,
+    ctx: &Context,
+    captures: &Captures,
+    req: Request<Incoming>,
+) -> Result<SvcResponse> {
+    let path = req.uri().path();
+    let script = match &cfg.script {
+        Some(script) => Script::fixed(Some(script), path, captures),
+        None => {
+            let Some(root) = &ctx.document_root else {
+                tracing::error!(
+                    "FastCGI route has no script, and there's no document root configured"
+                );
+                return cgi::status(StatusCode::INTERNAL_SERVER_ERROR);
+            };
+            let root = tokio::fs::canonicalize(root).await?;
+            let request_path = captures.subpath.as_deref().unwrap_or(path);
+            match static_files::resolve(&root, request_path).await? {
+                Some(filename) if tokio::fs::metadata(&filename).await?.is_file() => Script {
+                    filename: Some(filename),
+                    name: path.to_owned(),
+                    path_info: String::new(),
+                },
+                _ => return cgi::status(StatusCode::NOT_FOUND),
+            }
+        }
+    };
+    let Some(req) = cgi::sized(req, cfg.max_buffered_body).await? else {
+        return cgi::status(StatusCode::PAYLOAD_TOO_LARGE);
+    };
+    let mut params = cgi::environment(&req, ctx, &script);
+    params.extend(cfg.env.iter().map(|(n, v)| (n.clone(), v.clone())));
+
+    let deadline = Instant::now() + Duration::from_millis(cfg.timeout_ms);
+    let Some((socket, upstream, lease)) =
+        cgi::connect(&ctx.upstreams, &cfg.upstream, req.headers(), deadline).await
+    else {
+        return cgi::status(StatusCode::BAD_GATEWAY);
+    };
+    let source = upstream.to_string();
+    let body = req.into_body();
+    cgi::respond(source.clone(), lease, deadline, |stdout| {
+        exchange(socket, params, body, stdout, source)
+    })
+    .await
+}
+
+/// Send a request to the responder, and copy its output to `stdout` until it ends the request.
+async fn exchange(
+    socket: Socket,
+    params: Vec<(String, String)>,
+    mut body: BoxBody<Bytes, ServiceError>,
+    mut stdout: DuplexStream,
+    source: String,
+) -> std::io::Result<()> {
+    let (mut rd, mut wr) = tokio::io::split(socket);
+    let send = async {
+        let mut begin = RESPONDER.to_be_bytes().to_vec();
+        // flags (don't keep the connection open), then reserved bytes
+        begin.resize(8, 0);
+        wr.write_all(&record(BEGIN_REQUEST, &begin)).await?;
+        wr.write_all(&stream(PARAMS, &encode_params(&params)))
+            .await?;
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(std::io::Error::other)?;
+            if let Ok(data) = frame.into_data() {
+                for chunk in data.chunks(MAX_CONTENT_LEN) {
+                    wr.write_all(&record(STDIN, chunk)).await?;
+                }
+            }
+        }
+        wr.write_all(&record(STDIN, &[])).await?;
+        wr.flush().await
+    };
+    let receive = async {
+        loop {
+            let mut header = [0; 8];
+            rd.read_exact(&mut header).await?;
+            let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let mut content = vec![0; len + header[6] as usize];
+            rd.read_exact(&mut content).await?;
+            content.truncate(len);
+            match header[1] {
+                // if the client's gone, there's no point in reading further
+                STDOUT if stdout.write_all(&content).await.is_err() => return Ok(()),
+                STDERR => cgi::log_stderr(&source, &content),
+                END_REQUEST => return Ok(()),
+                _ => {}
+            }
+        }
+    };
+    cgi::send_and_receive(send, receive).await
+}
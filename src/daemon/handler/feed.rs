@@ -0,0 +1,481 @@
+//! Feeds & sitemaps of the pages in a directory.
+
+use super::{autoindex::SEGMENT, escape, markdown, static_files, unescape, Context};
+use crate::config::routes::{request_host, Feed, FeedFormat};
+use crate::daemon::{
+    service::{empty, full, Result, SvcResponse},
+    templates,
+};
+use bytes::Bytes;
+use hyper::{header, Method, Request, Response, StatusCode};
+use notify::Watcher;
+use percent_encoding::utf8_percent_encode;
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fmt::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use time::{
+    format_description::well_known::{Rfc2822, Rfc3339},
+    macros::format_description,
+    Date, OffsetDateTime, PrimitiveDateTime,
+};
+
+/// The most generated documents to remember before forgetting all of them.
+const MAX_CACHED: usize = 64;
+
+/// How long a directory's fingerprint is trusted, when the directory can't be watched for changes.
+const FINGERPRINT_TTL: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    /// Generated documents, by a hash of their settings & the version of their directory.
+    static ref CACHE: parking_lot::Mutex<HashMap<blake3::Hash, Bytes>> = Default::default();
+    /// How changes to each directory are noticed.
+    static ref CHANGES: parking_lot::Mutex<HashMap<PathBuf, Changes>> = Default::default();
+}
+
+enum Changes {
+    /// Counts the changes reported by the watcher, which is kept here so that it keeps watching.
+    Watched {
+        _watcher: notify::RecommendedWatcher,
+        count: Arc<AtomicU64>,
+    },
+    /// The directory couldn't be watched, so its fingerprint is taken every so often instead.
+    Polled(Instant, blake3::Hash),
+}
+
+/// A hash which changes whenever anything within `root` does, without looking through `root`
+/// each time.
+fn version(root: &Path) -> blake3::Hash {
+    let mut changes = CHANGES.lock();
+    let changes = changes
+        .entry(root.to_owned())
+        .or_insert_with(|| match watch(root) {
+            Ok((watcher, count)) => Changes::Watched {
+                _watcher: watcher,
+                count,
+            },
+            Err(e) => {
+                tracing::warn!(?root, error = %e, "failed to watch feed directory; checking it for changes every {FINGERPRINT_TTL:?} instead");
+                Changes::Polled(Instant::now(), templates::fingerprint(std::iter::once(root)))
+            }
+        });
+    match changes {
+        Changes::Watched { count, .. } => {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(root.as_os_str().as_bytes());
+            hasher.update(&count.load(Ordering::Acquire).to_le_bytes());
+            hasher.finalize()
+        }
+        Changes::Polled(taken, fingerprint) => {
+            if taken.elapsed() >= FINGERPRINT_TTL {
+                *fingerprint = templates::fingerprint(std::iter::once(root));
+                *taken = Instant::now();
+            }
+            *fingerprint
+        }
+    }
+}
+
+/// Watch everything within `root`, counting changes.
+fn watch(root: &Path) -> notify::Result<(notify::RecommendedWatcher, Arc<AtomicU64>)> {
+    let count = Arc::new(AtomicU64::new(0));
+    let counter = count.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) if !matches!(event.kind, notify::EventKind::Access(_)) => {
+                counter.fetch_add(1, Ordering::Release);
+            }
+            Ok(_) => {}
+            // events may have been missed
+            Err(_) => {
+                counter.fetch_add(1, Ordering::Release);
+            }
+        })?;
+    watcher.watch(root, notify::RecursiveMode::Recursive)?;
+    Ok((watcher, count))
+}
+
+pub async fn serve<B>(cfg: &Feed, ctx: &Context, req: &Request<B>) -> Result<SvcResponse> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD")
+            .body(empty())?);
+    }
+    let Some(root) = cfg.root.as_ref().or(ctx.document_root.as_ref()) else {
+        tracing::error!("feed route has no root, and there's no document root configured");
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(empty())?);
+    };
+    let root = tokio::fs::canonicalize(root).await?;
+    let host = ctx
+        .host_name
+        .as_deref()
+        .or(request_host(req))
+        .unwrap_or(&ctx.server_name)
+        .to_owned();
+    let base_url = match &cfg.base_url {
+        Some(base_url) => base_url.trim_end_matches('/').to_owned(),
+        None => ctx.origin(req),
+    };
+    let self_url = format!("{base_url}{}", req.uri().path());
+
+    let content_type = match cfg.format {
+        FeedFormat::Rss => "application/rss+xml; charset=utf-8",
+        FeedFormat::Atom => "application/atom+xml; charset=utf-8",
+        FeedFormat::Sitemap => "application/xml; charset=utf-8",
+    };
+    let cfg = cfg.clone();
+    let (key, doc) = tokio::task::spawn_blocking(move || {
+        let site = Site {
+            cfg: &cfg,
+            base_url: &base_url,
+            host: &host,
+            self_url: &self_url,
+        };
+        site.generate(&root)
+    })
+    .await
+    .map_err(std::io::Error::from)?;
+
+    let etag = format!("\"{}\"", &key.to_hex()[..16]);
+    if static_files::not_modified(req.headers(), &etag, None) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .body(empty())?);
+    }
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ETAG, etag)
+        .body(full(doc))?)
+}
+
+#[derive(Debug)]
+struct Page {
+    url: String,
+    title: Option<String>,
+    date: Option<OffsetDateTime>,
+    /// When the page was last changed; defaults to its modification time.
+    updated: OffsetDateTime,
+    summary: Option<String>,
+    author: Option<String>,
+}
+
+struct Site<'s> {
+    cfg: &'s Feed,
+    base_url: &'s str,
+    host: &'s str,
+    /// The URL of the feed itself.
+    self_url: &'s str,
+}
+
+impl Site<'_> {
+    /// Generate the document (or find it in the cache), returning its cache key too.
+    fn generate(&self, root: &Path) -> (blake3::Hash, Bytes) {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(version(root).as_bytes());
+        hasher.update(&serde_json::to_vec(self.cfg).unwrap_or_default());
+        for part in [self.base_url, self.host, self.self_url] {
+            hasher.update(part.as_bytes());
+            hasher.update(b"\0");
+        }
+        let key = hasher.finalize();
+        if let Some(doc) = CACHE.lock().get(&key) {
+            return (key, doc.clone());
+        }
+
+        let pages = self.scan(root);
+        let doc = Bytes::from(match self.cfg.format {
+            FeedFormat::Rss => self.rss(pages),
+            FeedFormat::Atom => self.atom(pages),
+            FeedFormat::Sitemap => sitemap(pages),
+        });
+        tracing::debug!(?root, format = ?self.cfg.format, "generated feed");
+        let mut cache = CACHE.lock();
+        if cache.len() >= MAX_CACHED {
+            cache.clear();
+        }
+        cache.insert(key, doc.clone());
+        (key, doc)
+    }
+
+    /// Every page within `root`, skipping files & directories whose names start with `.` or `_`.
+    fn scan(&self, root: &Path) -> Vec<Page> {
+        let prefix = format!("/{}/", self.cfg.prefix.trim_matches('/')).replace("//", "/");
+        let mut pages = Vec::new();
+        let mut pending = vec![(root.to_owned(), prefix)];
+        while let Some((dir, path)) = pending.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.filter_map(|e| e.ok()) {
+                let name = entry.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                if name.starts_with(['.', '_']) {
+                    continue;
+                }
+                let encoded = utf8_percent_encode(name, SEGMENT).to_string();
+                // symlinked directories aren't followed, in case they form a cycle
+                if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                    pending.push((entry.path(), format!("{path}{encoded}/")));
+                    continue;
+                }
+                let url = match Path::new(name).extension().and_then(OsStr::to_str) {
+                    Some("md") if name == "index.md" => path.clone(),
+                    Some("md") => format!("{path}{}", &encoded[..encoded.len() - 3]),
+                    Some("html") if name == "index.html" => path.clone(),
+                    Some("html") => format!("{path}{encoded}"),
+                    _ => continue,
+                };
+                let url = format!("{}{url}", self.base_url);
+                if let Some(page) = read_page(&entry.path(), url) {
+                    pages.push(page);
+                }
+            }
+        }
+        pages
+    }
+
+    fn title(&self) -> &str {
+        self.cfg.title.as_deref().unwrap_or(self.host)
+    }
+
+    /// The newest dated pages, newest first.
+    fn entries(&self, pages: Vec<Page>) -> Vec<Page> {
+        let mut pages = pages
+            .into_iter()
+            .filter(|p| p.date.is_some())
+            .collect::<Vec<_>>();
+        pages.sort_by_key(|p| std::cmp::Reverse(p.date));
+        pages.truncate(self.cfg.limit);
+        pages
+    }
+
+    fn rss(&self, pages: Vec<Page>) -> String {
+        let entries = self.entries(pages);
+        let mut res = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        res.push_str(
+            "<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n<channel>\n",
+        );
+        let _ = writeln!(res, "<title>{}</title>", escape(self.title()));
+        let _ = writeln!(res, "<link>{}/</link>", escape(self.base_url));
+        let _ = writeln!(
+            res,
+            "<description>{}</description>",
+            escape(&self.cfg.description)
+        );
+        let _ = writeln!(
+            res,
+            "<atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>",
+            escape(self.self_url)
+        );
+        if let Some(Ok(date)) = entries
+            .first()
+            .and_then(|e| e.date)
+            .map(|d| d.format(&Rfc2822))
+        {
+            let _ = writeln!(res, "<lastBuildDate>{date}</lastBuildDate>");
+        }
+        for entry in &entries {
+            res.push_str("<item>\n");
+            let url = escape(&entry.url);
+            if let Some(title) = &entry.title {
+                let _ = writeln!(res, "<title>{}</title>", escape(title));
+            }
+            let _ = writeln!(res, "<link>{url}</link>");
+            let _ = writeln!(res, "<guid isPermaLink=\"true\">{url}</guid>");
+            if let Some(Ok(date)) = entry.date.map(|d| d.format(&Rfc2822)) {
+                let _ = writeln!(res, "<pubDate>{date}</pubDate>");
+            }
+            if let Some(summary) = &entry.summary {
+                let _ = writeln!(res, "<description>{}</description>", escape(summary));
+            }
+            res.push_str("</item>\n");
+        }
+        res.push_str("</channel>\n</rss>\n");
+        res
+    }
+
+    fn atom(&self, pages: Vec<Page>) -> String {
+        let entries = self.entries(pages);
+        let updated = entries
+            .iter()
+            .map(|e| e.updated.max(e.date.unwrap_or(e.updated)))
+            .max()
+            .unwrap_or_else(OffsetDateTime::now_utc);
+        let mut res = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        res.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        let _ = writeln!(res, "<title>{}</title>", escape(self.title()));
+        if !self.cfg.description.is_empty() {
+            let _ = writeln!(
+                res,
+                "<subtitle>{}</subtitle>",
+                escape(&self.cfg.description)
+            );
+        }
+        let _ = writeln!(res, "<id>{}/</id>", escape(self.base_url));
+        let _ = writeln!(res, "<link href=\"{}/\"/>", escape(self.base_url));
+        let _ = writeln!(
+            res,
+            "<link rel=\"self\" href=\"{}\"/>",
+            escape(self.self_url)
+        );
+        let _ = writeln!(res, "<updated>{}</updated>", rfc3339(updated));
+        // feeds need an author, unless every entry has one
+        let author = self.cfg.author.as_deref().unwrap_or(self.title());
+        let _ = writeln!(res, "<author><name>{}</name></author>", escape(author));
+        for entry in &entries {
+            res.push_str("<entry>\n");
+            let url = escape(&entry.url);
+            let title = entry.title.as_deref().unwrap_or(&entry.url);
+            let _ = writeln!(res, "<title>{}</title>", escape(title));
+            let _ = writeln!(res, "<id>{url}</id>");
+            let _ = writeln!(res, "<link href=\"{url}\"/>");
+            if let Some(date) = entry.date {
+                let _ = writeln!(res, "<published>{}</published>", rfc3339(date));
+                let updated = entry.updated.max(date);
+                let _ = writeln!(res, "<updated>{}</updated>", rfc3339(updated));
+            }
+            if let Some(author) = &entry.author {
+                let _ = writeln!(res, "<author><name>{}</name></author>", escape(author));
+            }
+            if let Some(summary) = &entry.summary {
+                let _ = writeln!(res, "<summary>{}</summary>", escape(summary));
+            }
+            res.push_str("</entry>\n");
+        }
+        res.push_str("</feed>\n");
+        res
+    }
+}
+
+fn sitemap(mut pages: Vec<Page>) -> String {
+    pages.sort_by(|a, b| a.url.cmp(&b.url));
+    let mut res = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    res.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    for page in &pages {
+        let _ = writeln!(
+            res,
+            "<url><loc>{}</loc><lastmod>{}</lastmod></url>",
+            escape(&page.url),
+            rfc3339(page.updated)
+        );
+    }
+    res.push_str("</urlset>\n");
+    res
+}
+
+/// Format `date` per RFC 3339, to the second.
+fn rfc3339(date: OffsetDateTime) -> String {
+    let date = date.replace_nanosecond(0).unwrap_or(date);
+    date.format(&Rfc3339).unwrap_or_default()
+}
+
+/// Read the metadata of the page at `path`, which is served at `url`; `None` if it's a draft, or
+/// can't be read.
+fn read_page(path: &PathBuf, url: String) -> Option<Page> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    let mut page = Page {
+        url,
+        title: None,
+        date: None,
+        updated: modified.into(),
+        summary: None,
+        author: None,
+    };
+    if path.extension() == Some(OsStr::new("md")) {
+        let meta = markdown::front_matter(&text).0.unwrap_or_default();
+        if meta.get("draft").and_then(|d| d.as_bool()) == Some(true) {
+            return None;
+        }
+        let string = |key| meta.get(key).and_then(|v| v.as_str()).map(str::to_owned);
+        page.title = string("title");
+        page.date = string("date").as_deref().and_then(parse_date);
+        if let Some(updated) = string("updated").as_deref().and_then(parse_date) {
+            page.updated = updated;
+        }
+        page.summary = string("summary").or_else(|| string("description"));
+        page.author = string("author");
+    } else {
+        // tag & attribute names are matched case-insensitively; ASCII lowercasing keeps offsets
+        let lower = text.to_ascii_lowercase();
+        page.title = lower.find("<title").and_then(|start| {
+            let start = start + lower[start..].find('>')? + 1;
+            let end = start + lower[start..].find("</title")?;
+            Some(unescape(text[start..end].trim()))
+        });
+        for start in lower.match_indices("<meta").map(|(i, _)| i) {
+            let Some(len) = lower[start..].find('>') else {
+                break;
+            };
+            let (tag, lower_tag) = (&text[start..start + len], &lower[start..start + len]);
+            let content = attribute(tag, lower_tag, "content").map(unescape);
+            match attribute(lower_tag, lower_tag, "name") {
+                Some("date") => page.date = content.as_deref().and_then(parse_date),
+                Some("description") => page.summary = content,
+                Some("author") => page.author = content,
+                _ => {}
+            }
+        }
+    }
+    Some(page)
+}
+
+/// The value of the attribute `name` in `tag`, found by searching `lower_tag` (its lowercase
+/// copy); only quoted values are recognized.
+fn attribute<'t>(tag: &'t str, lower_tag: &str, name: &str) -> Option<&'t str> {
+    let mut from = 0;
+    while let Some(i) = lower_tag[from..].find(name) {
+        let at = from + i;
+        from = at + name.len();
+        let preceded = lower_tag[..at].ends_with(|c: char| c.is_ascii_whitespace());
+        let rest = lower_tag[from..].trim_start();
+        let Some(rest) = rest.strip_prefix('=').map(str::trim_start) else {
+            continue;
+        };
+        let Some(quote) = rest.chars().next().filter(|&q| q == '"' || q == '\'') else {
+            continue;
+        };
+        if !preceded {
+            continue;
+        }
+        let start = lower_tag.len() - rest.len() + 1;
+        let len = lower_tag[start..].find(quote)?;
+        return Some(&tag[start..start + len]);
+    }
+    None
+}
+
+/// Parse an RFC 3339 date-time, a date-time without an offset (as UTC), or a date (as midnight
+/// UTC).
+fn parse_date(date: &str) -> Option<OffsetDateTime> {
+    let date = date.trim();
+    OffsetDateTime::parse(date, &Rfc3339)
+        .ok()
+        .or_else(|| {
+            PrimitiveDateTime::parse(
+                date,
+                format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]"),
+            )
+            .ok()
+            .map(PrimitiveDateTime::assume_utc)
+        })
+        .or_else(|| {
+            Date::parse(date, format_description!("[year]-[month]-[day]"))
+                .ok()
+                .map(|d| d.midnight().assume_utc())
+        })
+}
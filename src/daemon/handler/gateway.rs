@@ -0,0 +1,132 @@
+//! SCGI and uwsgi, which each send a block of CGI variables followed by the request body.
+
+use super::{
+    cgi::{self, Script},
+    Context,
+};
+use crate::config::routes::{Captures, Gateway};
+use crate::daemon::{
+    service::{Result, ServiceError, SvcResponse},
+    upstreams::Socket,
+};
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{body::Incoming, Request, StatusCode};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream},
+    time::Instant,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Scgi,
+    Uwsgi,
+}
+
+impl Protocol {
+    /// The header block carrying `params`, or `None` if they don't fit in one.
+    fn encode(self, params: &[(String, String)]) -> Option<Vec<u8>> {
+        match self {
+            Protocol::Scgi => {
+                // CONTENT_LENGTH must come first, and is always present
+                let length = params
+                    .iter()
+                    .find(|(n, _)| n == "CONTENT_LENGTH")
+                    .map_or("0", |(_, v)| v.as_str());
+                let mut headers = Vec::new();
+                let pairs = [("CONTENT_LENGTH", length), ("SCGI", "1")]
+                    .into_iter()
+                    .chain(
+                        params
+                            .iter()
+                            .filter(|(n, _)| n != "CONTENT_LENGTH" && n != "SCGI")
+                            .map(|(n, v)| (n.as_str(), v.as_str())),
+                    );
+                for (name, value) in pairs {
+                    headers.extend_from_slice(name.as_bytes());
+                    headers.push(0);
+                    headers.extend_from_slice(value.as_bytes());
+                    headers.push(0);
+                }
+                let mut res = format!("{}:", headers.len()).into_bytes();
+                res.extend(headers);
+                res.push(b',');
+                Some(res)
+            }
+            Protocol::Uwsgi => {
+                let mut vars = Vec::new();
+                for (name, value) in params {
+                    for s in [name, value] {
+                        vars.extend_from_slice(&u16::try_from(s.len()).ok()?.to_le_bytes());
+                        vars.extend_from_slice(s.as_bytes());
+                    }
+                }
+                // modifier 0 (a WSGI request), the size of the variables, then modifier 0 again
+                let mut res = vec![0];
+                res.extend_from_slice(&u16::try_from(vars.len()).ok()?.to_le_bytes());
+                res.push(0);
+                res.extend(vars);
+                Some(res)
+            }
+        }
+    }
+}
+
+pub async fn serve(
+    cfg: &Gateway,
+    protocol: Protocol,
+    ctx: &Context,
+    captures: &Captures,
+    req: Request<Incoming>,
+) -> Result<SvcResponse> {
+    let script = Script::fixed(cfg.script.as_deref(), req.uri().path(), captures);
+    let Some(req) = cgi::sized(req, cfg.max_buffered_body).await? else {
+        return cgi::status(StatusCode::PAYLOAD_TOO_LARGE);
+    };
+    let mut params = cgi::environment(&req, ctx, &script);
+    params.extend(cfg.env.iter().map(|(n, v)| (n.clone(), v.clone())));
+    let Some(header) = protocol.encode(&params) else {
+        return cgi::status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    };
+
+    let deadline = Instant::now() + Duration::from_millis(cfg.timeout_ms);
+    let Some((socket, upstream, lease)) =
+        cgi::connect(&ctx.upstreams, &cfg.upstream, req.headers(), deadline).await
+    else {
+        return cgi::status(StatusCode::BAD_GATEWAY);
+    };
+    let body = req.into_body();
+    cgi::respond(upstream.to_string(), lease, deadline, |stdout| {
+        exchange(socket, header, body, stdout)
+    })
+    .await
+}
+
+/// Send a request to the server, and copy its response to `stdout` until it closes the connection.
+async fn exchange(
+    socket: Socket,
+    header: Vec<u8>,
+    mut body: BoxBody<Bytes, ServiceError>,
+    mut stdout: DuplexStream,
+) -> std::io::Result<()> {
+    let (mut rd, mut wr) = tokio::io::split(socket);
+    let send = async {
+        wr.write_all(&header).await?;
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(std::io::Error::other)?;
+            if let Ok(data) = frame.into_data() {
+                wr.write_all(&data).await?;
+            }
+        }
+        wr.flush().await
+    };
+    let receive = async {
+        // if the client's gone, there's no point in reading further
+        match tokio::io::copy(&mut rd, &mut stdout).await {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+            res => res.map(drop),
+        }
+    };
+    cgi::send_and_receive(send, receive).await
+}
@@ -0,0 +1,579 @@
+//! An IndieAuth server: the metadata, authorization, & token endpoints.
+
+use super::{collect_body, escape, Context};
+use crate::config::routes::{Captures, IndieAuth};
+use crate::daemon::{
+    indieauth::{self, Code, Grant},
+    service::{empty, full, Result, SvcResponse},
+};
+use hyper::{body::Incoming, header, Method, Request, Response, StatusCode};
+use std::{
+    fmt::Write,
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+use url::Url;
+
+/// The largest request body accepted.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// How long to wait before answering a wrong password, to slow down guessing.
+const FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+/// How many passwords may be tried before signing in is locked.
+const MAX_FAILURES: u32 = 5;
+
+/// How long signing in is first locked for; each later failure doubles it, up to [MAX_LOCKOUT].
+const LOCKOUT: Duration = Duration::from_secs(60);
+
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How long after the last failure the count of failures starts again.
+const FORGET_FAILURES: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The scopes a client may be granted; others are ignored.
+const SCOPES: &[&str] = &["profile", "email", "create", "update", "delete", "media"];
+
+const STYLE: &str =
+    "body { font-family: sans-serif; max-width: 30em; margin: 2em auto; padding: 0 1em; }
+label { display: block; margin: 0.5em 0; }
+.error { color: #b00; }
+";
+
+lazy_static::lazy_static! {
+    /// Sign-in attempts since the last success, from anywhere: there's only the one password, and
+    /// guessers can easily change addresses.
+    static ref ATTEMPTS: parking_lot::Mutex<Attempts> = Default::default();
+}
+
+#[derive(Debug, Default)]
+struct Attempts {
+    /// Attempts which weren't (or may not yet have been) successful.
+    failures: u32,
+    last: Option<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    /// Count an attempt, which is taken to have failed until [Self::succeeded] is called, so that
+    /// concurrent guesses count too; if signing in is locked, how long it'll be locked for.
+    fn begin(&mut self, now: Instant) -> std::result::Result<(), Duration> {
+        if let Some(until) = self.locked_until.filter(|until| *until > now) {
+            return Err(until - now);
+        }
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) > FORGET_FAILURES)
+        {
+            self.failures = 0;
+        }
+        self.failures += 1;
+        self.last = Some(now);
+        if self.failures >= MAX_FAILURES {
+            let doublings = 2u32.saturating_pow(self.failures - MAX_FAILURES);
+            self.locked_until = Some(now + LOCKOUT.saturating_mul(doublings).min(MAX_LOCKOUT));
+        }
+        Ok(())
+    }
+
+    fn succeeded(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Request parameters, from a query or form, in order.
+#[derive(Debug, Default)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn parse(input: &[u8]) -> Self {
+        Self(url::form_urlencoded::parse(input).into_owned().collect())
+    }
+
+    /// The first value of `name`, unless it's empty.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+
+    pub fn all<'p>(&'p self, name: &'p str) -> impl Iterator<Item = &'p str> + 'p {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+/// Where this server's endpoints are.
+struct Server {
+    /// The URL of the directory containing the endpoints, which identifies this server.
+    issuer: String,
+    me: String,
+}
+
+pub async fn serve(
+    cfg: &IndieAuth,
+    ctx: &Context,
+    captures: &Captures,
+    req: Request<Incoming>,
+) -> Result<SvcResponse> {
+    let Some(endpoint) = captures.subpath.clone() else {
+        tracing::error!("indieauth routes must have a prefix path pattern");
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(empty())?);
+    };
+    let path = req.uri().path();
+    let base = path[..path.len().saturating_sub(endpoint.len())].trim_end_matches('/');
+    let origin = ctx.origin(&req);
+    let server = Server {
+        issuer: format!("{origin}{base}/"),
+        me: cfg.me.clone().unwrap_or_else(|| format!("{origin}/")),
+    };
+
+    match (endpoint.trim_start_matches('/'), req.method().clone()) {
+        ("" | "metadata", Method::GET | Method::HEAD) => metadata(&server),
+        ("auth", Method::GET | Method::HEAD) => {
+            let params = Params::parse(req.uri().query().unwrap_or_default().as_bytes());
+            match AuthRequest::new(&params, &server) {
+                Ok(auth) => page(StatusCode::OK, &login_form(&auth, None)),
+                Err(message) => page(StatusCode::BAD_REQUEST, &problem(message)),
+            }
+        }
+        ("auth", Method::POST) => {
+            let Some(params) = read_form(req).await? else {
+                return too_large();
+            };
+            match params.get("grant_type") {
+                Some(_) => redeem(ctx, &params, None).await,
+                None => approve(cfg, ctx, &params, &server).await,
+            }
+        }
+        ("token", Method::GET | Method::HEAD) => {
+            let grant = match indieauth::bearer_token(req.headers()) {
+                Some(token) => indieauth::token(&ctx.state_dir, token).await?,
+                None => None,
+            };
+            match grant {
+                Some(grant) => json(
+                    StatusCode::OK,
+                    serde_json::json!({
+                        "me": grant.me,
+                        "client_id": grant.client_id,
+                        "scope": grant.scope,
+                    }),
+                ),
+                None => error(StatusCode::UNAUTHORIZED, "invalid_token", "unknown token"),
+            }
+        }
+        ("token", Method::POST) => {
+            let Some(params) = read_form(req).await? else {
+                return too_large();
+            };
+            match (params.get("grant_type"), params.get("token")) {
+                (Some(_), _) => redeem(ctx, &params, Some(cfg)).await,
+                // revocation, either per RFC 7009 or IndieAuth's older `action=revoke`
+                (None, Some(token)) => {
+                    indieauth::revoke(&ctx.state_dir, token).await?;
+                    Ok(Response::builder().body(empty())?)
+                }
+                (None, None) => error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    "expected `grant_type` or `token`",
+                ),
+            }
+        }
+        ("" | "metadata" | "auth" | "token", _) => Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD, POST")
+            .body(empty())?),
+        _ => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(empty())?),
+    }
+}
+
+fn metadata(server: &Server) -> Result<SvcResponse> {
+    let issuer = &server.issuer;
+    json(
+        StatusCode::OK,
+        serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}auth"),
+            "token_endpoint": format!("{issuer}token"),
+            "revocation_endpoint": format!("{issuer}token"),
+            "revocation_endpoint_auth_methods_supported": ["none"],
+            "scopes_supported": SCOPES,
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code"],
+            "code_challenge_methods_supported": ["S256"],
+            "authorization_response_iss_parameter_supported": true,
+        }),
+    )
+}
+
+/// A validated authorization request.
+#[derive(Debug)]
+struct AuthRequest<'p> {
+    client_id: Url,
+    redirect_uri: Url,
+    state: &'p str,
+    code_challenge: &'p str,
+    /// The requested scopes which we support.
+    scopes: Vec<&'p str>,
+    me: &'p str,
+    issuer: &'p str,
+}
+
+impl<'p> AuthRequest<'p> {
+    fn new(params: &'p Params, server: &'p Server) -> std::result::Result<Self, &'static str> {
+        if params.get("response_type").is_some_and(|t| t != "code") {
+            return Err("`response_type` must be `code`");
+        }
+        let Some(Ok(client_id)) = params.get("client_id").map(Url::parse) else {
+            return Err("`client_id` must be a URL");
+        };
+        let Some(Ok(redirect_uri)) = params.get("redirect_uri").map(Url::parse) else {
+            return Err("`redirect_uri` must be a URL");
+        };
+        if !matches!(client_id.scheme(), "http" | "https") || client_id.host_str().is_none() {
+            return Err("`client_id` must be an `http` or `https` URL");
+        }
+        if redirect_uri.origin() != client_id.origin() {
+            return Err("`redirect_uri` must be on the same host as `client_id`");
+        }
+        let Some(state) = params.get("state") else {
+            return Err("`state` is required");
+        };
+        let Some(code_challenge) = params.get("code_challenge") else {
+            return Err("`code_challenge` is required; clients must use PKCE");
+        };
+        if params.get("code_challenge_method") != Some("S256") {
+            return Err("`code_challenge_method` must be `S256`");
+        }
+        let scopes = params
+            .get("scope")
+            .unwrap_or_default()
+            .split_ascii_whitespace()
+            .filter(|s| SCOPES.contains(s))
+            .collect();
+        Ok(Self {
+            client_id,
+            redirect_uri,
+            state,
+            code_challenge,
+            scopes,
+            me: &server.me,
+            issuer: &server.issuer,
+        })
+    }
+}
+
+/// The page on which the owner approves an authorization request.
+fn login_form(auth: &AuthRequest, error: Option<&str>) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<h1>Sign in</h1>\n<p><strong>{client}</strong> would like to sign in as <strong>{me}</strong>.</p>\n",
+        client = escape(auth.client_id.as_str()),
+        me = escape(auth.me),
+    );
+    if let Some(error) = error {
+        let _ = writeln!(html, "<p class=\"error\">{}</p>", escape(error));
+    }
+    let _ = writeln!(html, "<form method=\"post\">");
+    for (name, value) in [
+        ("response_type", "code"),
+        ("client_id", auth.client_id.as_str()),
+        ("redirect_uri", auth.redirect_uri.as_str()),
+        ("state", auth.state),
+        ("code_challenge", auth.code_challenge),
+        ("code_challenge_method", "S256"),
+    ] {
+        let _ = writeln!(
+            html,
+            "<input type=\"hidden\" name=\"{name}\" value=\"{}\">",
+            escape(value)
+        );
+    }
+    if !auth.scopes.is_empty() {
+        let _ = writeln!(html, "<p>It's asking for permission to:</p>");
+    }
+    for scope in &auth.scopes {
+        let scope = escape(scope);
+        let _ = writeln!(
+            html,
+            "<label><input type=\"checkbox\" name=\"grant_scope\" value=\"{scope}\" checked> {scope}</label>"
+        );
+    }
+    let _ = write!(
+        html,
+        "<label>Password <input type=\"password\" name=\"password\" autocomplete=\"current-password\" required autofocus></label>\n\
+        <button type=\"submit\">Approve</button>\n</form>\n<p>You'll be sent to <code>{}</code>.</p>\n",
+        escape(auth.redirect_uri.as_str())
+    );
+    html
+}
+
+fn problem(message: &str) -> String {
+    format!(
+        "<h1>Invalid request</h1>\n<p class=\"error\">{}</p>\n",
+        escape(message)
+    )
+}
+
+/// Check the owner's password, then send the client an authorization code.
+async fn approve(
+    cfg: &IndieAuth,
+    ctx: &Context,
+    params: &Params,
+    server: &Server,
+) -> Result<SvcResponse> {
+    let mut auth = match AuthRequest::new(params, server) {
+        Ok(auth) => auth,
+        Err(message) => return page(StatusCode::BAD_REQUEST, &problem(message)),
+    };
+    // the owner may grant fewer scopes than were requested
+    auth.scopes = params
+        .all("grant_scope")
+        .filter(|s| SCOPES.contains(s))
+        .collect();
+
+    if let Err(locked_for) = ATTEMPTS.lock().begin(Instant::now()) {
+        tracing::warn!(client_id = %auth.client_id, ?locked_for, "IndieAuth sign-in is locked");
+        let minutes = locked_for.as_secs().div_ceil(60);
+        let mut res = page(
+            StatusCode::TOO_MANY_REQUESTS,
+            &login_form(
+                &auth,
+                Some(&format!(
+                    "Too many wrong passwords have been tried; try again in {minutes} minute{}.",
+                    if minutes == 1 { "" } else { "s" }
+                )),
+            ),
+        )?;
+        res.headers_mut()
+            .insert(header::RETRY_AFTER, locked_for.as_secs().max(1).into());
+        return Ok(res);
+    }
+    let (hash, password) = (
+        cfg.password_hash.clone(),
+        params.get("password").unwrap_or_default().to_owned(),
+    );
+    let correct = !hash.is_empty()
+        && tokio::task::spawn_blocking(move || indieauth::verify_password(&hash, &password))
+            .await
+            .map_err(std::io::Error::from)?;
+    if !correct {
+        tracing::warn!(client_id = %auth.client_id, "failed IndieAuth sign-in");
+        tokio::time::sleep(FAILURE_DELAY).await;
+        return page(
+            StatusCode::FORBIDDEN,
+            &login_form(&auth, Some("That password isn't right.")),
+        );
+    }
+    ATTEMPTS.lock().succeeded();
+
+    let expires = OffsetDateTime::now_utc().unix_timestamp() + cfg.code_lifetime_secs as i64;
+    let code = indieauth::issue_code(
+        &ctx.state_dir,
+        Code {
+            grant: Grant {
+                me: auth.me.to_owned(),
+                client_id: auth.client_id.to_string(),
+                scope: auth.scopes.join(" "),
+                expires: Some(expires),
+            },
+            redirect_uri: auth.redirect_uri.to_string(),
+            code_challenge: auth.code_challenge.to_owned(),
+        },
+    )
+    .await?;
+    tracing::info!(client_id = %auth.client_id, scope = auth.scopes.join(" "), "approved IndieAuth sign-in");
+    let mut location = auth.redirect_uri.clone();
+    location
+        .query_pairs_mut()
+        .append_pair("code", &code)
+        .append_pair("state", auth.state)
+        .append_pair("iss", auth.issuer);
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, location.as_str())
+        .body(empty())?)
+}
+
+/// Exchange an authorization code for the profile it identifies or, if `token` is set, an access
+/// token.
+async fn redeem(ctx: &Context, params: &Params, token: Option<&IndieAuth>) -> Result<SvcResponse> {
+    if params.get("grant_type") != Some("authorization_code") {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            "`grant_type` must be `authorization_code`",
+        );
+    }
+    let (Some(code), Some(client_id), Some(redirect_uri), Some(verifier)) = (
+        params.get("code"),
+        params.get("client_id"),
+        params.get("redirect_uri"),
+        params.get("code_verifier"),
+    ) else {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "`code`, `client_id`, `redirect_uri`, & `code_verifier` are required",
+        );
+    };
+    let Some(code) = indieauth::redeem_code(&ctx.state_dir, code).await? else {
+        return error(StatusCode::BAD_REQUEST, "invalid_grant", "unknown code");
+    };
+    // compare as URLs, since we normalized them when issuing the code
+    let same = |a: &str, b: &str| Url::parse(a).is_ok_and(|a| a.as_str() == b);
+    if !same(client_id, &code.grant.client_id)
+        || !same(redirect_uri, &code.redirect_uri)
+        || !indieauth::pkce_matches(&code.code_challenge, verifier)
+    {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "invalid_grant",
+            "the code was issued for a different request",
+        );
+    }
+
+    let Some(cfg) = token else {
+        return json(StatusCode::OK, serde_json::json!({ "me": code.grant.me }));
+    };
+    if code.grant.scope.is_empty() {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "invalid_grant",
+            "no scopes were granted, so no token may be issued",
+        );
+    }
+    let lifetime = (cfg.token_lifetime_days > 0).then(|| cfg.token_lifetime_days as i64 * 86_400);
+    let grant = Grant {
+        expires: lifetime.map(|l| OffsetDateTime::now_utc().unix_timestamp() + l),
+        ..code.grant
+    };
+    let access_token = indieauth::issue_token(&ctx.state_dir, grant.clone()).await?;
+    tracing::info!(
+        client_id = grant.client_id,
+        scope = grant.scope,
+        "issued IndieAuth token"
+    );
+    let mut res = serde_json::json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "scope": grant.scope,
+        "me": grant.me,
+    });
+    if let Some(lifetime) = lifetime {
+        res["expires_in"] = lifetime.into();
+    }
+    json(StatusCode::OK, res)
+}
+
+async fn read_form(req: Request<Incoming>) -> Result<Option<Params>> {
+    Ok(collect_body(req.into_body(), MAX_REQUEST_SIZE)
+        .await?
+        .map(|body| Params::parse(&body)))
+}
+
+fn too_large() -> Result<SvcResponse> {
+    Ok(Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(empty())?)
+}
+
+fn page(status: StatusCode, body: &str) -> Result<SvcResponse> {
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        // the form mustn't be framed by other sites
+        .header("x-frame-options", "DENY")
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; style-src 'unsafe-inline'; frame-ancestors 'none'",
+        )
+        .body(full(format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+            <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+            <title>Sign in</title>\n<style>\n{STYLE}</style>\n</head>\n<body>\n{body}</body>\n</html>\n"
+        )))?)
+}
+
+pub fn json(status: StatusCode, value: serde_json::Value) -> Result<SvcResponse> {
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(full(value.to_string()))?)
+}
+
+/// An OAuth-style error response.
+pub fn error(status: StatusCode, error: &str, description: &str) -> Result<SvcResponse> {
+    json(
+        status,
+        serde_json::json!({ "error": error, "error_description": description }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_lock_signing_in() {
+        let mut attempts = Attempts::default();
+        let start = Instant::now();
+        for _ in 1..MAX_FAILURES {
+            assert!(attempts.begin(start).is_ok());
+        }
+        // the attempt which reaches the limit is still checked, but those after it aren't
+        assert!(attempts.begin(start).is_ok());
+        assert_eq!(attempts.begin(start), Err(LOCKOUT));
+        let later = start + Duration::from_secs(20);
+        assert_eq!(
+            attempts.begin(later),
+            Err(LOCKOUT - Duration::from_secs(20))
+        );
+
+        // each failure after the lock lifts locks it for twice as long, up to a limit
+        let mut now = start + LOCKOUT;
+        for lockout in [2, 4, 8, 16, 32, 60, 60].map(|m| Duration::from_secs(m * 60)) {
+            assert!(attempts.begin(now).is_ok());
+            assert_eq!(attempts.begin(now), Err(lockout));
+            now += lockout;
+        }
+
+        // until a success
+        assert!(attempts.begin(now).is_ok());
+        attempts.succeeded();
+        for _ in 1..MAX_FAILURES {
+            assert!(attempts.begin(now).is_ok());
+        }
+    }
+
+    #[test]
+    fn failures_are_forgotten() {
+        let mut attempts = Attempts::default();
+        let mut now = Instant::now();
+        for _ in 0..3 {
+            for _ in 1..MAX_FAILURES {
+                assert!(attempts.begin(now).is_ok());
+            }
+            now += FORGET_FAILURES + Duration::from_secs(1);
+        }
+        assert_eq!(attempts.failures, MAX_FAILURES - 1);
+        assert!(attempts.locked_until.is_none());
+    }
+}
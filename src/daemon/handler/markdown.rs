@@ -0,0 +1,477 @@
+//! Renders Markdown files as HTML pages, caching the results until their sources change.
+
+use super::{
+    autoindex::SEGMENT,
+    escape,
+    static_files::{self, Representation},
+    Context,
+};
+use crate::config::routes::{Captures, Markdown, StaticFiles};
+use crate::daemon::{
+    compression::NoCompression,
+    encoding,
+    service::{empty, full, Result, SvcResponse},
+};
+use hyper::{header, Method, Request, Response, StatusCode};
+use percent_encoding::utf8_percent_encode;
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use syntect::{highlighting::ThemeSet, parsing::SyntaxSet};
+
+const DEFAULT_TEMPLATE: &str = "\
+<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+<title>{{title}}</title>
+</head>
+<body>
+{{content}}
+</body>
+</html>
+";
+
+const INDEX: &str = "index.md";
+
+lazy_static::lazy_static! {
+    static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref THEMES: ThemeSet = ThemeSet::load_defaults();
+}
+
+/// Front matter values, by key.
+pub type Meta = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FrontMatterError {
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    #[error("front matter must be a table of keys & values")]
+    NotATable,
+}
+
+/// Split a Markdown file into its front matter (YAML between `---` lines, or TOML between `+++`
+/// lines, at the very start of the file) and its content.
+pub fn front_matter(source: &str) -> (std::result::Result<Meta, FrontMatterError>, &str) {
+    let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+    let Some((first, rest)) = source.split_once('\n') else {
+        return (Ok(Meta::new()), source);
+    };
+    let delimiter = match first.trim_end() {
+        delimiter @ ("---" | "+++") => delimiter,
+        _ => return (Ok(Meta::new()), source),
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == delimiter {
+            let (matter, content) = (&rest[..offset], &rest[offset + line.len()..]);
+            let value = match delimiter {
+                "---" => serde_yaml::from_str(matter).map_err(FrontMatterError::from),
+                _ => toml::from_str(matter)
+                    .map(toml_to_json)
+                    .map_err(FrontMatterError::from),
+            };
+            let meta = value.and_then(|value| match value {
+                serde_json::Value::Object(meta) => Ok(meta),
+                serde_json::Value::Null => Ok(Meta::new()),
+                _ => Err(FrontMatterError::NotATable),
+            });
+            return (meta, content);
+        }
+        offset += line.len();
+    }
+    // an unterminated block is just content (ex. a document starting with a thematic break)
+    (Ok(Meta::new()), source)
+}
+
+/// A TOML value as JSON; dates & times become strings in their TOML format, rather than serde's
+/// private representation.
+fn toml_to_json(value: toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(s) => s.into(),
+        toml::Value::Integer(i) => i.into(),
+        toml::Value::Float(f) => f.into(),
+        toml::Value::Boolean(b) => b.into(),
+        toml::Value::Datetime(d) => d.to_string().into(),
+        toml::Value::Array(a) => a.into_iter().map(toml_to_json).collect(),
+        toml::Value::Table(t) => t.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect(),
+    }
+}
+
+pub async fn serve<B>(
+    cfg: &Markdown,
+    ctx: &Context,
+    captures: &Captures,
+    req: &Request<B>,
+) -> Result<SvcResponse> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD")
+            .body(empty())?);
+    }
+    let Some(root) = cfg.root.as_ref().or(ctx.document_root.as_ref()) else {
+        tracing::error!("markdown route has no root, and there's no document root configured");
+        return status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    let root = tokio::fs::canonicalize(root).await?;
+    let request_path = captures.subpath.as_deref().unwrap_or(req.uri().path());
+    let source = match find_source(&root, request_path).await? {
+        Source::Markdown(source) => source,
+        Source::Directory => {
+            // relative links in index files only work if the directory path ends with a slash
+            let location = match req.uri().query() {
+                Some(query) => format!("{}/?{query}", req.uri().path()),
+                None => format!("{}/", req.uri().path()),
+            };
+            return Ok(Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, location)
+                .body(empty())?);
+        }
+        Source::Other => {
+            let files = StaticFiles {
+                root: Some(root),
+                index: Vec::new(),
+                ..Default::default()
+            };
+            return static_files::serve(&files, ctx, captures, req).await;
+        }
+    };
+
+    let template_meta = match &cfg.template {
+        Some(template) => match tokio::fs::metadata(template).await {
+            Ok(meta) => Some(meta),
+            Err(e) => {
+                tracing::error!(?template, error = ?e, "failed to read markdown template");
+                return status(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        },
+        None => None,
+    };
+    let source_meta = tokio::fs::metadata(&source).await?;
+    let path = page_path(req.uri().path(), request_path, &root, &source);
+    // the name changes whenever the source or template does, so any cached page is fresh
+    let cached = cache_path(
+        &ctx.cache_dir,
+        cfg,
+        &path,
+        (&source, &source_meta),
+        template_meta.as_ref(),
+    );
+    let fresh = tokio::fs::metadata(&cached).await.is_ok();
+    if !fresh {
+        let page = render(cfg, &source, &path).await?;
+        if let Err(e) = write_cache(&cached, &page).await {
+            tracing::warn!(?source, ?cached, error = ?e, "failed to cache rendered markdown");
+            return Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(full(page))?);
+        }
+    }
+
+    let meta = tokio::fs::metadata(&cached).await?;
+    let accepted = encoding::accepted(req.headers());
+    let mut repr = Representation::new(&cached, &meta);
+    let mut file = (cached, meta.len());
+    static_files::use_compression_cache(ctx, &accepted, &meta, &mut repr, &mut file).await;
+    let mut res = static_files::serve_file(&file.0, file.1, &repr, req.headers()).await?;
+    res.extensions_mut().insert(NoCompression);
+    Ok(res)
+}
+
+fn status(status: StatusCode) -> Result<SvcResponse> {
+    Ok(Response::builder().status(status).body(empty())?)
+}
+
+#[derive(Debug)]
+enum Source {
+    /// A Markdown file to render.
+    Markdown(PathBuf),
+    /// A directory with an index, requested without a trailing slash.
+    Directory,
+    /// Anything else, which is served as a static file (if it exists).
+    Other,
+}
+
+async fn find_source(root: &Path, request_path: &str) -> Result<Source> {
+    if !request_path.ends_with('/') && !request_path.ends_with(".md") {
+        if let Some(path) = static_files::resolve(root, &format!("{request_path}.md")).await? {
+            if tokio::fs::metadata(&path).await?.is_file() {
+                return Ok(Source::Markdown(path));
+            }
+        }
+    }
+    let Some(path) = static_files::resolve(root, request_path).await? else {
+        return Ok(Source::Other);
+    };
+    let meta = tokio::fs::metadata(&path).await?;
+    if meta.is_dir() {
+        let index = path.join(INDEX);
+        return Ok(match tokio::fs::metadata(&index).await {
+            Ok(meta) if meta.is_file() && !request_path.ends_with('/') => Source::Directory,
+            Ok(meta) if meta.is_file() => Source::Markdown(index),
+            _ => Source::Other,
+        });
+    }
+    Ok(match path.extension() == Some(OsStr::new("md")) {
+        true => Source::Markdown(path),
+        false => Source::Other,
+    })
+}
+
+/// The path at which the page rendered from `source` is served: the route's prefix, followed by
+/// `source`'s path within `root` (without `.md`, or `index.md`). Unlike the request path, this is
+/// the same however the page is requested (ex. `/post`, `//post`, `/./post`, or `/post.md`).
+fn page_path(uri_path: &str, request_path: &str, root: &Path, source: &Path) -> String {
+    // routes without a prefix handle the whole path
+    let prefix = uri_path.strip_suffix(request_path).unwrap_or_default();
+    let relative = source.strip_prefix(root).unwrap_or(source);
+    let relative = relative.to_string_lossy();
+    let relative = match relative.strip_suffix(INDEX) {
+        Some(dir) if dir.is_empty() || dir.ends_with('/') => dir,
+        _ => relative.strip_suffix(".md").unwrap_or(&relative),
+    };
+    let mut res = prefix.trim_end_matches('/').to_owned();
+    for segment in relative.split('/') {
+        res.push('/');
+        res.extend(utf8_percent_encode(segment, SEGMENT));
+    }
+    res
+}
+
+/// Where the page rendered from `source` for `path` is cached. Pages are cached separately for
+/// each source, path (which pages may include), template, & theme, in a directory holding only
+/// the version rendered from the current source & template (by modification time & length).
+fn cache_path(
+    cache_dir: &Path,
+    cfg: &Markdown,
+    path: &str,
+    (source, source_meta): (&Path, &std::fs::Metadata),
+    template_meta: Option<&std::fs::Metadata>,
+) -> PathBuf {
+    fn version(hasher: &mut blake3::Hasher, meta: &std::fs::Metadata) {
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        hasher.update(&modified.as_nanos().to_le_bytes());
+        hasher.update(&meta.len().to_le_bytes());
+    }
+    let mut key = blake3::Hasher::new();
+    key.update(source.as_os_str().as_bytes());
+    key.update(b"\0");
+    key.update(path.as_bytes());
+    key.update(b"\0");
+    if let Some(template) = &cfg.template {
+        key.update(template.as_os_str().as_bytes());
+    }
+    key.update(b"\0");
+    key.update(cfg.theme.as_bytes());
+
+    let mut versions = blake3::Hasher::new();
+    version(&mut versions, source_meta);
+    if let Some(meta) = template_meta {
+        version(&mut versions, meta);
+    }
+    cache_dir
+        .join("markdown")
+        .join(key.finalize().to_hex().as_str())
+        .join(format!("{}.html", &versions.finalize().to_hex()[..32]))
+}
+
+/// Cache a rendered page at `cached`, removing pages rendered from older versions.
+async fn write_cache(cached: &Path, page: &str) -> std::io::Result<()> {
+    // concurrent requests may render the same page, so each writes its own temporary file
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let dir = cached.parent().unwrap();
+    tokio::fs::create_dir_all(dir).await?;
+    let tmp = cached.with_extension(format!("{}.tmp", NEXT.fetch_add(1, Ordering::Relaxed)));
+    tokio::fs::write(&tmp, page).await?;
+    tokio::fs::rename(&tmp, cached).await?;
+
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let stale = entry.path();
+        // others' temporary files are left to them
+        if stale != cached && stale.extension() == Some(OsStr::new("html")) {
+            tokio::fs::remove_file(&stale).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Render the Markdown file at `source` into the route's template.
+#[tracing::instrument(skip(cfg))]
+async fn render(cfg: &Markdown, source: &Path, path: &str) -> Result<String> {
+    let text = tokio::fs::read_to_string(source).await?;
+    let template = match &cfg.template {
+        Some(template) => tokio::fs::read_to_string(template).await?,
+        None => DEFAULT_TEMPLATE.to_owned(),
+    };
+    let (source, path, theme) = (source.to_owned(), path.to_owned(), cfg.theme.clone());
+    let page = tokio::task::spawn_blocking(move || {
+        let (meta, content) = front_matter(&text);
+        let meta = meta.unwrap_or_else(|e| {
+            tracing::warn!(?source, error = %e, "ignoring invalid front matter");
+            Meta::new()
+        });
+        let (content, heading) = to_html(content, &theme);
+        let title = match meta.get("title") {
+            Some(serde_json::Value::String(title)) => title.clone(),
+            _ => heading.unwrap_or_else(|| {
+                source
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned()
+            }),
+        };
+        fill(&template, |name| match name {
+            "content" => Some(Cow::Borrowed(content.as_str())),
+            "title" => Some(escape(&title).into()),
+            "path" => Some(escape(&path).into()),
+            name => name.strip_prefix("meta.").map(|key| match meta.get(key) {
+                None | Some(serde_json::Value::Null) => Cow::Borrowed(""),
+                Some(serde_json::Value::String(value)) => escape(value).into(),
+                Some(value) => escape(&value.to_string()).into(),
+            }),
+        })
+    })
+    .await
+    .map_err(std::io::Error::from)?;
+    tracing::debug!("rendered markdown");
+    Ok(page)
+}
+
+/// Render Markdown to HTML, highlighting fenced code blocks with `theme`, and returning the text
+/// of the first heading, if any.
+fn to_html(content: &str, theme: &str) -> (String, Option<String>) {
+    let theme = match theme {
+        "" => None,
+        name => {
+            let theme = THEMES.themes.get(name);
+            if theme.is_none() {
+                tracing::warn!(theme = name, "unknown highlighting theme");
+            }
+            theme
+        }
+    };
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+
+    let mut events = Vec::new();
+    let mut code = None;
+    let mut heading = None;
+    let mut in_heading = false;
+    for event in Parser::new_ext(content, options) {
+        match (&event, &mut code) {
+            (Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))), _) => {
+                // the info string may have more than a language, ex. `rust,ignore`
+                let token = info.split([' ', ',', '{']).next().unwrap_or_default();
+                let syntax = (!token.is_empty())
+                    .then(|| SYNTAXES.find_syntax_by_token(token))
+                    .flatten();
+                if let (Some(theme), Some(syntax)) = (theme, syntax) {
+                    code = Some((theme, syntax, String::new()));
+                    continue;
+                }
+            }
+            (Event::Text(text), Some((_, _, buf))) => {
+                buf.push_str(text);
+                continue;
+            }
+            (Event::End(TagEnd::CodeBlock), Some(_)) => {
+                let (theme, syntax, buf) = code.take().unwrap();
+                let html =
+                    syntect::html::highlighted_html_for_string(&buf, &SYNTAXES, syntax, theme)
+                        .unwrap_or_else(|e| {
+                            tracing::warn!(error = %e, "failed to highlight code block");
+                            format!("<pre><code>{}</code></pre>\n", escape(&buf))
+                        });
+                events.push(Event::Html(html.into()));
+                continue;
+            }
+            (Event::Start(Tag::Heading { .. }), _) if heading.is_none() => {
+                in_heading = true;
+                heading = Some(String::new());
+            }
+            (Event::End(TagEnd::Heading(_)), _) => in_heading = false,
+            (Event::Text(text) | Event::Code(text), _) if in_heading => {
+                heading.get_or_insert_with(String::new).push_str(text);
+            }
+            _ => {}
+        }
+        events.push(event);
+    }
+
+    let mut html = String::with_capacity(content.len() * 3 / 2);
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    (html, heading.filter(|h| !h.trim().is_empty()))
+}
+
+/// Replace each `{{name}}` in `template` with `value(name)`; placeholders without a value are
+/// left as they are. Substituted values aren't searched for placeholders themselves.
+fn fill<'v>(template: &str, value: impl Fn(&str) -> Option<Cow<'v, str>>) -> String {
+    let mut res = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        res.push_str(&rest[..start]);
+        let placeholder = &rest[start..start + len + 2];
+        match value(placeholder[2..len].trim()) {
+            Some(value) => res.push_str(&value),
+            None => res.push_str(placeholder),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    res.push_str(rest);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_paths_are_canonical() {
+        let root = Path::new("/srv/site");
+        let post = root.join("notes/a post.md");
+        let page = |uri, request| page_path(uri, request, root, &post);
+        for (uri, request) in [
+            ("/blog/notes/a%20post", "notes/a%20post"),
+            ("/blog//notes/./a%20post", "/notes/./a%20post"),
+            ("/blog/notes/a%20post.md", "notes/a%20post.md"),
+        ] {
+            assert_eq!(page(uri, request), "/blog/notes/a%20post");
+        }
+        // routes without a prefix
+        assert_eq!(
+            page("//notes/a%20post", "//notes/a%20post"),
+            "/notes/a%20post"
+        );
+
+        let index = root.join("notes/index.md");
+        assert_eq!(page_path("/notes/", "/notes/", root, &index), "/notes/");
+        assert_eq!(page_path("/", "/", root, &root.join("index.md")), "/");
+        let not_index = root.join("notes/reindex.md");
+        assert_eq!(
+            page_path("/notes/reindex", "/notes/reindex", root, &not_index),
+            "/notes/reindex"
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! A Micropub media endpoint, which stores uploaded files.
+
+use super::{
+    indieauth::error,
+    micropub::{authorize, unauthorized},
+    Context,
+};
+use crate::config::routes::Media;
+use crate::daemon::service::{empty, Result, SvcResponse};
+use bytes::Bytes;
+use http_body_util::BodyDataStream;
+use hyper::{body::Body, header, Method, Request, Response, StatusCode};
+use std::path::{Component, Path};
+
+/// An uploaded file, read into memory.
+struct Upload {
+    data: Vec<u8>,
+    extension: String,
+}
+
+pub async fn serve<B>(cfg: &Media, ctx: &Context, req: Request<B>) -> Result<SvcResponse>
+where
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    if req.method() != Method::POST {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "POST")
+            .body(empty())?);
+    }
+    let Some(boundary) = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .and_then(|t| multer::parse_boundary(t).ok())
+    else {
+        return error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "invalid_request",
+            "files must be sent as `multipart/form-data`",
+        );
+    };
+    // the token must be checked before the upload is read, so if it's not in a header, it must be
+    // sent in a field before the file
+    let scopes = &["media", "create"];
+    let mut authorized = req.headers().contains_key(header::AUTHORIZATION);
+    if authorized {
+        if let Err(res) = authorize(ctx, &req, None, scopes).await? {
+            return Ok(res);
+        }
+    }
+
+    let (parts, body) = req.into_parts();
+    let req = Request::from_parts(parts, ());
+    // leave room for the other fields, & the multipart framing itself
+    let constraints = multer::Constraints::new().size_limit(
+        multer::SizeLimit::new()
+            .whole_stream(cfg.max_size + 64 * 1024)
+            .per_field(cfg.max_size),
+    );
+    let mut multipart =
+        multer::Multipart::with_constraints(BodyDataStream::new(body), boundary, constraints);
+    let mut upload = None;
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return multipart_error(e),
+        };
+        match field.name() {
+            Some("file") if upload.is_none() => {
+                if !authorized {
+                    return unauthorized(
+                        "an access token is required before the file, or in an `Authorization` header",
+                    );
+                }
+                let extension = field
+                    .file_name()
+                    .and_then(|name| Path::new(name).extension())
+                    .and_then(|ext| ext.to_str())
+                    .or_else(|| {
+                        field
+                            .content_type()
+                            .and_then(|t| mime_guess::get_mime_extensions(t))
+                            // `jfif` is listed before `jpg` for JPEG images
+                            .and_then(|exts| exts.iter().find(|e| **e == "jpg").or(exts.first()))
+                            .copied()
+                    })
+                    .map(str::to_ascii_lowercase)
+                    .filter(|ext| cfg.extensions.contains(ext));
+                let Some(extension) = extension else {
+                    return error(
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "invalid_request",
+                        "files of this type aren't accepted",
+                    );
+                };
+                let mut data = Vec::new();
+                loop {
+                    match field.chunk().await {
+                        Ok(Some(chunk)) => data.extend_from_slice(&chunk),
+                        Ok(None) => break,
+                        Err(e) => return multipart_error(e),
+                    }
+                }
+                upload = Some(Upload { data, extension });
+            }
+            Some("access_token") if !authorized => {
+                let token = match field.text().await {
+                    Ok(text) => text,
+                    Err(e) => return multipart_error(e),
+                };
+                if let Err(res) = authorize(ctx, &req, Some(&token), scopes).await? {
+                    return Ok(res);
+                }
+                authorized = true;
+            }
+            _ => {}
+        }
+    }
+    if !authorized {
+        return unauthorized("an access token is required");
+    }
+    let Some(upload) = upload else {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "expected a `file` field",
+        );
+    };
+
+    let Some(root) = cfg.root.as_ref().or(ctx.document_root.as_ref()) else {
+        tracing::error!("media route has no root, and there's no document root configured");
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(empty())?);
+    };
+    let dir = Path::new(cfg.dir.trim_matches('/'));
+    if !dir.components().all(|c| matches!(c, Component::Normal(_))) {
+        tracing::error!(dir = cfg.dir, "media route's `dir` must be a relative path");
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(empty())?);
+    }
+    let hash = blake3::hash(&upload.data).to_hex();
+    // identical uploads share a file
+    let name = format!("{}.{}", &hash[..32], upload.extension);
+    let path = root.join(dir).join(&name);
+    if tokio::fs::metadata(&path).await.is_err() {
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let tmp = path.with_extension("upload.tmp");
+        tokio::fs::write(&tmp, &upload.data).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+    }
+
+    let url_path = [
+        cfg.prefix.trim_matches('/'),
+        cfg.dir.trim_matches('/'),
+        &name,
+    ]
+    .into_iter()
+    .filter(|s| !s.is_empty())
+    .collect::<Vec<_>>()
+    .join("/");
+    let location = format!("{}/{url_path}", ctx.origin(&req));
+    tracing::info!(location, size = upload.data.len(), "stored upload");
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(header::LOCATION, location)
+        .body(empty())?)
+}
+
+fn multipart_error(e: multer::Error) -> Result<SvcResponse> {
+    match e {
+        multer::Error::FieldSizeExceeded { .. } | multer::Error::StreamSizeExceeded { .. } => {
+            Ok(Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(empty())?)
+        }
+        e => error(StatusCode::BAD_REQUEST, "invalid_request", &e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::indieauth::{issue_token, Grant};
+    use http_body_util::Full;
+
+    const BOUNDARY: &str = "boundary";
+
+    fn multipart(fields: &[(&str, &str)]) -> Full<Bytes> {
+        let mut body = String::new();
+        for (name, value) in fields {
+            body += &format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"");
+            if *name == "file" {
+                body += "; filename=\"photo.jpg\"\r\nContent-Type: image/jpeg";
+            }
+            body += &format!("\r\n\r\n{value}\r\n");
+        }
+        body += &format!("--{BOUNDARY}--\r\n");
+        Full::new(Bytes::from(body))
+    }
+
+    async fn upload(
+        cfg: &Media,
+        ctx: &Context,
+        header: Option<&str>,
+        fields: &[(&str, &str)],
+    ) -> SvcResponse {
+        let mut req = Request::post("/media")
+            .header(header::HOST, "example.com")
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={BOUNDARY}"),
+            );
+        if let Some(token) = header {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        serve(cfg, ctx, req.body(multipart(fields)).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tokens_come_before_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = Context::for_host("example.com", dir.path());
+        let cfg = Media {
+            root: Some(dir.path().join("site")),
+            ..Default::default()
+        };
+        let grant = |scope: &str| Grant {
+            me: "https://example.com/".to_owned(),
+            client_id: "https://app.example/".to_owned(),
+            scope: scope.to_owned(),
+            expires: None,
+        };
+        let token = issue_token(dir.path(), grant("media")).await.unwrap();
+        let profile = issue_token(dir.path(), grant("profile")).await.unwrap();
+        let stored = || std::fs::read_dir(dir.path().join("site")).is_ok();
+
+        // a token after the file is too late, as is no token at all
+        let res = upload(
+            &cfg,
+            &ctx,
+            None,
+            &[("file", "data"), ("access_token", &token)],
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = upload(&cfg, &ctx, None, &[("file", "data")]).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = upload(&cfg, &ctx, Some("nope"), &[("file", "data")]).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = upload(
+            &cfg,
+            &ctx,
+            None,
+            &[("access_token", &profile), ("file", "data")],
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(!stored());
+
+        let res = upload(
+            &cfg,
+            &ctx,
+            None,
+            &[("access_token", &token), ("file", "data")],
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = upload(&cfg, &ctx, Some(&token), &[("file", "data")]).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let location = res.headers()[header::LOCATION].to_str().unwrap();
+        let name = location.rsplit('/').next().unwrap();
+        assert!(location.starts_with("https://example.com/") && name.ends_with(".jpg"));
+        assert_eq!(
+            std::fs::read(
+                dir.path()
+                    .join("site")
+                    .join(cfg.dir.trim_matches('/'))
+                    .join(name)
+            )
+            .unwrap(),
+            b"data"
+        );
+    }
+}
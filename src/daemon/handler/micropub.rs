@@ -0,0 +1,555 @@
+//! A Micropub endpoint, which writes posts as Markdown files.
+
+use super::{
+    collect_body,
+    indieauth::{error, json, Params},
+    Context,
+};
+use crate::config::routes::{request_host, Micropub};
+use crate::daemon::{
+    indieauth::{self, Grant},
+    service::{empty, Result, SvcResponse},
+};
+use hyper::{body::Incoming, header, HeaderMap, Method, Request, Response, StatusCode};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Component, Path},
+};
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+
+/// The largest request body accepted.
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// The most numbered variants of a slug tried before giving up.
+const MAX_SLUG_ATTEMPTS: usize = 100;
+
+/// Properties which become a link at the top of a post, with the text before the link.
+const RESPONSES: &[(&str, &str)] = &[
+    ("in-reply-to", "In reply to"),
+    ("like-of", "Liked"),
+    ("repost-of", "Reposted"),
+    ("bookmark-of", "Bookmarked"),
+];
+
+/// A post's properties, by name, as in Microformats2 JSON.
+type Properties = BTreeMap<String, Vec<Value>>;
+
+pub async fn serve(cfg: &Micropub, ctx: &Context, req: Request<Incoming>) -> Result<SvcResponse> {
+    match *req.method() {
+        Method::GET | Method::HEAD => {
+            if let Err(res) = authorize(ctx, &req, None, &["create", "post"]).await? {
+                return Ok(res);
+            }
+            query(cfg, ctx, &req)
+        }
+        Method::POST => create(cfg, ctx, req).await,
+        _ => Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD, POST")
+            .body(empty())?),
+    }
+}
+
+/// Check a request's access token (from its `Authorization` header, or else `token`), which must
+/// have one of `scopes`, and have been issued for this site; the error is the response to send.
+pub async fn authorize<B>(
+    ctx: &Context,
+    req: &Request<B>,
+    token: Option<&str>,
+    scopes: &[&str],
+) -> Result<std::result::Result<Grant, SvcResponse>> {
+    let Some(token) = indieauth::bearer_token(req.headers()).or(token) else {
+        return Ok(Err(unauthorized("an access token is required")?));
+    };
+    let Some(grant) = indieauth::token(&ctx.state_dir, token).await? else {
+        return Ok(Err(unauthorized("unknown access token")?));
+    };
+    if !scopes.iter().any(|scope| grant.has_scope(scope)) {
+        return Ok(Err(error(
+            StatusCode::FORBIDDEN,
+            "insufficient_scope",
+            &format!(
+                "the access token needs the `{}` scope",
+                scopes.join("` or `")
+            ),
+        )?));
+    }
+    let me = url::Url::parse(&grant.me).ok();
+    let ours = [request_host(req), ctx.host_name.as_deref()];
+    if !ours.contains(&me.as_ref().and_then(url::Url::host_str)) {
+        return Ok(Err(error(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "the access token was issued for another site",
+        )?));
+    }
+    Ok(Ok(grant))
+}
+
+pub fn unauthorized(description: &str) -> Result<SvcResponse> {
+    let mut res = error(StatusCode::UNAUTHORIZED, "unauthorized", description)?;
+    res.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        header::HeaderValue::from_static("Bearer"),
+    );
+    Ok(res)
+}
+
+/// Answer `?q=config` & `?q=syndicate-to`.
+fn query(cfg: &Micropub, ctx: &Context, req: &Request<Incoming>) -> Result<SvcResponse> {
+    let params = Params::parse(req.uri().query().unwrap_or_default().as_bytes());
+    match params.get("q") {
+        Some("config") => {
+            let mut res = serde_json::json!({
+                "syndicate-to": [],
+                "post-types": [
+                    { "type": "note", "name": "Note" },
+                    { "type": "article", "name": "Article" },
+                    { "type": "photo", "name": "Photo" },
+                    { "type": "reply", "name": "Reply" },
+                    { "type": "like", "name": "Like" },
+                    { "type": "repost", "name": "Repost" },
+                    { "type": "bookmark", "name": "Bookmark" },
+                ],
+            });
+            if let Some(media) = &cfg.media_endpoint {
+                res["media-endpoint"] = match media.starts_with('/') {
+                    true => format!("{}{media}", ctx.origin(req)),
+                    false => media.clone(),
+                }
+                .into();
+            }
+            json(StatusCode::OK, res)
+        }
+        Some("syndicate-to") => json(StatusCode::OK, serde_json::json!({ "syndicate-to": [] })),
+        _ => error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "supported queries are `q=config` & `q=syndicate-to`",
+        ),
+    }
+}
+
+/// The type & properties of a post to create, along with its access token, if it was sent in
+/// the body.
+#[derive(Debug, Default)]
+struct Post {
+    kind: String,
+    properties: Properties,
+    token: Option<String>,
+}
+
+async fn create(cfg: &Micropub, ctx: &Context, req: Request<Incoming>) -> Result<SvcResponse> {
+    let json_body = content_type(req.headers()).starts_with("application/json");
+    if content_type(req.headers()).starts_with("multipart/form-data") {
+        return error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "invalid_request",
+            "files must be uploaded to the media endpoint",
+        );
+    }
+    let (parts, body) = req.into_parts();
+    let req = Request::from_parts(parts, ());
+    let Some(body) = collect_body(body, MAX_REQUEST_SIZE).await? else {
+        return Ok(Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(empty())?);
+    };
+    let post = match json_body {
+        true => match serde_json::from_slice::<Value>(&body) {
+            Ok(body) => from_json(body),
+            Err(e) => {
+                return error(StatusCode::BAD_REQUEST, "invalid_request", &e.to_string());
+            }
+        },
+        false => from_form(&Params::parse(&body)),
+    };
+    if let Err(res) = authorize(ctx, &req, post.token.as_deref(), &["create", "post"]).await? {
+        return Ok(res);
+    }
+    if let Some(action) = post.properties.get("action").and_then(|a| first_str(a)) {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            &format!("`{action}` isn't supported; posts may only be created"),
+        );
+    }
+    if post.kind != "entry" {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "only `h-entry` posts are supported",
+        );
+    }
+
+    let Some(root) = cfg.root.as_ref().or(ctx.document_root.as_ref()) else {
+        tracing::error!("micropub route has no root, and there's no document root configured");
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(empty())?);
+    };
+    let dir = Path::new(cfg.dir.trim_matches('/'));
+    if !dir.components().all(|c| matches!(c, Component::Normal(_))) {
+        tracing::error!(
+            dir = cfg.dir,
+            "micropub route's `dir` must be a relative path"
+        );
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(empty())?);
+    }
+    let dir = root.join(dir);
+
+    let now = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap();
+    let slug = post
+        .properties
+        .get("mp-slug")
+        .or(post.properties.get("name"))
+        .and_then(|v| first_str(v))
+        .map(slugify)
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| {
+            now.format(format_description!(
+                "[year]-[month]-[day]-[hour][minute][second]"
+            ))
+            .unwrap_or_default()
+        });
+    let document = to_markdown(&post.properties, now);
+    let slug = tokio::task::spawn_blocking(move || write_post(&dir, &slug, &document))
+        .await
+        .map_err(std::io::Error::from)??;
+
+    let path = [
+        cfg.prefix.trim_matches('/'),
+        cfg.dir.trim_matches('/'),
+        &slug,
+    ]
+    .into_iter()
+    .filter(|s| !s.is_empty())
+    .collect::<Vec<_>>()
+    .join("/");
+    let location = format!("{}/{path}", ctx.origin(&req));
+    tracing::info!(location, "created post");
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(header::LOCATION, location)
+        .body(empty())?)
+}
+
+fn content_type(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .unwrap_or_default()
+}
+
+fn first_str(values: &[Value]) -> Option<&str> {
+    values.first().and_then(Value::as_str)
+}
+
+/// A form-encoded request, in which `h` is the type (`entry`, if it's missing), `name[]` is one of
+/// several values, and `mp-*` are commands.
+fn from_form(params: &Params) -> Post {
+    let mut res = Post {
+        kind: "entry".to_owned(),
+        ..Default::default()
+    };
+    for (name, value) in params.iter() {
+        match name {
+            "h" => res.kind = value.to_owned(),
+            "access_token" => res.token = Some(value.to_owned()),
+            _ => res
+                .properties
+                .entry(name.trim_end_matches("[]").to_owned())
+                .or_default()
+                .push(Value::from(value)),
+        }
+    }
+    res
+}
+
+/// A JSON request, in Microformats2 JSON, in which commands are properties.
+fn from_json(body: Value) -> Post {
+    let kind = body["type"]
+        .as_array()
+        .and_then(|t| first_str(t))
+        .unwrap_or_default();
+    let mut properties = match &body["properties"] {
+        Value::Object(properties) => properties
+            .iter()
+            .map(|(name, values)| {
+                let values = match values {
+                    Value::Array(values) => values.clone(),
+                    value => vec![value.clone()],
+                };
+                (name.clone(), values)
+            })
+            .collect(),
+        _ => Properties::new(),
+    };
+    // updates & deletes are sent without properties
+    if let Some(action) = body["action"].as_str() {
+        properties.insert("action".to_owned(), vec![Value::from(action)]);
+    }
+    Post {
+        kind: kind.strip_prefix("h-").unwrap_or(kind).to_owned(),
+        properties,
+        token: body["access_token"].as_str().map(str::to_owned),
+    }
+}
+
+/// Lowercase ASCII letters & digits, separated by hyphens.
+fn slugify(text: &str) -> String {
+    let mut res = String::new();
+    for word in text
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        if res.len() + word.len() > 64 {
+            break;
+        }
+        if !res.is_empty() {
+            res.push('-');
+        }
+        res.push_str(&word.to_ascii_lowercase());
+    }
+    res
+}
+
+/// A post as a Markdown document, with TOML front matter.
+fn to_markdown(properties: &Properties, now: OffsetDateTime) -> String {
+    let mut meta = toml::Table::new();
+    let mut body = String::new();
+    if let Some(title) = properties.get("name").and_then(|v| first_str(v)) {
+        meta.insert("title".to_owned(), title.into());
+    }
+    let date = properties
+        .get("published")
+        .and_then(|v| first_str(v))
+        .and_then(|d| OffsetDateTime::parse(d, &Rfc3339).ok())
+        .unwrap_or(now);
+    meta.insert(
+        "date".to_owned(),
+        match date.format(&Rfc3339).ok().and_then(|d| d.parse().ok()) {
+            Some(date) => toml::Value::Datetime(date),
+            None => toml::Value::String(date.to_string()),
+        },
+    );
+    if properties
+        .get("post-status")
+        .and_then(|v| first_str(v))
+        .is_some_and(|s| s == "draft")
+    {
+        meta.insert("draft".to_owned(), true.into());
+    }
+    if let Some(tags) = properties.get("category") {
+        let tags = tags.iter().filter_map(Value::as_str).map(toml::Value::from);
+        meta.insert("tags".to_owned(), toml::Value::Array(tags.collect()));
+    }
+
+    for (property, text) in RESPONSES {
+        for url in properties.get(*property).into_iter().flatten() {
+            let Some(url) = url.as_str().or(url["properties"]["url"][0].as_str()) else {
+                continue;
+            };
+            let url = super::escape(url);
+            body.push_str(&format!(
+                "<p>{text} <a class=\"u-{property}\" href=\"{url}\">{url}</a></p>\n\n"
+            ));
+        }
+    }
+    for content in properties.get("content").into_iter().flatten() {
+        match content {
+            Value::String(text) => body.push_str(text),
+            content => {
+                let html = content["html"].as_str();
+                body.push_str(html.or(content["value"].as_str()).unwrap_or_default());
+            }
+        }
+        body.push_str("\n\n");
+    }
+    let mut photos = Vec::new();
+    for photo in properties.get("photo").into_iter().flatten() {
+        let (Some(url), alt) = (
+            photo.as_str().or(photo["value"].as_str()),
+            photo["alt"].as_str().unwrap_or_default(),
+        ) else {
+            continue;
+        };
+        // normalizing the URL percent-encodes spaces, `<`, & `>`, & backslashes are encoded too,
+        // so that it can't end the `<...>` destination early (as parentheses would a bare one)
+        let Some(url) = url::Url::parse(url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+        else {
+            continue;
+        };
+        let url = url.as_str().replace('\\', "%5C");
+        let alt = alt.replace(['[', ']'], "").replace(['\r', '\n'], " ");
+        body.push_str(&format!("![{alt}](<{url}>)\n\n"));
+        photos.push(toml::Value::from(url));
+    }
+    if !photos.is_empty() {
+        meta.insert("photos".to_owned(), toml::Value::Array(photos));
+    }
+
+    // anything else is kept as it was sent, for templates to make use of
+    for (name, values) in properties {
+        let known = [
+            "name",
+            "published",
+            "post-status",
+            "category",
+            "content",
+            "photo",
+        ];
+        if name.starts_with("mp-") || name == "action" || known.contains(&name.as_str()) {
+            continue;
+        }
+        let values = values
+            .iter()
+            .filter_map(|v| toml::Value::try_from(v).ok())
+            .collect::<Vec<_>>();
+        let key = name.replace('-', "_");
+        match <[_; 1]>::try_from(values) {
+            Ok([value]) => meta.insert(key, value),
+            Err(values) if !values.is_empty() => meta.insert(key, toml::Value::Array(values)),
+            Err(_) => continue,
+        };
+    }
+
+    format!(
+        "+++\n{}+++\n\n{}",
+        toml::to_string(&meta).unwrap_or_default(),
+        body.trim_end()
+    ) + "\n"
+}
+
+/// Write `document` to `<slug>.md` in `dir` (or, if that's taken, `<slug>-2.md`, ...), returning
+/// the slug used.
+fn write_post(dir: &Path, slug: &str, document: &str) -> std::io::Result<String> {
+    std::fs::create_dir_all(dir)?;
+    for attempt in 1..=MAX_SLUG_ATTEMPTS {
+        let slug = match attempt {
+            1 => slug.to_owned(),
+            n => format!("{slug}-{n}"),
+        };
+        let path = dir.join(format!("{slug}.md"));
+        match std::fs::File::create_new(&path) {
+            Ok(mut file) => {
+                file.write_all(document.as_bytes())?;
+                return Ok(slug);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!("too many posts named {slug:?}"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn token(ctx: &Context, me: &str, scope: &str) -> String {
+        let grant = Grant {
+            me: me.to_owned(),
+            client_id: "https://app.example/".to_owned(),
+            scope: scope.to_owned(),
+            expires: None,
+        };
+        indieauth::issue_token(&ctx.state_dir, grant).await.unwrap()
+    }
+
+    fn request(host: &str, token: Option<&str>) -> Request<()> {
+        let mut req = Request::builder().header(header::HOST, host);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        req.body(()).unwrap()
+    }
+
+    /// The status & OAuth error code of a refusal.
+    async fn refusal(res: std::result::Result<Grant, SvcResponse>) -> (StatusCode, String) {
+        let res = res.unwrap_err();
+        let status = res.status();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        (status, body["error"].as_str().unwrap().to_owned())
+    }
+
+    #[tokio::test]
+    async fn tokens_are_checked() {
+        let state = tempfile::tempdir().unwrap();
+        let ctx = Context::for_host("example.com", state.path());
+        let create = token(&ctx, "https://example.com/", "create").await;
+        let media = token(&ctx, "https://example.com/", "media").await;
+        let other = token(&ctx, "https://other.example/", "create media").await;
+
+        let req = request("example.com", Some(&create));
+        let grant = authorize(&ctx, &req, None, &["create"]).await.unwrap();
+        assert_eq!(grant.unwrap().scope, "create");
+        // a token in the header is preferred
+        let req = request("example.com", Some(&media));
+        let res = authorize(&ctx, &req, Some(&create), &["create"]).await;
+        assert_eq!(
+            refusal(res.unwrap()).await,
+            (StatusCode::FORBIDDEN, "insufficient_scope".into())
+        );
+        let req = request("example.com", None);
+        let res = authorize(&ctx, &req, Some(&create), &["update", "create"]).await;
+        assert!(res.unwrap().is_ok());
+
+        let res = authorize(&ctx, &req, None, &["create"]).await;
+        let (status, _) = refusal(res.unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let req = request("example.com", Some("nope"));
+        let res = authorize(&ctx, &req, None, &["create"]).await;
+        assert_eq!(
+            refusal(res.unwrap()).await,
+            (StatusCode::UNAUTHORIZED, "unauthorized".into())
+        );
+
+        // tokens must have been issued for this site, by its canonical name or as requested
+        let req = request("example.com", Some(&other));
+        let res = authorize(&ctx, &req, None, &["create"]).await;
+        assert_eq!(
+            refusal(res.unwrap()).await,
+            (StatusCode::FORBIDDEN, "forbidden".into())
+        );
+        let req = request("other.example:8443", Some(&other));
+        assert!(authorize(&ctx, &req, None, &["create"])
+            .await
+            .unwrap()
+            .is_ok());
+    }
+
+    #[test]
+    fn photos_are_escaped() {
+        let properties = serde_json::from_value::<Properties>(serde_json::json!({
+            "photo": [
+                "https://example.com/a b.jpg",
+                { "value": "https://example.com/x.jpg?a=>)![](https://evil.example/\\", "alt": "[a]\nb" },
+                "javascript:alert(1)",
+            ],
+        }))
+        .unwrap();
+        let now = OffsetDateTime::UNIX_EPOCH;
+        let markdown = to_markdown(&properties, now);
+        let body = markdown.rsplit("+++\n\n").next().unwrap();
+        assert_eq!(
+            body,
+            "![](<https://example.com/a%20b.jpg>)\n\n\
+            ![a b](<https://example.com/x.jpg?a=%3E)![](https://evil.example/%5C>)\n"
+        );
+        assert!(markdown.contains(
+            "photos = [\"https://example.com/a%20b.jpg\", \"https://example.com/x.jpg?a=%3E)![](https://evil.example/%5C\"]"
+        ));
+    }
+}
@@ -0,0 +1,312 @@
+//! Forwards requests to upstream servers.
+
+use crate::config::{
+    routes::{Captures, Proxy},
+    upstreams::Upstream,
+};
+use crate::daemon::{
+    service::{empty, Connection, Result, ServiceError, SvcResponse},
+    upstreams::Upstreams,
+};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{
+    body::Incoming,
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap, Request, Response, StatusCode, Uri, Version,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioIo},
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+    time::Duration,
+};
+use tokio::net::UnixStream;
+
+lazy_static::lazy_static! {
+    static ref HTTP: Client<HttpConnector, Incoming> =
+        Client::builder(TokioExecutor::new()).build_http();
+    /// One pool per socket.
+    static ref UNIX: parking_lot::Mutex<HashMap<PathBuf, Client<UnixConnector, Incoming>>> =
+        Default::default();
+}
+
+/// Connects to a fixed unix socket, regardless of the requested URI.
+#[derive(Debug, Clone)]
+struct UnixConnector {
+    path: Arc<Path>,
+}
+
+impl tower::Service<Uri> for UnixConnector {
+    type Response = TokioIo<UnixStream>;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        let path = self.path.clone();
+        Box::pin(async move { Ok(TokioIo::new(UnixStream::connect(path).await?)) })
+    }
+}
+
+fn unix_client(path: &Path) -> Client<UnixConnector, Incoming> {
+    UNIX.lock()
+        .entry(path.to_owned())
+        .or_insert_with(|| {
+            Client::builder(TokioExecutor::new()).build(UnixConnector {
+                path: Arc::from(path),
+            })
+        })
+        .clone()
+}
+
+/// Headers which only apply to a single connection, and so mustn't be forwarded.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // `Connection` may name further headers which apply only to this connection
+    let named = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    for name in named.iter().map(String::as_str).chain(HOP_BY_HOP) {
+        headers.remove(name);
+    }
+}
+
+/// A node identifier for `Forwarded`.
+fn forwarded_node(ip: Option<IpAddr>) -> String {
+    match ip {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => format!("\"[{ip}]\""),
+        None => "unknown".to_owned(),
+    }
+}
+
+/// Add `Forwarded` & `X-Forwarded-*` headers describing the client's request.
+pub fn add_forwarded(headers: &mut HeaderMap, conn: &Connection, host: Option<&str>) {
+    let ip = conn.peer.map(|peer| peer.ip());
+    let proto = if conn.tls { "https" } else { "http" };
+    let mut forwarded = format!("for={};proto={proto}", forwarded_node(ip));
+    if let Some(host) = host {
+        forwarded.push_str(&format!(";host=\"{host}\""));
+    }
+    append(headers, header::FORWARDED, forwarded);
+    if let Some(ip) = ip {
+        append(
+            headers,
+            HeaderName::from_static("x-forwarded-for"),
+            ip.to_string(),
+        );
+    }
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
+    match host.and_then(|host| HeaderValue::from_str(host).ok()) {
+        Some(host) => headers.insert("x-forwarded-host", host),
+        None => headers.remove("x-forwarded-host"),
+    };
+}
+
+/// Append `value` to the comma-separated list in `name`.
+fn append(headers: &mut HeaderMap, name: HeaderName, value: String) {
+    let value = match headers.get(&name).and_then(|v| v.to_str().ok()) {
+        Some(existing) => format!("{existing}, {value}"),
+        None => value,
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(name, value);
+    }
+}
+
+pub async fn serve(
+    cfg: &Proxy,
+    upstreams: &Upstreams,
+    captures: &Captures,
+    mut req: Request<Incoming>,
+) -> Result<SvcResponse> {
+    let conn = req
+        .extensions()
+        .get::<Connection>()
+        .cloned()
+        .unwrap_or_default();
+    // upgrades only exist in HTTP/1.1
+    let upgrade = req
+        .headers()
+        .get(header::UPGRADE)
+        .filter(|_| req.version() == Version::HTTP_11)
+        .cloned();
+    let client_upgrade = upgrade.is_some().then(|| hyper::upgrade::on(&mut req));
+
+    let (mut parts, body) = req.into_parts();
+    let host = parts
+        .uri
+        .authority()
+        .map(|a| a.as_str().to_owned())
+        .or_else(|| Some(parts.headers.get(header::HOST)?.to_str().ok()?.to_owned()));
+    let path = match captures.subpath.as_deref() {
+        Some(subpath) if cfg.strip_prefix => subpath,
+        _ => parts.uri.path(),
+    };
+    let path = path.trim_start_matches('/');
+    let query = parts
+        .uri
+        .query()
+        .map(|q| format!("?{q}"))
+        .unwrap_or_default();
+    let Some((upstream, lease)) = upstreams.resolve(&cfg.upstream, &parts.headers) else {
+        return status(StatusCode::BAD_GATEWAY);
+    };
+    let (uri, upstream_host) = upstream_uri(&upstream, path, &query);
+    let Ok(uri) = uri.parse::<Uri>() else {
+        return status(StatusCode::BAD_REQUEST);
+    };
+
+    strip_hop_by_hop(&mut parts.headers);
+    if let Some(upgrade) = upgrade {
+        parts
+            .headers
+            .insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        parts.headers.insert(header::UPGRADE, upgrade);
+    }
+    add_forwarded(&mut parts.headers, &conn, host.as_deref());
+    let forwarded_host = match host {
+        Some(host) if cfg.preserve_host => HeaderValue::from_str(&host).ok(),
+        _ => None,
+    };
+    parts.headers.insert(
+        header::HOST,
+        forwarded_host.unwrap_or_else(|| HeaderValue::from_str(&upstream_host).unwrap()),
+    );
+    parts.uri = uri;
+    parts.version = Version::HTTP_11;
+
+    let req = Request::from_parts(parts, body);
+    let res = match &upstream {
+        Upstream::Http { .. } | Upstream::Tcp(_) => HTTP.request(req).await,
+        Upstream::Unix(path) => unix_client(path).request(req).await,
+    };
+    let mut res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            tracing::warn!(%upstream, error = ?e, "failed to reach upstream");
+            if let Some(lease) = lease {
+                lease.failed();
+            }
+            return status(StatusCode::BAD_GATEWAY);
+        }
+    };
+    if let Some(lease) = &lease {
+        lease.succeeded();
+    }
+
+    match client_upgrade {
+        Some(client_upgrade) if res.status() == StatusCode::SWITCHING_PROTOCOLS => {
+            let upstream_upgrade = hyper::upgrade::on(&mut res);
+            tokio::task::spawn(async move {
+                // the upgraded connection counts as in flight until it closes
+                let _lease = lease;
+                let (client, upstream_io) = match tokio::try_join!(client_upgrade, upstream_upgrade)
+                {
+                    Ok(io) => io,
+                    Err(e) => {
+                        tracing::warn!(%upstream, error = ?e, "failed to upgrade proxied connection");
+                        return;
+                    }
+                };
+                if let Err(e) = tokio::io::copy_bidirectional(
+                    &mut TokioIo::new(client),
+                    &mut TokioIo::new(upstream_io),
+                )
+                .await
+                {
+                    tracing::debug!(%upstream, error = ?e, "upgraded connection closed");
+                }
+            });
+        }
+        _ => {
+            strip_hop_by_hop(res.headers_mut());
+            // the request counts as in flight until its response body has been sent
+            return Ok(res.map(|body| {
+                body.map_err(move |e| {
+                    let _ = &lease;
+                    ServiceError::from(e)
+                })
+                .boxed()
+            }));
+        }
+    }
+    Ok(res.map(|body| body.map_err(ServiceError::from).boxed()))
+}
+
+/// The URI to request `path` & `query` from `upstream`, and the authority it expects.
+pub fn upstream_uri(upstream: &Upstream, path: &str, query: &str) -> (String, String) {
+    match upstream {
+        Upstream::Http { authority, base } => (
+            format!("http://{authority}{base}/{path}{query}"),
+            authority.to_string(),
+        ),
+        Upstream::Tcp(authority) => (
+            format!("http://{authority}/{path}{query}"),
+            authority.to_string(),
+        ),
+        Upstream::Unix(_) => (
+            format!("http://localhost/{path}{query}"),
+            "localhost".to_owned(),
+        ),
+    }
+}
+
+/// Whether `upstream` responds to a `GET` for `path` with a 2xx or 3xx status within `timeout`.
+pub async fn probe(upstream: &Upstream, path: &str, timeout: Duration) -> bool {
+    let (uri, host) = upstream_uri(upstream, path.trim_start_matches('/'), "");
+    let Ok(req) = Request::get(uri)
+        .header(header::HOST, host)
+        .header(header::USER_AGENT, "melia health check")
+        .body(Empty::<Bytes>::new())
+    else {
+        return false;
+    };
+    let builder = Client::builder(TokioExecutor::new());
+    let res = tokio::time::timeout(timeout, async {
+        match upstream {
+            Upstream::Http { .. } | Upstream::Tcp(_) => builder.build_http().request(req).await,
+            Upstream::Unix(path) => {
+                builder
+                    .build(UnixConnector {
+                        path: Arc::from(path.as_path()),
+                    })
+                    .request(req)
+                    .await
+            }
+        }
+    })
+    .await;
+    matches!(res, Ok(Ok(res)) if res.status().is_success() || res.status().is_redirection())
+}
+
+fn status(status: StatusCode) -> Result<SvcResponse> {
+    Ok(Response::builder().status(status).body(empty())?)
+}
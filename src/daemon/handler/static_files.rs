@@ -0,0 +1,484 @@
+//! Serves files from a directory.
+
+use super::{autoindex, Context};
+use crate::config::routes::{Captures, StaticFiles};
+use crate::daemon::compression::{self, NoCompression};
+use crate::daemon::encoding::{self, Encoding};
+use crate::daemon::service::{empty, Result, ServiceError, SvcResponse};
+use futures::TryStreamExt;
+use http_body_util::{BodyExt, StreamBody};
+use httpdate::HttpDate;
+use hyper::{body::Frame, header, HeaderMap, Method, Request, Response, StatusCode};
+use std::{
+    ffi::OsStr,
+    io::SeekFrom,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+pub async fn serve<B>(
+    cfg: &StaticFiles,
+    ctx: &Context,
+    captures: &Captures,
+    req: &Request<B>,
+) -> Result<SvcResponse> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD")
+            .body(empty())?);
+    }
+    let Some(root) = cfg.root.as_ref().or(ctx.document_root.as_ref()) else {
+        tracing::error!("static file route has no root, and there's no document root configured");
+        return status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    let root = tokio::fs::canonicalize(root).await?;
+    let request_path = captures.subpath.as_deref().unwrap_or(req.uri().path());
+    let Some(path) = resolve(&root, request_path).await? else {
+        return status(StatusCode::NOT_FOUND);
+    };
+
+    let meta = tokio::fs::metadata(&path).await?;
+    let (path, meta) = if meta.is_dir() {
+        // relative links in index files only work if the directory path ends with a slash
+        if !req.uri().path().ends_with('/') {
+            let location = match req.uri().query() {
+                Some(query) => format!("{}/?{query}", req.uri().path()),
+                None => format!("{}/", req.uri().path()),
+            };
+            return Ok(Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, location)
+                .body(empty())?);
+        }
+        match find_index(&path, &cfg.index).await {
+            Some(index) => index,
+            None if cfg.autoindex.enabled => {
+                return autoindex::serve(&cfg.autoindex, &root, &path, req).await
+            }
+            None => return status(StatusCode::NOT_FOUND),
+        }
+    } else {
+        (path, meta)
+    };
+
+    let accepted = encoding::accepted(req.headers());
+    let mut repr = Representation::new(&path, &meta);
+    let mut file = (path, meta.len());
+    if cfg.precompressed {
+        repr.vary = true;
+        for &encoding in &accepted {
+            if let Some(sibling) = precompressed(&root, &file.0, encoding).await {
+                repr = repr.encoded(encoding);
+                file = sibling;
+                break;
+            }
+        }
+    }
+    if repr.encoding.is_none() {
+        use_compression_cache(ctx, &accepted, &meta, &mut repr, &mut file).await;
+    }
+
+    let mut res = serve_file(&file.0, file.1, &repr, req.headers()).await?;
+    // compressing on the fly would reuse the identity entity tag, so static files are only ever
+    // compressed through the cache
+    res.extensions_mut().insert(NoCompression);
+    Ok(res)
+}
+
+/// Switch `file` (a path & length) to its copy in the compression cache, in the client's preferred
+/// of the `accepted` encodings, if the route allows compression and the copy is ready.
+pub async fn use_compression_cache(
+    ctx: &Context,
+    accepted: &[Encoding],
+    meta: &std::fs::Metadata,
+    repr: &mut Representation,
+    file: &mut (PathBuf, u64),
+) {
+    let compression = &ctx.compression;
+    if ctx.compress
+        && compression.enabled
+        && compression.cache
+        && compression::is_compressible(compression, Some(&repr.content_type), Some(file.1))
+    {
+        repr.vary = true;
+        if let Some(&encoding) = accepted.first() {
+            if let Some(cached) = compression::cached(&ctx.cache_dir, &file.0, meta, encoding).await
+            {
+                *repr = repr.clone().encoded(encoding);
+                *file = cached;
+            }
+        }
+    }
+}
+
+fn status(status: StatusCode) -> Result<SvcResponse> {
+    Ok(Response::builder().status(status).body(empty())?)
+}
+
+/// Map a request path to a file within `root` (which must be canonical), refusing `..` components
+/// and paths which escape `root` through symlinks. Returns `None` if there is no such file.
+pub async fn resolve(root: &Path, request_path: &str) -> Result<Option<PathBuf>> {
+    let mut relative = PathBuf::new();
+    for segment in request_path.split('/') {
+        let segment = percent_encoding::percent_decode_str(segment).collect::<Vec<u8>>();
+        match segment.as_slice() {
+            b"" | b"." => {}
+            b".." => return Ok(None),
+            s if s.contains(&b'/') || s.contains(&b'\0') => return Ok(None),
+            s => relative.push(OsStr::from_bytes(s)),
+        }
+    }
+
+    match tokio::fs::canonicalize(root.join(relative)).await {
+        Ok(path) if path.starts_with(root) => Ok(Some(path)),
+        Ok(path) => {
+            tracing::warn!(?path, ?root, "refusing to serve file outside of root");
+            Ok(None)
+        }
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn is_not_found(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory
+    )
+}
+
+/// Find the first of `index` which is a file within `dir`.
+async fn find_index(dir: &Path, index: &[String]) -> Option<(PathBuf, std::fs::Metadata)> {
+    for name in index {
+        let path = dir.join(name);
+        if let Ok(meta) = tokio::fs::metadata(&path).await {
+            if meta.is_file() {
+                return Some((path, meta));
+            }
+        }
+    }
+    None
+}
+
+/// The `Content-Type` for a file, guessed from its extension.
+pub fn content_type(path: &Path) -> String {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    if mime.type_() == mime_guess::mime::TEXT {
+        format!("{mime}; charset=utf-8")
+    } else {
+        mime.to_string()
+    }
+}
+
+/// A strong validator derived from a file's size & modification time.
+pub fn etag(meta: &std::fs::Metadata) -> String {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", meta.len(), mtime.as_nanos())
+}
+
+/// Whether `etag` matches any of the entity tags in an `If-None-Match`/`If-Match` header value,
+/// using weak comparison.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Whether the client's cached copy is still fresh, per `If-None-Match` or (in its absence)
+/// `If-Modified-Since`.
+pub fn not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(inm) = headers.get(header::IF_NONE_MATCH) {
+        return inm.to_str().is_ok_and(|inm| etag_matches(inm, etag));
+    }
+    match (
+        headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|ims| ims.to_str().ok()?.parse::<HttpDate>().ok()),
+        modified,
+    ) {
+        (Some(since), Some(modified)) => HttpDate::from(modified) <= since,
+        _ => false,
+    }
+}
+
+/// Whether an `If-Range` header value refers to the current version of a file.
+fn if_range_matches(if_range: &str, etag: &str, modified: Option<SystemTime>) -> bool {
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+    match (if_range.parse::<HttpDate>(), modified) {
+        (Ok(date), Some(modified)) => date == HttpDate::from(modified),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteRange {
+    /// Serve the whole file (including when the `Range` header is invalid or unsupported).
+    Full,
+    /// Serve `start..=end`.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header; only single ranges are supported.
+fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), _) if start >= len => ByteRange::Unsatisfiable,
+        (Ok(start), Ok(end)) if start <= end => ByteRange::Partial(start, end.min(len - 1)),
+        (Ok(start), Err(_)) if end.is_empty() => ByteRange::Partial(start, len - 1),
+        (Err(_), Ok(0)) if start.is_empty() => ByteRange::Unsatisfiable,
+        (Err(_), Ok(_)) if start.is_empty() && len == 0 => ByteRange::Unsatisfiable,
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            ByteRange::Partial(len.saturating_sub(suffix), len - 1)
+        }
+        _ => ByteRange::Full,
+    }
+}
+
+/// How a file is presented to clients.
+#[derive(Debug, Clone)]
+pub struct Representation {
+    pub etag: String,
+    pub modified: Option<SystemTime>,
+    pub content_type: String,
+    pub encoding: Option<Encoding>,
+    /// Whether the response depends on `Accept-Encoding`.
+    pub vary: bool,
+}
+
+impl Representation {
+    pub fn new(path: &Path, meta: &std::fs::Metadata) -> Self {
+        Self {
+            etag: etag(meta),
+            modified: meta.modified().ok(),
+            content_type: content_type(path),
+            encoding: None,
+            vary: false,
+        }
+    }
+
+    /// The same content, compressed with `encoding`. The entity tag is derived from the original's,
+    /// so that each encoding has a consistent tag.
+    pub fn encoded(self, encoding: Encoding) -> Self {
+        Self {
+            etag: format!("{}-{}\"", self.etag.trim_end_matches('"'), encoding.name()),
+            encoding: Some(encoding),
+            vary: true,
+            ..self
+        }
+    }
+}
+
+/// Find a precompressed sibling of `path` (ex. `style.css.br` for `style.css`) within `root`,
+/// returning its path & length.
+async fn precompressed(root: &Path, path: &Path, encoding: Encoding) -> Option<(PathBuf, u64)> {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(encoding.extension());
+    let sibling = tokio::fs::canonicalize(sibling).await.ok()?;
+    if !sibling.starts_with(root) {
+        return None;
+    }
+    let meta = tokio::fs::metadata(&sibling).await.ok()?;
+    meta.is_file().then_some((sibling, meta.len()))
+}
+
+/// Respond with the contents of the file at `path`, which is `len` bytes long, handling
+/// conditional & range requests.
+pub async fn serve_file(
+    path: &Path,
+    len: u64,
+    repr: &Representation,
+    headers: &HeaderMap,
+) -> Result<SvcResponse> {
+    let etag = &repr.etag;
+    let modified = repr.modified;
+
+    let mut res = Response::builder()
+        .header(header::ETAG, etag)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(modified) = modified {
+        res = res.header(header::LAST_MODIFIED, HttpDate::from(modified).to_string());
+    }
+    if repr.vary {
+        res = res.header(header::VARY, "accept-encoding");
+    }
+
+    if not_modified(headers, etag, modified) {
+        return Ok(res.status(StatusCode::NOT_MODIFIED).body(empty())?);
+    }
+
+    // a range only applies if the client's copy (per `If-Range`) is current
+    let range = match headers.get(header::RANGE).and_then(|r| r.to_str().ok()) {
+        Some(range)
+            if headers.get(header::IF_RANGE).is_none_or(|r| {
+                r.to_str()
+                    .is_ok_and(|r| if_range_matches(r, etag, modified))
+            }) =>
+        {
+            parse_range(range, len)
+        }
+        _ => ByteRange::Full,
+    };
+
+    let (start, count) = match range {
+        ByteRange::Full => {
+            res = res.status(StatusCode::OK);
+            (0, len)
+        }
+        ByteRange::Partial(start, end) => {
+            res = res
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"));
+            (start, end - start + 1)
+        }
+        ByteRange::Unsatisfiable => {
+            return Ok(res
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+                .body(empty())?);
+        }
+    };
+
+    let mut file = tokio::fs::File::open(path).await?;
+    if start > 0 {
+        file.seek(SeekFrom::Start(start)).await?;
+    }
+    let body = StreamBody::new(
+        tokio_util::io::ReaderStream::new(file.take(count))
+            .map_ok(Frame::data)
+            .map_err(ServiceError::from),
+    );
+    if let Some(encoding) = repr.encoding {
+        res = res.header(header::CONTENT_ENCODING, encoding.name());
+    }
+    Ok(res
+        .header(header::CONTENT_TYPE, &repr.content_type)
+        .header(header::CONTENT_LENGTH, count)
+        .body(BodyExt::boxed(body))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A canonical root containing `index.html`, `a/b.txt`, and a secret file beside it.
+    fn site() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::write(root.join("index.html"), "index").unwrap();
+        std::fs::write(root.join("a/b.txt"), "b").unwrap();
+        std::fs::write(dir.path().join("secret"), "secret").unwrap();
+        let root = root.canonicalize().unwrap();
+        (dir, root)
+    }
+
+    #[tokio::test]
+    async fn resolve_finds_files_within_root() {
+        let (_dir, root) = site();
+        assert_eq!(
+            resolve(&root, "/a/b.txt").await.unwrap(),
+            Some(root.join("a/b.txt"))
+        );
+        assert_eq!(
+            resolve(&root, "/./a//%62.txt").await.unwrap(),
+            Some(root.join("a/b.txt"))
+        );
+        assert_eq!(resolve(&root, "/").await.unwrap(), Some(root.clone()));
+        assert_eq!(resolve(&root, "/missing").await.unwrap(), None);
+        assert_eq!(resolve(&root, "/index.html/x").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn resolve_rejects_traversal() {
+        let (_dir, root) = site();
+        assert_eq!(resolve(&root, "/../secret").await.unwrap(), None);
+        assert_eq!(resolve(&root, "/a/../../secret").await.unwrap(), None);
+        assert_eq!(
+            resolve(&root, "/a/%2e%2e/%2e%2e/secret").await.unwrap(),
+            None
+        );
+        assert_eq!(resolve(&root, "/..%2fsecret").await.unwrap(), None);
+        assert_eq!(resolve(&root, "/a%2Fb.txt").await.unwrap(), None);
+        assert_eq!(resolve(&root, "/index.html%00").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn resolve_rejects_symlinks_out_of_root() {
+        let (dir, root) = site();
+        std::os::unix::fs::symlink(dir.path().join("secret"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), root.join("up")).unwrap();
+        std::os::unix::fs::symlink(root.join("a/b.txt"), root.join("inside")).unwrap();
+        assert_eq!(resolve(&root, "/link").await.unwrap(), None);
+        assert_eq!(resolve(&root, "/up/secret").await.unwrap(), None);
+        assert_eq!(
+            resolve(&root, "/inside").await.unwrap(),
+            Some(root.join("a/b.txt"))
+        );
+    }
+
+    #[test]
+    fn parse_range_single() {
+        assert_eq!(parse_range("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(parse_range("bytes=90-", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range("bytes=90-200", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range(" bytes= 5-5 ", 100), ByteRange::Partial(5, 5));
+        assert_eq!(parse_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-0", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn parse_range_suffix() {
+        assert_eq!(parse_range("bytes=-10", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range("bytes=-200", 100), ByteRange::Partial(0, 99));
+        assert_eq!(parse_range("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn parse_range_ignores_invalid() {
+        for header in [
+            "",
+            "0-9",
+            "items=0-9",
+            "bytes=",
+            "bytes=-",
+            "bytes=9-0",
+            "bytes=a-9",
+            "bytes=0-b",
+            "bytes=0",
+            "bytes=--5",
+        ] {
+            assert_eq!(parse_range(header, 100), ByteRange::Full, "{header:?}");
+        }
+    }
+
+    #[test]
+    fn parse_range_ignores_multiple_ranges() {
+        // overlapping or not, multiple ranges would need a multipart response
+        assert_eq!(parse_range("bytes=0-9,5-14", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=0-9,20-29", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=-5,0-", 100), ByteRange::Full);
+    }
+}
@@ -0,0 +1,193 @@
+//! Renders pages from templates.
+
+use super::{static_files, Context};
+use crate::config::routes::{request_host, Captures, StaticFiles, Templates};
+use crate::daemon::service::{empty, full, Result, SvcResponse};
+use hyper::{header, Method, Request, Response, StatusCode};
+use minijinja::Value;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path},
+};
+
+/// The template rendered for a directory.
+const INDEX: &str = "index.html";
+
+/// The extensions of files which are rendered as templates; other files are served as they are.
+const EXTENSIONS: &[&str] = &["html", "htm", "xml"];
+
+/// What templates know about the request.
+#[derive(Debug, Serialize)]
+struct RequestInfo<'r> {
+    method: &'r str,
+    path: &'r str,
+    query: &'r str,
+    params: BTreeMap<String, String>,
+    host: &'r str,
+    headers: BTreeMap<&'r str, String>,
+}
+
+pub async fn serve<B>(
+    cfg: &Templates,
+    ctx: &Context,
+    captures: &Captures,
+    req: &Request<B>,
+) -> Result<SvcResponse> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD")
+            .body(empty())?);
+    }
+    let Some(root) = cfg.root.as_ref().or(ctx.document_root.as_ref()) else {
+        tracing::error!("templates route has no root, and there's no document root configured");
+        return status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    let root = tokio::fs::canonicalize(root).await?;
+    let request_path = captures.subpath.as_deref().unwrap_or(req.uri().path());
+    let name = match find_template(&root, request_path).await? {
+        Found::Template(name) => name,
+        Found::Directory => {
+            // relative links in index pages only work if the directory path ends with a slash
+            let location = match req.uri().query() {
+                Some(query) => format!("{}/?{query}", req.uri().path()),
+                None => format!("{}/", req.uri().path()),
+            };
+            return Ok(Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, location)
+                .body(empty())?);
+        }
+        Found::Other => {
+            let files = StaticFiles {
+                root: Some(root),
+                index: Vec::new(),
+                ..Default::default()
+            };
+            return static_files::serve(&files, ctx, captures, req).await;
+        }
+        Found::Nothing => return status(StatusCode::NOT_FOUND),
+    };
+
+    let query = req.uri().query().unwrap_or_default();
+    let mut headers = BTreeMap::<&str, String>::new();
+    for (name, value) in req.headers() {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        headers
+            .entry(name.as_str())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(&value);
+            })
+            .or_insert_with(|| value.into_owned());
+    }
+    let request = Value::from_serialize(RequestInfo {
+        method: req.method().as_str(),
+        path: req.uri().path(),
+        query,
+        params: url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect(),
+        host: request_host(req).unwrap_or(&ctx.server_name),
+        headers,
+    });
+
+    let templates = ctx.templates.clone();
+    let (data, template) = (cfg.data.clone(), name.clone());
+    let rendered = tokio::task::spawn_blocking(move || {
+        templates.render(&root, data.as_deref(), &template, request)
+    })
+    .await
+    .map_err(std::io::Error::from)?;
+    match rendered {
+        Ok(page) => Ok(Response::builder()
+            .header(
+                header::CONTENT_TYPE,
+                static_files::content_type(Path::new(&name)),
+            )
+            .body(full(page))?),
+        Err(e) => {
+            tracing::error!(
+                template = name,
+                error = format!("{e:#}"),
+                "failed to render template"
+            );
+            if ctx.templates.development() {
+                return Ok(Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                    .body(full(format!("{e:#}")))?);
+            }
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn status(status: StatusCode) -> Result<SvcResponse> {
+    Ok(Response::builder().status(status).body(empty())?)
+}
+
+#[derive(Debug)]
+enum Found {
+    /// The name of the template to render.
+    Template(String),
+    /// A directory with an index, requested without a trailing slash.
+    Directory,
+    /// A file which isn't a template, which is served as it is.
+    Other,
+    Nothing,
+}
+
+async fn find_template(root: &Path, request_path: &str) -> Result<Found> {
+    let last = request_path.rsplit('/').next().unwrap_or_default();
+    if !last.is_empty() && !last.contains('.') {
+        if let Some(path) = static_files::resolve(root, &format!("{request_path}.html")).await? {
+            if tokio::fs::metadata(&path).await?.is_file() {
+                return Ok(template_name(root, &path).map_or(Found::Nothing, Found::Template));
+            }
+        }
+    }
+    let Some(path) = static_files::resolve(root, request_path).await? else {
+        return Ok(Found::Nothing);
+    };
+    let path = match tokio::fs::metadata(&path).await?.is_dir() {
+        false => path,
+        true => {
+            let index = path.join(INDEX);
+            match tokio::fs::metadata(&index).await {
+                Ok(meta) if meta.is_file() && !request_path.ends_with('/') => {
+                    return Ok(Found::Directory)
+                }
+                Ok(meta) if meta.is_file() => index,
+                _ => return Ok(Found::Nothing),
+            }
+        }
+    };
+    let is_template = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| EXTENSIONS.iter().any(|t| e.eq_ignore_ascii_case(t)));
+    if !is_template {
+        return Ok(Found::Other);
+    }
+    Ok(template_name(root, &path).map_or(Found::Nothing, Found::Template))
+}
+
+/// The name by which the template at `path` is loaded, unless it's only for use by other
+/// templates (or isn't valid UTF-8).
+fn template_name(root: &Path, path: &Path) -> Option<String> {
+    let mut segments = Vec::new();
+    for component in path.strip_prefix(root).ok()?.components() {
+        let Component::Normal(segment) = component else {
+            return None;
+        };
+        let segment = segment.to_str()?;
+        if segment.starts_with('_') {
+            return None;
+        }
+        segments.push(segment);
+    }
+    Some(segments.join("/"))
+}
@@ -0,0 +1,155 @@
+//! Receives Webmentions, and lists them for each page.
+
+use super::{collect_body, static_files, Context};
+use crate::config::{
+    hosts,
+    routes::{Handler, Webmention},
+};
+use crate::daemon::{
+    service::{empty, full, Result, SvcResponse},
+    webmentions::{self, Queued},
+};
+use hyper::{body::Incoming, header, Method, Request, Response, StatusCode};
+use std::path::Path;
+use url::Url;
+
+/// The largest request body accepted.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+pub async fn serve(cfg: &Webmention, ctx: &Context, req: Request<Incoming>) -> Result<SvcResponse> {
+    match *req.method() {
+        Method::GET | Method::HEAD => list(ctx, &req).await,
+        Method::POST => receive(cfg, ctx, req).await,
+        _ => Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD, POST")
+            .body(empty())?),
+    }
+}
+
+/// Respond with the mentions of the page named by the `target` query parameter, as JSON.
+async fn list(ctx: &Context, req: &Request<Incoming>) -> Result<SvcResponse> {
+    let target = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find_map(|(name, value)| (name == "target").then_some(value));
+    let Some(Ok(target)) = target.map(|t| Url::parse(&t)) else {
+        return bad_request("`target` must be a URL");
+    };
+    let state_dir = ctx.state_dir.clone();
+    let mentions =
+        tokio::task::spawn_blocking(move || webmentions::mentions_of(&state_dir, target.as_str()))
+            .await
+            .map_err(std::io::Error::from)??;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .body(full(
+            serde_json::to_string(&mentions).map_err(std::io::Error::from)?,
+        ))?)
+}
+
+/// Accept a mention to be verified in the background.
+async fn receive(cfg: &Webmention, ctx: &Context, req: Request<Incoming>) -> Result<SvcResponse> {
+    let Some(body) = collect_body(req.into_body(), MAX_REQUEST_SIZE).await? else {
+        return Ok(Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(empty())?);
+    };
+    let (mut source, mut target) = (None, None);
+    for (name, value) in url::form_urlencoded::parse(&body) {
+        match name.as_ref() {
+            "source" => source = Url::parse(&value).ok(),
+            "target" => target = Url::parse(&value).ok(),
+            _ => {}
+        }
+    }
+    let (Some(source), Some(target)) = (source, target) else {
+        return bad_request("`source` and `target` must be URLs");
+    };
+    if ![&source, &target]
+        .iter()
+        .all(|url| matches!(url.scheme(), "http" | "https") && url.host_str().is_some())
+    {
+        return bad_request("`source` and `target` must be `http` or `https` URLs");
+    }
+    if source == target {
+        return bad_request("`source` and `target` must differ");
+    }
+    if !is_ours(ctx, &target).await? {
+        return bad_request("`target` isn't on this site");
+    }
+
+    match webmentions::enqueue(cfg, &ctx.state_dir, source, target) {
+        Queued::Added | Queued::Duplicate => Ok(Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(full("webmention accepted; it will be verified shortly"))?),
+        Queued::Full => Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::RETRY_AFTER, "60")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(full("too many webmentions are waiting to be verified"))?),
+    }
+}
+
+/// Whether `target` names a page on one of our hosts: its host must be configured (or be
+/// `server.domain`), a route must match its path, and if that route serves files, a file must
+/// exist for it.
+async fn is_ours(ctx: &Context, target: &Url) -> Result<bool> {
+    let Some(host) = target.host_str() else {
+        return Ok(false);
+    };
+    let (handler, subpath, default_root) = {
+        let cfg = ctx.config.read().unwrap();
+        let domain = &cfg.server.domain;
+        if hosts::find(&cfg.hosts, host).is_none()
+            && (domain.is_empty() || !host.eq_ignore_ascii_case(domain))
+        {
+            return Ok(false);
+        }
+        let vhost = cfg.virtual_host(Some(host));
+        let req = Request::get(target.path())
+            .header(header::HOST, host)
+            .body(())?;
+        let Some((route, captures)) = vhost.routes.find(&req) else {
+            return Ok(false);
+        };
+        (
+            route.handler.clone(),
+            captures.subpath,
+            vhost.root.map(Path::to_owned),
+        )
+    };
+    let root = match handler {
+        Handler::Static(h) => h.root,
+        Handler::Markdown(h) => h.root,
+        Handler::Templates(h) => h.root,
+        // there's no telling what other handlers serve
+        _ => return Ok(true),
+    };
+    let Some(root) = root.or(default_root) else {
+        return Ok(false);
+    };
+    let root = match tokio::fs::canonicalize(root).await {
+        Ok(root) => root,
+        Err(_) => return Ok(false),
+    };
+    let path = subpath.as_deref().unwrap_or(target.path());
+    // pages may be rendered from files with another extension
+    for candidate in [
+        path.to_owned(),
+        format!("{path}.md"),
+        format!("{path}.html"),
+    ] {
+        if static_files::resolve(&root, &candidate).await?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn bad_request(message: &'static str) -> Result<SvcResponse> {
+    Ok(Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(full(message))?)
+}
@@ -0,0 +1,426 @@
+//! WebSocket connections, served by native [Endpoint]s or relayed to upstream servers.
+
+use super::{cgi, proxy, Context};
+use crate::config::{
+    routes::{self, request_host, WebSocket},
+    upstreams::ProxyTarget,
+};
+use crate::daemon::{
+    service::{empty, Connection, Result, SvcResponse},
+    upstreams::{Lease, Socket},
+};
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream::SplitStream, FutureExt, SinkExt, StreamExt};
+use hyper::{
+    body::Incoming,
+    header::{self, HeaderValue},
+    upgrade::{OnUpgrade, Upgraded},
+    Method, Request, Response, StatusCode, Version,
+};
+use hyper_util::rt::TokioIo;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc,
+    time::{Instant, Interval},
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        handshake::{client::generate_key, derive_accept_key},
+        protocol::{frame::coding::CloseCode, CloseFrame, Role, WebSocketConfig},
+    },
+    WebSocketStream,
+};
+
+pub mod chat;
+pub mod counter;
+
+pub use tungstenite::Message;
+
+/// A native WebSocket endpoint, which serves each client in its own [Session].
+pub trait Endpoint: Send + Sync {
+    /// Serve a client. The connection is closed once the returned future finishes, which it should
+    /// do once `session.incoming` ends or `session.outgoing` closes.
+    fn serve(&self, session: Session) -> BoxFuture<'static, ()>;
+}
+
+/// A client connected to an [Endpoint]; keepalive and size limits are handled outside of it.
+#[derive(Debug)]
+pub struct Session {
+    /// The request path, by which endpoints may group clients (ex. into chat rooms).
+    pub path: String,
+    pub peer: Option<SocketAddr>,
+    /// Text and binary messages from the client, which end when it disconnects. Endpoints which
+    /// don't expect any should drop this, rather than leave messages unread.
+    pub incoming: mpsc::Receiver<Message>,
+    /// Messages to send to the client.
+    pub outgoing: mpsc::Sender<Message>,
+}
+
+lazy_static::lazy_static! {
+    static ref COUNTER: counter::Counter = Default::default();
+    static ref CHAT: chat::Chat = Default::default();
+}
+
+fn endpoint(name: routes::Endpoint) -> &'static dyn Endpoint {
+    match name {
+        routes::Endpoint::Counter => &*COUNTER,
+        routes::Endpoint::Chat => &*CHAT,
+    }
+}
+
+/// Messages buffered in each direction between a client and its endpoint.
+const CHANNEL_CAPACITY: usize = 16;
+/// How long an upstream has to accept a relayed connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a client to acknowledge a close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn serve(
+    cfg: &WebSocket,
+    ctx: &Context,
+    mut req: Request<Incoming>,
+) -> Result<SvcResponse> {
+    let Some(key) = handshake_key(&req) else {
+        return Ok(Response::builder()
+            .status(StatusCode::UPGRADE_REQUIRED)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .body(empty())?);
+    };
+    if !origin_allowed(&cfg.origins, &req) {
+        tracing::debug!(origin = ?req.headers().get(header::ORIGIN), "rejected websocket origin");
+        return status(StatusCode::FORBIDDEN);
+    }
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(cfg.max_message_size))
+        .max_frame_size(Some(cfg.max_message_size));
+    let ping = (cfg.ping_interval_ms > 0).then(|| Duration::from_millis(cfg.ping_interval_ms));
+    let path = req.uri().path().to_owned();
+    let peer = req.extensions().get::<Connection>().and_then(|c| c.peer);
+    let mut res = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(
+            header::SEC_WEBSOCKET_ACCEPT,
+            derive_accept_key(key.as_bytes()),
+        );
+    let upgrade = hyper::upgrade::on(&mut req);
+
+    match (cfg.endpoint, &cfg.upstream) {
+        (Some(name), None) => {
+            tokio::task::spawn(async move {
+                if let Some(client) = accept(upgrade, config).await {
+                    let session = |session| endpoint(name).serve(session);
+                    run(client, path, peer, ping, session).await;
+                }
+            });
+        }
+        (None, Some(target)) => {
+            let Some((upstream, protocol, lease)) = connect(ctx, target, &req, config).await else {
+                return status(StatusCode::BAD_GATEWAY);
+            };
+            if let Some(protocol) = protocol {
+                res = res.header(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+            }
+            tokio::task::spawn(async move {
+                // the connection counts as in flight until it closes
+                let _lease = lease;
+                if let Some(client) = accept(upgrade, config).await {
+                    let session = |session| relay(upstream, session).boxed();
+                    run(client, path, peer, ping, session).await;
+                }
+            });
+        }
+        _ => {
+            tracing::error!("websocket route must have exactly one of `endpoint` or `upstream`");
+            return status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    Ok(res.body(empty())?)
+}
+
+/// The `Sec-WebSocket-Key` of a valid opening handshake (RFC 6455 §4.2.1).
+fn handshake_key<B>(req: &Request<B>) -> Option<&str> {
+    fn has_token<B>(req: &Request<B>, name: header::HeaderName, token: &str) -> bool {
+        req.headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+    let headers = req.headers();
+    (req.method() == Method::GET
+        && req.version() == Version::HTTP_11
+        && has_token(req, header::CONNECTION, "upgrade")
+        && has_token(req, header::UPGRADE, "websocket")
+        && headers.get(header::SEC_WEBSOCKET_VERSION)? == "13")
+        .then(|| headers.get(header::SEC_WEBSOCKET_KEY)?.to_str().ok())
+        .flatten()
+}
+
+fn origin_allowed<B>(origins: &[String], req: &Request<B>) -> bool {
+    let Some(origin) = req.headers().get(header::ORIGIN) else {
+        return true;
+    };
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+    if origins.is_empty() {
+        let Some((_, authority)) = origin.split_once("://") else {
+            return false;
+        };
+        let host = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => &authority[..i],
+            _ => authority,
+        };
+        return request_host(req).is_some_and(|h| h.eq_ignore_ascii_case(host));
+    }
+    origins
+        .iter()
+        .any(|o| o == "*" || o.eq_ignore_ascii_case(origin))
+}
+
+type ClientSocket = WebSocketStream<TokioIo<Upgraded>>;
+
+async fn accept(upgrade: OnUpgrade, config: WebSocketConfig) -> Option<ClientSocket> {
+    match upgrade.await {
+        Ok(io) => Some(
+            WebSocketStream::from_raw_socket(TokioIo::new(io), Role::Server, Some(config)).await,
+        ),
+        Err(e) => {
+            tracing::debug!(error = ?e, "failed to upgrade websocket connection");
+            None
+        }
+    }
+}
+
+/// Serve `client` with `endpoint` until either ends the session, pinging the client every `ping`.
+///
+/// Messages from the client are read in a task of their own, so that an endpoint which is slow to
+/// read them never stops messages being written to the client, nor the reverse.
+async fn run(
+    client: ClientSocket,
+    path: String,
+    peer: Option<SocketAddr>,
+    ping: Option<Duration>,
+    endpoint: impl FnOnce(Session) -> BoxFuture<'static, ()>,
+) {
+    let (incoming_tx, incoming) = mpsc::channel(CHANNEL_CAPACITY);
+    let (outgoing, mut outgoing_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    tokio::task::spawn(endpoint(Session {
+        path,
+        peer,
+        incoming,
+        outgoing,
+    }));
+    let (mut sink, stream) = client.split();
+    // whether the client has sent anything since the last ping
+    let heard = Arc::new(AtomicBool::new(true));
+    let mut reader = tokio::task::spawn(read(stream, incoming_tx, heard.clone(), peer));
+    let mut pings = ping.map(|ping| tokio::time::interval_at(Instant::now() + ping, ping));
+    let close = loop {
+        tokio::select! {
+            res = &mut reader => match res {
+                Ok(close) => break close,
+                Err(_) => break None,
+            },
+            msg = outgoing_rx.recv() => match msg {
+                Some(msg) => if sink.send(msg).await.is_err() {
+                    break None;
+                },
+                None => break Some(CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: "".into(),
+                }),
+            },
+            _ = tick(&mut pings) => {
+                if !heard.swap(false, Ordering::Relaxed) {
+                    tracing::debug!(?peer, "websocket client stopped responding");
+                    break Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "ping timeout".into(),
+                    });
+                }
+                if sink.send(Message::Ping(Bytes::new())).await.is_err() {
+                    break None;
+                }
+            }
+        }
+    };
+    drop(outgoing_rx);
+    // wait for the client to acknowledge our close frame, which ends the reader
+    if let Some(frame) = close {
+        if sink.send(Message::Close(Some(frame))).await.is_ok() && !reader.is_finished() {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, &mut reader).await;
+        }
+    }
+    // ending the channels ends the endpoint
+    reader.abort();
+}
+
+/// Forward text & binary messages from the client to its endpoint until the client disconnects,
+/// returning the frame with which to close the connection, if any.
+async fn read(
+    mut stream: SplitStream<ClientSocket>,
+    incoming: mpsc::Sender<Message>,
+    heard: Arc<AtomicBool>,
+    peer: Option<SocketAddr>,
+) -> Option<CloseFrame> {
+    loop {
+        match stream.next().await {
+            Some(Ok(msg)) => {
+                heard.store(true, Ordering::Relaxed);
+                if let Message::Text(_) | Message::Binary(_) = msg {
+                    // the endpoint may not care
+                    let _ = incoming.send(msg).await;
+                }
+            }
+            Some(Err(tungstenite::Error::Capacity(e))) => {
+                break Some(CloseFrame {
+                    code: CloseCode::Size,
+                    reason: e.to_string().into(),
+                })
+            }
+            Some(Err(e)) => {
+                tracing::debug!(?peer, error = ?e, "websocket connection failed");
+                break None;
+            }
+            // the client closed the connection; the reply to its close frame has been sent
+            None => break None,
+        }
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Open a connection to the upstream `target` names on behalf of `req`, returning it, the
+/// subprotocol the upstream chose, and its lease.
+async fn connect(
+    ctx: &Context,
+    target: &ProxyTarget,
+    req: &Request<Incoming>,
+    config: WebSocketConfig,
+) -> Option<(WebSocketStream<Socket>, Option<HeaderValue>, Option<Lease>)> {
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    let (socket, upstream, lease) =
+        cgi::connect(&ctx.upstreams, target, req.headers(), deadline).await?;
+    let path = req.uri().path().trim_start_matches('/');
+    let query = req
+        .uri()
+        .query()
+        .map(|q| format!("?{q}"))
+        .unwrap_or_default();
+    let (uri, upstream_host) = proxy::upstream_uri(&upstream, path, &query);
+    let uri = format!("ws{}", uri.strip_prefix("http").unwrap_or(&uri));
+
+    let mut headers = req.headers().clone();
+    proxy::strip_hop_by_hop(&mut headers);
+    for name in [
+        header::HOST,
+        header::SEC_WEBSOCKET_KEY,
+        header::SEC_WEBSOCKET_VERSION,
+        header::SEC_WEBSOCKET_ACCEPT,
+        // compression isn't supported
+        header::SEC_WEBSOCKET_EXTENSIONS,
+    ] {
+        headers.remove(name);
+    }
+    let conn = req
+        .extensions()
+        .get::<Connection>()
+        .cloned()
+        .unwrap_or_default();
+    let host = req
+        .uri()
+        .authority()
+        .map(|a| a.as_str())
+        .or_else(|| req.headers().get(header::HOST)?.to_str().ok());
+    proxy::add_forwarded(&mut headers, &conn, host);
+    let mut request = Request::get(uri)
+        .header(header::HOST, host.unwrap_or(&upstream_host))
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_VERSION, "13")
+        .header(header::SEC_WEBSOCKET_KEY, generate_key())
+        .body(())
+        .ok()?;
+    request.headers_mut().extend(headers);
+
+    let handshake = tokio_tungstenite::client_async_with_config(request, socket, Some(config));
+    match tokio::time::timeout_at(deadline, handshake).await {
+        Ok(Ok((stream, res))) => {
+            let protocol = res.headers().get(header::SEC_WEBSOCKET_PROTOCOL).cloned();
+            Some((stream, protocol, lease))
+        }
+        Ok(Err(e)) => {
+            tracing::warn!(%upstream, error = ?e, "upstream refused websocket connection");
+            None
+        }
+        Err(_) => {
+            tracing::warn!(%upstream, "upstream websocket handshake timed out");
+            None
+        }
+    }
+}
+
+/// Relay messages between a session and an upstream until either closes. Each direction is
+/// forwarded independently, so that neither side waits on the other.
+async fn relay(upstream: WebSocketStream<Socket>, session: Session) {
+    let (mut tx, mut rx) = upstream.split();
+    let Session {
+        mut incoming,
+        outgoing,
+        ..
+    } = session;
+    let to_upstream = async {
+        while let Some(msg) = incoming.recv().await {
+            if tx.send(msg).await.is_err() {
+                return;
+            }
+        }
+        let _ = tx.close().await;
+    };
+    let to_client = async {
+        loop {
+            match rx.next().await {
+                Some(Ok(msg @ (Message::Text(_) | Message::Binary(_)))) => {
+                    if outgoing.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    tracing::debug!(error = ?e, "upstream websocket connection failed");
+                    break;
+                }
+                None => break,
+            }
+        }
+    };
+    tokio::select! {
+        _ = to_upstream => {}
+        _ = to_client => {}
+    }
+}
+
+fn status(status: StatusCode) -> Result<SvcResponse> {
+    Ok(Response::builder().status(status).body(empty())?)
+}
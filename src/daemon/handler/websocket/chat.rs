@@ -0,0 +1,70 @@
+//! Relays text messages among the clients connected to each path.
+
+use super::{Endpoint, Message, Session};
+use futures::{future::BoxFuture, FutureExt};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc::error::TrySendError,
+};
+
+/// Messages buffered for each client; clients which fall further behind miss messages.
+const ROOM_CAPACITY: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct Chat {
+    rooms: Arc<parking_lot::Mutex<HashMap<String, broadcast::Sender<Message>>>>,
+}
+
+impl Endpoint for Chat {
+    fn serve(&self, session: Session) -> BoxFuture<'static, ()> {
+        let rooms = self.rooms.clone();
+        async move {
+            let Session {
+                path,
+                peer,
+                mut incoming,
+                outgoing,
+            } = session;
+            let (room, mut messages) = {
+                let mut rooms = rooms.lock();
+                let room = rooms
+                    .entry(path.clone())
+                    .or_insert_with(|| broadcast::channel(ROOM_CAPACITY).0);
+                (room.clone(), room.subscribe())
+            };
+            loop {
+                tokio::select! {
+                    msg = incoming.recv() => match msg {
+                        Some(msg @ Message::Text(_)) => {
+                            let _ = room.send(msg);
+                        }
+                        Some(_) => {}
+                        None => break,
+                    },
+                    msg = messages.recv() => match msg {
+                        // waiting for a slow client would stop us reading its messages, so it
+                        // misses messages instead, as if it had lagged
+                        Ok(msg) => match outgoing.try_send(msg) {
+                            Ok(()) => {}
+                            Err(TrySendError::Full(_)) => {
+                                tracing::debug!(?peer, path, "chat client fell behind");
+                            }
+                            Err(TrySendError::Closed(_)) => break,
+                        },
+                        Err(RecvError::Lagged(missed)) => {
+                            tracing::debug!(?peer, path, missed, "chat client fell behind");
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                }
+            }
+            drop(messages);
+            let mut rooms = rooms.lock();
+            if room.receiver_count() == 0 {
+                rooms.remove(&path);
+            }
+        }
+        .boxed()
+    }
+}
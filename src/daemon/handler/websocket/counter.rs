@@ -0,0 +1,63 @@
+//! Counts the clients connected to each path.
+
+use super::{Endpoint, Message, Session};
+use futures::{future::BoxFuture, FutureExt};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::watch;
+
+type Counts = Arc<parking_lot::Mutex<HashMap<String, watch::Sender<usize>>>>;
+
+#[derive(Debug, Default)]
+pub struct Counter {
+    counts: Counts,
+}
+
+/// A client's presence, which is counted until this is dropped.
+struct Visit {
+    counts: Counts,
+    path: String,
+}
+
+impl Drop for Visit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock();
+        if let Some(count) = counts.get(&self.path) {
+            count.send_modify(|n| *n -= 1);
+            if *count.borrow() == 0 {
+                counts.remove(&self.path);
+            }
+        }
+    }
+}
+
+impl Endpoint for Counter {
+    fn serve(&self, session: Session) -> BoxFuture<'static, ()> {
+        let counts = self.counts.clone();
+        async move {
+            let Session { path, outgoing, .. } = session;
+            let mut count = {
+                let mut counts = counts.lock();
+                let count = counts
+                    .entry(path.clone())
+                    .or_insert_with(|| watch::channel(0).0);
+                count.send_modify(|n| *n += 1);
+                count.subscribe()
+            };
+            let _visit = Visit { counts, path };
+            loop {
+                let visitors = *count.borrow_and_update();
+                let msg = Message::text(format!("{{\"visitors\":{visitors}}}"));
+                if outgoing.send(msg).await.is_err() {
+                    break;
+                }
+                tokio::select! {
+                    res = count.changed() => if res.is_err() {
+                        break;
+                    },
+                    _ = outgoing.closed() => break,
+                }
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,90 @@
+//! Plain HTTP listeners which redirect every request to HTTPS, except for ACME challenges and
+//! health checks. Only requests for names in `[[hosts]]` or `server.domain` are redirected; others
+//! are refused with `421 Misdirected Request`.
+
+use super::service::{empty, full, Result, Service, SvcResponse};
+use crate::config::{hosts, routes::request_host};
+use hyper::{body::Incoming, header, Request, Response, StatusCode};
+use std::path::PathBuf;
+
+const ACME_CHALLENGE: &str = "/.well-known/acme-challenge/";
+
+/// What to do with a request, decided while holding the configuration lock.
+enum Action {
+    Health,
+    Challenge(PathBuf),
+    Redirect(String),
+}
+
+pub async fn respond(svc: &Service, req: Request<Incoming>) -> Result<SvcResponse> {
+    let action = {
+        let cfg = svc.cfg.read().unwrap();
+        let redirect = &cfg.https_redirect;
+        let path = req.uri().path();
+        match (&redirect.acme_challenges, path.strip_prefix(ACME_CHALLENGE)) {
+            _ if redirect.health_path.as_deref() == Some(path) => Action::Health,
+            (Some(dir), Some(token)) => {
+                // tokens are base64url, so this also keeps requests within `dir`
+                if token.is_empty()
+                    || !token
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+                {
+                    return status(StatusCode::NOT_FOUND);
+                }
+                Action::Challenge(dir.join(token))
+            }
+            _ => {
+                let domain = cfg.server.domain.as_str();
+                let name = match request_host(&req) {
+                    // only redirect to names we serve, so that we can't be used to send clients
+                    // elsewhere
+                    Some(host) if hosts::find(&cfg.hosts, host).is_some() => {
+                        cfg.virtual_host(Some(host)).redirect_to.unwrap_or(host)
+                    }
+                    Some(host) if !domain.is_empty() && is_domain(domain, host) => domain,
+                    Some(_) => return status(StatusCode::MISDIRECTED_REQUEST),
+                    None => domain,
+                };
+                if name.is_empty() {
+                    return status(StatusCode::BAD_REQUEST);
+                }
+                let port = redirect
+                    .port
+                    .or_else(|| cfg.listen.https.first().map(|addr| addr.port()))
+                    .filter(|&port| port != 443)
+                    .map(|port| format!(":{port}"))
+                    .unwrap_or_default();
+                let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+                Action::Redirect(format!("https://{name}{port}{path}"))
+            }
+        }
+    };
+    match action {
+        Action::Health => Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(full("ok"))?),
+        Action::Challenge(path) => match tokio::fs::read(&path).await {
+            Ok(key_authorization) => Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "text/plain")
+                .body(full(key_authorization))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => status(StatusCode::NOT_FOUND),
+            Err(e) => Err(e.into()),
+        },
+        Action::Redirect(location) => Ok(Response::builder()
+            .status(StatusCode::PERMANENT_REDIRECT)
+            .header(header::LOCATION, location)
+            .body(empty())?),
+    }
+}
+
+/// Whether `host` names `domain`, ignoring case & any trailing dot.
+fn is_domain(domain: &str, host: &str) -> bool {
+    host.strip_suffix('.')
+        .unwrap_or(host)
+        .eq_ignore_ascii_case(domain)
+}
+
+fn status(status: StatusCode) -> Result<SvcResponse> {
+    Ok(Response::builder().status(status).body(empty())?)
+}
@@ -0,0 +1,237 @@
+//! Authorization codes & access tokens issued by IndieAuth routes, and checked by Micropub &
+//! media routes.
+
+use super::channels::constant_time_eq;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hyper::{header, HeaderMap};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use time::OffsetDateTime;
+
+lazy_static::lazy_static! {
+    /// Held while changing stored codes & tokens, so that concurrent updates aren't lost.
+    static ref STORE: tokio::sync::Mutex<()> = Default::default();
+}
+
+/// What a client was allowed to do, and on whose behalf.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Grant {
+    pub me: String,
+    pub client_id: String,
+    /// Space-separated scopes, ex. `create media`.
+    pub scope: String,
+    /// When this expires, as a Unix timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+}
+
+impl Grant {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split_ascii_whitespace().any(|s| s == scope)
+    }
+
+    fn expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= OffsetDateTime::now_utc().unix_timestamp())
+    }
+}
+
+/// An authorization code, which may be exchanged once for the grant it represents.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Code {
+    #[serde(flatten)]
+    pub grant: Grant,
+    pub redirect_uri: String,
+    /// The client's PKCE challenge, which must be answered to redeem the code.
+    pub code_challenge: String,
+}
+
+/// Stored codes or tokens, by a hash of their secret, so that reading the store doesn't reveal
+/// them.
+type Stored<T> = BTreeMap<String, T>;
+
+fn store_path(state_dir: &Path, name: &str) -> PathBuf {
+    state_dir.join("indieauth").join(format!("{name}.json"))
+}
+
+async fn read<T: DeserializeOwned>(path: &Path) -> std::io::Result<Stored<T>> {
+    match tokio::fs::read(path).await {
+        Ok(json) => Ok(serde_json::from_slice(&json)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+async fn write<T: Serialize>(path: &Path, stored: &Stored<T>) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    // write to a temporary file first, so that readers never see a partially-written file
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(stored)?).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// A new random secret, encoded as base64url.
+fn secret() -> std::io::Result<String> {
+    let mut bytes = [0; 32];
+    getrandom::fill(&mut bytes)?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+fn key(secret: &str) -> String {
+    blake3::hash(secret.as_bytes()).to_hex().to_string()
+}
+
+/// Store `code`, returning the secret by which it's redeemed.
+pub async fn issue_code(state_dir: &Path, code: Code) -> std::io::Result<String> {
+    let secret = secret()?;
+    let _guard = STORE.lock().await;
+    let path = store_path(state_dir, "codes");
+    let mut codes = read::<Code>(&path).await?;
+    codes.retain(|_, code| !code.grant.expired());
+    codes.insert(key(&secret), code);
+    write(&path, &codes).await?;
+    Ok(secret)
+}
+
+/// Remove & return the unexpired code whose secret is `code`, if there is one.
+pub async fn redeem_code(state_dir: &Path, code: &str) -> std::io::Result<Option<Code>> {
+    let _guard = STORE.lock().await;
+    let path = store_path(state_dir, "codes");
+    let mut codes = read::<Code>(&path).await?;
+    let Some(code) = codes.remove(&key(code)) else {
+        return Ok(None);
+    };
+    codes.retain(|_, code| !code.grant.expired());
+    write(&path, &codes).await?;
+    Ok((!code.grant.expired()).then_some(code))
+}
+
+/// Store an access token for `grant`, returning it.
+pub async fn issue_token(state_dir: &Path, grant: Grant) -> std::io::Result<String> {
+    let secret = secret()?;
+    let _guard = STORE.lock().await;
+    let path = store_path(state_dir, "tokens");
+    let mut tokens = read::<Grant>(&path).await?;
+    tokens.retain(|_, grant| !grant.expired());
+    tokens.insert(key(&secret), grant);
+    write(&path, &tokens).await?;
+    Ok(secret)
+}
+
+/// The grant for an unexpired access token.
+pub async fn token(state_dir: &Path, token: &str) -> std::io::Result<Option<Grant>> {
+    let tokens = read::<Grant>(&store_path(state_dir, "tokens")).await?;
+    Ok(tokens
+        .get(&key(token))
+        .filter(|grant| !grant.expired())
+        .cloned())
+}
+
+/// Forget an access token; unknown tokens are ignored.
+pub async fn revoke(state_dir: &Path, token: &str) -> std::io::Result<()> {
+    let _guard = STORE.lock().await;
+    let path = store_path(state_dir, "tokens");
+    let mut tokens = read::<Grant>(&path).await?;
+    if tokens.remove(&key(token)).is_some() {
+        write(&path, &tokens).await?;
+    }
+    Ok(())
+}
+
+/// The bearer token in a request's `Authorization` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Whether `verifier` answers a PKCE `S256` `challenge`.
+pub fn pkce_matches(challenge: &str, verifier: &str) -> bool {
+    let expected = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    constant_time_eq(expected.as_bytes(), challenge.as_bytes())
+}
+
+/// Whether `password` matches a PHC-format `hash`; invalid hashes match nothing.
+///
+/// This is deliberately slow, so it shouldn't be called from async tasks.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            tracing::error!(error = %e, "invalid password hash");
+            false
+        }
+    }
+}
+
+/// Hash `password` with Argon2id, in PHC format.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let mut salt = [0; 16];
+    getrandom::fill(&mut salt).map_err(|_| argon2::password_hash::Error::Crypto)?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &SaltString::encode_b64(&salt)?)?
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pkce_s256() {
+        // from RFC 7636, appendix B
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+        assert!(pkce_matches(challenge, verifier));
+        assert!(!pkce_matches(challenge, &verifier[1..]));
+        assert!(!pkce_matches(&challenge[..42], verifier));
+        // `plain` challenges aren't accepted
+        assert!(!pkce_matches(verifier, verifier));
+        assert!(!pkce_matches("", ""));
+    }
+
+    #[test]
+    fn passwords() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(verify_password(&hash, "correct horse"));
+        assert!(!verify_password(&hash, "correct horse "));
+        assert!(!verify_password("", ""));
+        assert!(!verify_password("correct horse", "correct horse"));
+    }
+
+    #[tokio::test]
+    async fn tokens() {
+        let state = tempfile::tempdir().unwrap();
+        let grant = |expires| Grant {
+            me: "https://example.com/".into(),
+            client_id: "https://app.example/".into(),
+            scope: "create media".into(),
+            expires,
+        };
+        let token = issue_token(state.path(), grant(None)).await.unwrap();
+        let found = super::token(state.path(), &token).await.unwrap().unwrap();
+        assert!(found.has_scope("media") && !found.has_scope("update"));
+        assert!(super::token(state.path(), "nope").await.unwrap().is_none());
+
+        let expired = issue_token(state.path(), grant(Some(1))).await.unwrap();
+        assert!(super::token(state.path(), &expired)
+            .await
+            .unwrap()
+            .is_none());
+
+        revoke(state.path(), &token).await.unwrap();
+        assert!(super::token(state.path(), &token).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{
+    cli::Cli,
+    config::{Config, ConfigError, Listen},
+};
+use crossbeam::sync::ShardedLock;
+use std::sync::Arc;
+
+use super::{
+    channels::Channels,
+    gemini,
+    templates::Templates,
+    tls::{CertResolver, Certificates},
+    upstreams::Upstreams,
+};
+
+/// Reloads the running configuration from the same sources used at startup.
+#[derive(Debug, Clone)]
+pub struct Reloader {
+    args: Arc<Cli>,
+    cfg: Arc<ShardedLock<Config>>,
+    certs: Arc<CertResolver>,
+    gemini_certs: Arc<gemini::Certificates>,
+    upstreams: Arc<Upstreams>,
+    channels: Arc<Channels>,
+    templates: Arc<Templates>,
+    /// The listeners configured at startup, which can't be changed without a restart.
+    initial_listen: Listen,
+}
+
+impl Reloader {
+    /// Must be called within the tokio runtime.
+    pub fn new(
+        args: Arc<Cli>,
+        cfg: Arc<ShardedLock<Config>>,
+        certs: Arc<CertResolver>,
+        gemini_certs: Arc<gemini::Certificates>,
+    ) -> Self {
+        let upstreams = Arc::new(Upstreams::default());
+        let channels = Arc::new(Channels::default());
+        let templates = Arc::new(Templates::default());
+        let initial_listen = {
+            let cfg = cfg.read().unwrap();
+            upstreams.update(&cfg);
+            channels.update(&cfg);
+            templates.update(&cfg);
+            cfg.listen.clone()
+        };
+        Self {
+            args,
+            cfg,
+            certs,
+            gemini_certs,
+            upstreams,
+            channels,
+            templates,
+            initial_listen,
+        }
+    }
+
+    pub fn config(&self) -> &Arc<ShardedLock<Config>> {
+        &self.cfg
+    }
+
+    pub fn certificates(&self) -> &Arc<CertResolver> {
+        &self.certs
+    }
+
+    pub fn upstreams(&self) -> &Arc<Upstreams> {
+        &self.upstreams
+    }
+
+    pub fn channels(&self) -> &Arc<Channels> {
+        &self.channels
+    }
+
+    pub fn templates(&self) -> &Arc<Templates> {
+        &self.templates
+    }
+
+    /// Load & validate the configuration, then replace the running configuration with it. If
+    /// loading fails, the running configuration is left untouched.
+    #[tracing::instrument(skip(self))]
+    pub fn reload(&self) -> Result<(), ConfigError<'static>> {
+        let mut new = Config::from_args(&self.args)?;
+        for problem in new.check() {
+            tracing::warn!("{problem}");
+        }
+        if new.listen != self.initial_listen {
+            tracing::warn!("listener configuration changed; restart to apply");
+        }
+        let certs = Certificates::load(&new)?;
+
+        self.certs.replace(certs);
+        let serves_gemini = {
+            let mut cfg = self.cfg.write().unwrap();
+            // keep the listeners we actually opened (including those passed from systemd)
+            new.listen = std::mem::take(&mut cfg.listen);
+            *cfg = new;
+            self.upstreams.update(&cfg);
+            self.channels.update(&cfg);
+            self.templates.update(&cfg);
+            tracing::info!(sources = ?cfg.sources, "reloaded configuration");
+            !cfg.listen.gemini.is_empty()
+        };
+        // new hosts need certificates; this reads the configuration, so it's done once it's been
+        // replaced
+        if serves_gemini {
+            if let Err(e) = self.gemini_certs.refresh() {
+                tracing::error!(error = %e, "failed to load gemini certificates");
+            }
+        }
+        Ok(())
+    }
+
+    /// Reload, logging (and otherwise ignoring) failures.
+    pub fn reload_or_log(&self) -> bool {
+        match self.reload() {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to reload configuration; keeping current configuration");
+                false
+            }
+        }
+    }
+
+    /// Like [`reload_or_log`](Self::reload_or_log), but run on the blocking thread pool, since
+    /// reloading reads files.
+    pub async fn reload_blocking_or_log(&self) -> bool {
+        let reloader = self.clone();
+        match tokio::task::spawn_blocking(move || reloader.reload_or_log()).await {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                tracing::error!(error = ?e, "configuration reload task failed");
+                false
+            }
+        }
+    }
+}
+
+/// Reload whenever we receive `SIGHUP`.
+pub async fn on_hangup(reloader: Reloader) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        tracing::info!("received SIGHUP");
+        reloader.reload_blocking_or_log().await;
+    }
+    Ok(())
+}
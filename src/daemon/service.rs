@@ -2,26 +2,55 @@ use bytes::Bytes;
 use crossbeam::sync::ShardedLock;
 use futures::FutureExt;
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Full};
+use http_body_util::{BodyExt, Empty, Full};
 use hyper::body;
-use hyper::{Method, Request, Response, StatusCode};
+use hyper::{header, header::HeaderValue, Method, Request, Response, StatusCode, Uri};
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 
+use super::{errors, reload::Reloader};
+use crate::config::{
+    routes::{request_host, Handler, PathPattern, RouteTable},
+    rules::{self, Action},
+};
+
 // type PinFuture<Output> = Pin<Box<dyn Future<Output = Output> + Send>>;
 
 pub type Result<O> = std::result::Result<O, ServiceError>;
 
+pub type SvcResponse = Response<BoxBody<Bytes, ServiceError>>;
+
 #[derive(Debug, thiserror::Error)]
 pub enum ServiceError {
     #[error(transparent)]
     Hyper(#[from] hyper::Error),
     #[error(transparent)]
     Http(#[from] hyper::http::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
     #[error("infallible...?")]
     Infallible,
 }
 
+impl ServiceError {
+    /// The status with which to respond when this prevents a response from being made.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            // failures reading the request
+            Self::Hyper(_) => StatusCode::BAD_REQUEST,
+            Self::Io(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                std::io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+                std::io::ErrorKind::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Self::Http(_) | Self::Infallible => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 impl From<std::convert::Infallible> for ServiceError {
     fn from(_: std::convert::Infallible) -> Self {
         Self::Infallible
@@ -30,42 +59,77 @@ impl From<std::convert::Infallible> for ServiceError {
 
 #[derive(Debug, Clone, Copy)]
 pub struct ServiceConfig {
-    tls: bool,
-    allow_ctl: bool,
+    pub tls: bool,
+    pub allow_ctl: bool,
+    /// Redirect requests to HTTPS instead of serving them; see [super::https_redirect].
+    pub redirect_https: bool,
 }
 
 impl ServiceConfig {
     pub const UNIX: Self = Self {
         tls: false,
         allow_ctl: true,
+        redirect_https: false,
     };
     pub const HTTP: Self = Self {
         tls: false,
         allow_ctl: false,
+        redirect_https: false,
     };
     pub const HTTPS: Self = Self {
         tls: true,
         allow_ctl: false,
+        redirect_https: false,
+    };
+    pub const HTTP_REDIRECT: Self = Self {
+        tls: false,
+        allow_ctl: false,
+        redirect_https: true,
     };
 }
 
+/// What we know about the connection on which a request arrived.
+#[derive(Debug, Clone, Default)]
+pub struct Connection {
+    /// The client's address, if it connected over TCP.
+    pub peer: Option<SocketAddr>,
+    pub tls: bool,
+    /// The server name sent by the client during the TLS handshake.
+    pub sni: Option<Arc<str>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Service {
     pub cfg: Arc<ShardedLock<crate::config::Config>>,
+    pub reloader: Reloader,
     pub allow_ctl: bool,
+    pub redirect_https: bool,
+    pub conn: Connection,
 }
 
 impl Service {
-    pub fn new(cfg: Arc<ShardedLock<crate::config::Config>>, svc_cfg: &ServiceConfig) -> Self {
+    pub fn new(reloader: Reloader, svc_cfg: &ServiceConfig) -> Self {
         Self {
-            cfg,
+            cfg: reloader.config().clone(),
+            reloader,
             allow_ctl: svc_cfg.allow_ctl,
+            redirect_https: svc_cfg.redirect_https,
+            conn: Connection {
+                peer: None,
+                tls: svc_cfg.tls,
+                sni: None,
+            },
         }
     }
+
+    /// This service, for requests arriving on `conn`.
+    pub fn with_connection(self, conn: Connection) -> Self {
+        Self { conn, ..self }
+    }
 }
 
-impl hyper::service::Service<Request<body::Incoming>> for Service {
-    type Response = Response<BoxBody<Bytes, ServiceError>>;
+impl tower::Service<Request<body::Incoming>> for Service {
+    type Response = SvcResponse;
     type Error = ServiceError;
     type Future = Pin<
         Box<
@@ -74,34 +138,157 @@ impl hyper::service::Service<Request<body::Incoming>> for Service {
         >,
     >;
 
-    fn call(&self, req: Request<body::Incoming>) -> Self::Future {
+    fn poll_ready(
+        &mut self,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::result::Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<body::Incoming>) -> Self::Future {
         tracing::debug!(request = ?req, "received request");
-        respond(self.cfg.clone(), self.allow_ctl, req).boxed()
+        req.extensions_mut().insert(self.conn.clone());
+        respond(self.clone(), req).boxed()
     }
 }
 
 // type SvcResponse = <Svc as Service<Request<body::Incoming>>>::Response;
 // type SvcError = <Svc as Service<Request<body::Incoming>>>::Error;
 
-pub async fn respond(
-    cfg: Arc<ShardedLock<crate::config::Config>>,
-    allow_ctl: bool,
-    req: Request<body::Incoming>,
-) -> Result<Response<BoxBody<Bytes, ServiceError>>> {
-    fn mk_response(s: impl ToString) -> Result<Response<BoxBody<Bytes, ServiceError>>> {
-        Response::builder()
-            .body(full(Bytes::from(s.to_string())))
-            .map_err(ServiceError::from)
+pub async fn respond(svc: Service, req: Request<body::Incoming>) -> Result<SvcResponse> {
+    if svc.allow_ctl && req.uri().path() == "/api" {
+        return respond_api(&svc.cfg, &svc.reloader, req).await;
     }
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, "/") => mk_response(
-            "Try POSTing data to /echo (ex. `curl localhost:8080/echo -XPOST -d \"Hello, World\"`)",
-        ),
-        (&Method::POST, "/echo") => Ok(Response::new(
-            req.into_body().map_err(ServiceError::from).boxed(),
-        )),
-        (_, "/api") if allow_ctl => respond_api(&cfg, req).await,
-        _ => {
+    let info = errors::RequestInfo::new(&req, &svc.conn);
+    let (hsts, links) = {
+        let cfg = svc.cfg.read().unwrap();
+        let host = cfg.virtual_host(request_host(&req).or(svc.conn.sni.as_deref()));
+        let hsts = match svc.conn.tls {
+            true => host
+                .hsts
+                .and_then(|hsts| HeaderValue::from_str(&hsts.header_value()).ok()),
+            false => None,
+        };
+        (hsts, advertised_links(host.routes))
+    };
+    let res = match svc.redirect_https {
+        true => super::https_redirect::respond(&svc, req).await,
+        false => dispatch(&svc, req).await,
+    };
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let status = e.status();
+            match status.is_server_error() {
+                true => tracing::error!(error = ?e, %status, "failed to respond"),
+                false => tracing::debug!(error = ?e, %status, "failed to respond"),
+            }
+            Response::builder().status(status).body(empty())?
+        }
+    };
+    let mut res = errors::describe(&svc.cfg, &info, res).await;
+    if let Some(hsts) = hsts {
+        res.headers_mut()
+            .insert(header::STRICT_TRANSPORT_SECURITY, hsts);
+    }
+    if !links.is_empty() {
+        let html = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .is_some_and(|t| t.starts_with("text/html"));
+        if html && res.status().is_success() {
+            for link in links {
+                res.headers_mut().append(header::LINK, link);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// `Link` headers advertising the first of each IndieWeb endpoint (Webmention, IndieAuth, &
+/// Micropub) in `routes` which should be.
+fn advertised_links(routes: &RouteTable) -> Vec<HeaderValue> {
+    let (mut webmention, mut indieauth, mut micropub) = (None, None, None);
+    for route in routes.iter() {
+        match (&route.handler, &route.path) {
+            (Handler::Webmention(cfg), PathPattern::Exact(path)) if cfg.advertise => {
+                webmention.get_or_insert_with(|| format!("<{path}>; rel=\"webmention\""));
+            }
+            (Handler::IndieAuth(cfg), PathPattern::Prefix(prefix)) if cfg.advertise => {
+                let base = prefix.trim_end_matches('/');
+                indieauth.get_or_insert_with(|| {
+                    format!(
+                        "<{base}/metadata>; rel=\"indieauth-metadata\", \
+                        <{base}/auth>; rel=\"authorization_endpoint\", \
+                        <{base}/token>; rel=\"token_endpoint\""
+                    )
+                });
+            }
+            (Handler::Micropub(cfg), PathPattern::Exact(path)) if cfg.advertise => {
+                micropub.get_or_insert_with(|| format!("<{path}>; rel=\"micropub\""));
+            }
+            _ => {}
+        }
+    }
+    [webmention, indieauth, micropub]
+        .into_iter()
+        .flatten()
+        .filter_map(|link| HeaderValue::from_str(&link).ok())
+        .collect()
+}
+
+/// Apply rules, then route `req` within its virtual host.
+async fn dispatch(svc: &Service, mut req: Request<body::Incoming>) -> Result<SvcResponse> {
+    // clone what we need so that we don't hold the lock while responding
+    let route = {
+        let cfg = svc.cfg.read().unwrap();
+        match rules::apply(cfg.rules(), &req, svc.conn.tls) {
+            Some(Action::Redirect(status, location)) => {
+                return Ok(Response::builder()
+                    .status(status)
+                    .header(header::LOCATION, location)
+                    .body(empty())?);
+            }
+            Some(Action::Rewrite(to)) => {
+                if let Err(e) = rewrite(&mut req, &to) {
+                    tracing::error!(uri = %req.uri(), to, error = ?e, "invalid rewrite target");
+                    return Ok(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(empty())?);
+                }
+            }
+            None => {}
+        }
+        let host = cfg.virtual_host(request_host(&req).or(svc.conn.sni.as_deref()));
+        if let Some(sni) = &svc.conn.sni {
+            // the connection was established for a different host, maybe with a different
+            // certificate, so the client should retry with a new connection
+            if cfg.virtual_host(Some(sni)).index != host.index {
+                return Ok(Response::builder()
+                    .status(StatusCode::MISDIRECTED_REQUEST)
+                    .body(empty())?);
+            }
+        }
+        if let Some(canonical) = host.redirect_to {
+            return Ok(Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(header::LOCATION, canonical_url(&svc.conn, canonical, &req))
+                .body(empty())?);
+        }
+        host.routes.find(&req).map(|(route, captures)| {
+            (
+                route.handler.clone(),
+                captures,
+                super::handler::Context::new(&cfg, &host, route, &svc.reloader),
+            )
+        })
+    };
+    match route {
+        Some((handler, captures, ctx)) => {
+            super::handler::handle(&handler, &ctx, captures, req).await
+        }
+        None => {
             let mut not_found = Response::default();
             *not_found.status_mut() = StatusCode::NOT_FOUND;
             Ok(not_found)
@@ -109,14 +296,44 @@ pub async fn respond(
     }
 }
 
-fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, ServiceError> {
+/// Replace the path & query of `req` with `to`.
+fn rewrite<B>(req: &mut Request<B>, to: &str) -> std::result::Result<(), hyper::http::Error> {
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(to.parse()?);
+    *req.uri_mut() = Uri::from_parts(parts)?;
+    Ok(())
+}
+
+/// The URL of `req`, with its host replaced by `name`.
+fn canonical_url<B>(conn: &Connection, name: &str, req: &Request<B>) -> String {
+    let scheme = if conn.tls { "https" } else { "http" };
+    let authority = req
+        .uri()
+        .authority()
+        .map(|a| a.as_str())
+        .or_else(|| req.headers().get(header::HOST)?.to_str().ok())
+        .unwrap_or_default();
+    let port = match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => &authority[i..],
+        _ => "",
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    format!("{scheme}://{name}{port}{path}")
+}
+
+pub fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, ServiceError> {
     Full::new(chunk.into()).map_err(|n| match n {}).boxed()
 }
 
+pub fn empty() -> BoxBody<Bytes, ServiceError> {
+    Empty::new().map_err(|n| match n {}).boxed()
+}
+
 async fn respond_api(
     cfg: &Arc<ShardedLock<crate::config::Config>>,
+    reloader: &Reloader,
     req: Request<body::Incoming>,
-) -> Result<Response<BoxBody<Bytes, ServiceError>>> {
+) -> Result<SvcResponse> {
     match (req.method(), req.uri().query()) {
         (&Method::GET, Some("config")) => Ok(Response::builder()
             .body(
@@ -126,6 +343,43 @@ async fn respond_api(
                     .boxed(),
             )
             .unwrap()),
+        (&Method::GET, Some("upstreams")) => Ok(Response::new(full(
+            serde_json::to_string(&reloader.upstreams().status()).unwrap(),
+        ))),
+        (&Method::POST, Some(query)) if query.starts_with("publish&") => {
+            let mut params = url::form_urlencoded::parse(query.as_bytes()).into_owned();
+            let (mut channel, mut event) = (None, None);
+            for (name, value) in &mut params {
+                match name.as_str() {
+                    "channel" => channel = Some(value),
+                    "event" => event = Some(value),
+                    _ => {}
+                }
+            }
+            match channel.and_then(|name| reloader.channels().get(&name)) {
+                Some(channel) => super::handler::events::publish(&channel, event, req).await,
+                None => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(full("no such channel"))
+                    .map_err(ServiceError::from),
+            }
+        }
+        (&Method::POST, Some("reload")) => {
+            let reloader = reloader.clone();
+            match tokio::task::spawn_blocking(move || reloader.reload().map_err(|e| e.to_string()))
+                .await
+            {
+                Ok(Ok(())) => Ok(Response::new(full("configuration reloaded"))),
+                Ok(Err(e)) => Response::builder()
+                    .status(StatusCode::UNPROCESSABLE_ENTITY)
+                    .body(full(e))
+                    .map_err(ServiceError::from),
+                Err(e) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(full(e.to_string()))
+                    .map_err(ServiceError::from),
+            }
+        }
         _ => {
             let mut not_found = Response::default();
             *not_found.status_mut() = StatusCode::NOT_FOUND;
@@ -0,0 +1,214 @@
+//! Loaded templates & data files, for template routes.
+
+use super::webmentions;
+use crate::config::Config;
+use crossbeam::sync::ShardedLock;
+use minijinja::{context, Environment, ErrorKind, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+    #[error(transparent)]
+    Render(#[from] minijinja::Error),
+    #[error("failed to read data files: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid data file {0:?}: {1}")]
+    Toml(PathBuf, toml::de::Error),
+    #[error("invalid data file {0:?}: {1}")]
+    Json(PathBuf, serde_json::Error),
+}
+
+/// A template directory, and a data directory.
+type Key = (PathBuf, Option<PathBuf>);
+
+/// The templates & data files for one route.
+#[derive(Debug)]
+struct Loaded {
+    env: Environment<'static>,
+    data: Value,
+    /// The state of the files when they were loaded; only tracked in development mode.
+    fingerprint: Option<blake3::Hash>,
+}
+
+/// Every route's templates, loaded when first used.
+#[derive(Debug, Default)]
+pub struct Templates {
+    /// The `[site]` variables.
+    site: ShardedLock<Value>,
+    state_dir: ShardedLock<PathBuf>,
+    development: AtomicBool,
+    /// By template & data directory.
+    loaded: ShardedLock<HashMap<Key, Arc<Loaded>>>,
+}
+
+impl Templates {
+    /// Apply the settings in `cfg`, forgetting every loaded template, so that they're read again
+    /// when next used.
+    pub fn update(&self, cfg: &Config) {
+        *self.site.write().unwrap() = Value::from(
+            cfg.site
+                .iter()
+                .map(|(k, v)| (k.clone(), toml_value(v)))
+                .collect::<BTreeMap<_, _>>(),
+        );
+        *self.state_dir.write().unwrap() = cfg.directories.state.clone();
+        self.development
+            .store(cfg.server.development, Ordering::Relaxed);
+        self.loaded.write().unwrap().clear();
+    }
+
+    pub fn development(&self) -> bool {
+        self.development.load(Ordering::Relaxed)
+    }
+
+    /// Render the template `name` from `root`, with the data files in `data`. In development mode,
+    /// the templates & data are loaded again if any of their files have changed.
+    ///
+    /// This reads files synchronously, so it shouldn't be called from async tasks.
+    pub fn render(
+        &self,
+        root: &Path,
+        data: Option<&Path>,
+        name: &str,
+        request: Value,
+    ) -> Result<String, TemplateError> {
+        let key = (root.to_owned(), data.map(Path::to_owned));
+        let fingerprint = self
+            .development()
+            .then(|| fingerprint([Some(root), data].into_iter().flatten()));
+        let loaded = self
+            .loaded
+            .read()
+            .unwrap()
+            .get(&key)
+            .filter(|loaded| loaded.fingerprint == fingerprint)
+            .cloned();
+        let loaded = match loaded {
+            Some(loaded) => loaded,
+            None => {
+                let state_dir = self.state_dir.read().unwrap().clone();
+                let loaded = Arc::new(load(root, data, state_dir, fingerprint)?);
+                tracing::debug!(?root, ?data, "loaded templates");
+                self.loaded.write().unwrap().insert(key, loaded.clone());
+                loaded
+            }
+        };
+        let site = self.site.read().unwrap().clone();
+        Ok(loaded.env.get_template(name)?.render(context! {
+            request,
+            site,
+            data => loaded.data.clone(),
+        })?)
+    }
+}
+
+fn load(
+    root: &Path,
+    data: Option<&Path>,
+    state_dir: PathBuf,
+    fingerprint: Option<blake3::Hash>,
+) -> Result<Loaded, TemplateError> {
+    let mut env = Environment::new();
+    env.set_loader(minijinja::path_loader(root));
+    env.add_function("webmentions", move |target: &str| {
+        // stored targets are normalized, as URLs
+        let target = url::Url::parse(target).map_or(target.to_owned(), String::from);
+        webmentions::mentions_of(&state_dir, &target)
+            .map(Value::from_serialize)
+            .map_err(|e| minijinja::Error::new(ErrorKind::InvalidOperation, e.to_string()))
+    });
+    let data = match data {
+        Some(data) => load_data(data)?,
+        None => Value::from(BTreeMap::<String, Value>::new()),
+    };
+    Ok(Loaded {
+        env,
+        data,
+        fingerprint,
+    })
+}
+
+/// The contents of each TOML/JSON file in `dir`, by file stem.
+fn load_data(dir: &Path) -> Result<Value, TemplateError> {
+    let mut res = BTreeMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let value = match path.extension().and_then(OsStr::to_str) {
+            Some("toml") => toml_value(
+                &toml::from_str(&std::fs::read_to_string(&path)?)
+                    .map_err(|e| TemplateError::Toml(path.clone(), e))?,
+            ),
+            Some("json") => Value::from_serialize(
+                serde_json::from_str::<serde_json::Value>(&std::fs::read_to_string(&path)?)
+                    .map_err(|e| TemplateError::Json(path.clone(), e))?,
+            ),
+            _ => continue,
+        };
+        let Some(stem) = path.file_stem() else {
+            continue;
+        };
+        res.insert(stem.to_string_lossy().into_owned(), value);
+    }
+    Ok(Value::from(res))
+}
+
+/// A TOML value as a template value; dates & times become strings in their TOML format, rather
+/// than serde's private representation.
+fn toml_value(value: &toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::from(s.as_str()),
+        toml::Value::Integer(i) => Value::from(*i),
+        toml::Value::Float(f) => Value::from(*f),
+        toml::Value::Boolean(b) => Value::from(*b),
+        toml::Value::Datetime(d) => Value::from(d.to_string()),
+        toml::Value::Array(a) => Value::from(a.iter().map(toml_value).collect::<Vec<_>>()),
+        toml::Value::Table(t) => Value::from(
+            t.iter()
+                .map(|(k, v)| (k.clone(), toml_value(v)))
+                .collect::<BTreeMap<_, _>>(),
+        ),
+    }
+}
+
+/// A hash of the paths, sizes, & modification times of every file within `dirs`, which changes
+/// whenever any of them do.
+pub fn fingerprint<'p>(dirs: impl Iterator<Item = &'p Path>) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    let mut pending = dirs.map(Path::to_owned).collect::<Vec<_>>();
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut entries = entries.filter_map(|e| e.ok()).collect::<Vec<_>>();
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let Ok(meta) = std::fs::metadata(&path) else {
+                continue;
+            };
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            hasher.update(path.as_os_str().as_bytes());
+            hasher.update(&meta.len().to_le_bytes());
+            hasher.update(&mtime.as_nanos().to_le_bytes());
+            // symlinked directories aren't followed, in case they form a cycle
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                pending.push(path);
+            }
+        }
+    }
+    hasher.finalize()
+}
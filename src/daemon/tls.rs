@@ -0,0 +1,104 @@
+//! TLS termination, with certificates selected by SNI per virtual host.
+
+use crate::config::{
+    hosts::{self, Host, Tls},
+    Config, ConfigError, ConfigErrorVariant,
+};
+use crossbeam::sync::ShardedLock;
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use std::{path::Path, sync::Arc};
+use tokio_rustls::TlsAcceptor;
+
+/// The certificates named by a configuration.
+#[derive(Debug, Default)]
+pub struct Certificates {
+    hosts: Vec<Host>,
+    /// Parallel to `hosts`.
+    keys: Vec<Option<Arc<CertifiedKey>>>,
+    /// For clients which don't send SNI, or which name a host without a certificate.
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    pub fn load(cfg: &Config) -> Result<Self, ConfigError<'static>> {
+        let keys = cfg
+            .hosts
+            .iter()
+            .map(|host| host.tls.as_ref().map(load_key).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+        let default = match &cfg.server.tls {
+            Some(tls) => Some(load_key(tls)?),
+            None => cfg.default_host().and_then(|i| keys[i].clone()),
+        };
+        Ok(Self {
+            hosts: cfg.hosts.clone(),
+            keys,
+            default,
+        })
+    }
+
+    fn resolve(&self, name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        name.and_then(|name| hosts::find(&self.hosts, name))
+            .and_then(|(i, _)| self.keys[i].clone())
+            .or_else(|| self.default.clone())
+    }
+}
+
+pub fn load_key(tls: &Tls) -> Result<Arc<CertifiedKey>, ConfigError<'static>> {
+    fn err<E: Into<ConfigErrorVariant>>(path: &Path) -> impl FnOnce(E) -> ConfigError<'static> {
+        let path = path.to_owned();
+        move |e| ConfigError::new(path, e)
+    }
+    let certs = CertificateDer::pem_file_iter(&tls.certificate)
+        .map_err(err(&tls.certificate))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(err(&tls.certificate))?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key).map_err(err(&tls.key))?;
+    let key = ring::sign::any_supported_type(&key).map_err(err(&tls.key))?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+/// Selects certificates for incoming connections; replaced on reload.
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    certs: ShardedLock<Arc<Certificates>>,
+}
+
+impl CertResolver {
+    pub fn new(certs: Certificates) -> Self {
+        Self {
+            certs: ShardedLock::new(Arc::new(certs)),
+        }
+    }
+
+    pub fn replace(&self, certs: Certificates) {
+        *self.certs.write().unwrap() = Arc::new(certs);
+    }
+
+    /// An acceptor for TLS connections, which negotiates HTTP/2 or HTTP/1.1 via ALPN.
+    pub fn acceptor(self: &Arc<Self>) -> Result<TlsAcceptor, rustls::Error> {
+        let mut cfg = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        cfg.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(cfg)))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap().clone();
+        let res = certs.resolve(client_hello.server_name());
+        if res.is_none() {
+            tracing::warn!(sni = ?client_hello.server_name(), "no certificate for TLS connection");
+        }
+        res
+    }
+}
@@ -0,0 +1,338 @@
+//! The running state of upstream groups: choosing members for requests, and tracking their health.
+
+use super::handler::proxy;
+use crate::config::{
+    upstreams::{
+        HashKey, HealthCheck, PassiveHealth, Policy, ProxyTarget, Upstream, UpstreamGroup,
+    },
+    Config,
+};
+use crossbeam::sync::ShardedLock;
+use hyper::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpStream, UnixStream},
+    task::JoinHandle,
+};
+use tokio_util::either::Either;
+
+#[derive(Debug, Default)]
+struct Health {
+    /// Consecutive failed health check probes.
+    probe_failures: u32,
+    /// Whether health checks have taken this member out of rotation.
+    down: bool,
+    /// Consecutive failed proxied requests.
+    failures: u32,
+    ejected_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Member {
+    pub upstream: Upstream,
+    /// Requests in flight.
+    active: AtomicUsize,
+    health: parking_lot::Mutex<Health>,
+}
+
+impl Member {
+    fn new(upstream: Upstream) -> Self {
+        Self {
+            upstream,
+            active: AtomicUsize::new(0),
+            health: Default::default(),
+        }
+    }
+
+    fn available(&self, now: Instant) -> bool {
+        let health = self.health.lock();
+        !health.down && health.ejected_until.is_none_or(|until| until <= now)
+    }
+
+    fn probed(&self, healthy: bool, cfg: &HealthCheck) {
+        let mut health = self.health.lock();
+        if healthy {
+            health.probe_failures = 0;
+            if health.down {
+                tracing::info!(upstream = %self.upstream, "upstream passed health check; back in rotation");
+                health.down = false;
+            }
+        } else {
+            health.probe_failures += 1;
+            if !health.down && health.probe_failures >= cfg.unhealthy_threshold {
+                tracing::warn!(upstream = %self.upstream, "upstream failed health checks; out of rotation");
+                health.down = true;
+            }
+        }
+    }
+
+    fn status(&self, now: Instant) -> MemberStatus {
+        let health = self.health.lock();
+        let ejected_for = health
+            .ejected_until
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|d| !d.is_zero());
+        MemberStatus {
+            upstream: self.upstream.to_string(),
+            state: match (health.down, ejected_for) {
+                (true, _) => MemberState::Down,
+                (false, Some(_)) => MemberState::Ejected,
+                (false, None) => MemberState::Up,
+            },
+            active: self.active.load(Ordering::Relaxed),
+            failures: health.failures,
+            ejected_for_ms: ejected_for.map(|d| d.as_millis() as u64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MemberState {
+    Up,
+    /// Taken out of rotation by health checks.
+    Down,
+    /// Taken out of rotation after failed requests.
+    Ejected,
+}
+
+/// A member's health, as reported by the control API.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MemberStatus {
+    pub upstream: String,
+    pub state: MemberState,
+    pub active: usize,
+    pub failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ejected_for_ms: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Group {
+    cfg: UpstreamGroup,
+    members: Vec<Arc<Member>>,
+    next: AtomicUsize,
+}
+
+impl Group {
+    fn round_robin<'m>(&self, candidates: &[&'m Arc<Member>]) -> &'m Arc<Member> {
+        candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+    }
+}
+
+/// A member chosen to serve a request, which counts as in flight until this is dropped.
+#[derive(Debug)]
+pub struct Lease {
+    member: Arc<Member>,
+    passive: PassiveHealth,
+}
+
+impl Lease {
+    fn new(member: Arc<Member>, passive: PassiveHealth) -> Self {
+        member.active.fetch_add(1, Ordering::Relaxed);
+        Self { member, passive }
+    }
+
+    pub fn upstream(&self) -> &Upstream {
+        &self.member.upstream
+    }
+
+    pub fn succeeded(&self) {
+        self.member.health.lock().failures = 0;
+    }
+
+    /// Record a failed request, ejecting the member if it's failed too many times in a row.
+    pub fn failed(&self) {
+        let mut health = self.member.health.lock();
+        health.failures += 1;
+        if self.passive.max_fails > 0 && health.failures >= self.passive.max_fails {
+            tracing::warn!(upstream = %self.member.upstream, failures = health.failures, "ejecting upstream");
+            health.failures = 0;
+            health.ejected_until =
+                Some(Instant::now() + Duration::from_millis(self.passive.eject_ms));
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.member.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Every configured upstream group, along with its health checks.
+#[derive(Debug, Default)]
+pub struct Upstreams {
+    groups: ShardedLock<HashMap<String, Arc<Group>>>,
+    checks: parking_lot::Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Upstreams {
+    /// Replace the running groups with those in `cfg`. Members which remain in the same group keep
+    /// their health and connection counts. Must be called within the tokio runtime.
+    pub fn update(&self, cfg: &Config) {
+        let mut groups = self.groups.write().unwrap();
+        let new = cfg
+            .upstreams
+            .iter()
+            .map(|(name, group_cfg)| {
+                let old = groups.get(name);
+                let members = group_cfg
+                    .members
+                    .iter()
+                    .map(|upstream| {
+                        old.and_then(|old| old.members.iter().find(|m| m.upstream == *upstream))
+                            .cloned()
+                            .unwrap_or_else(|| Arc::new(Member::new(upstream.clone())))
+                    })
+                    .collect();
+                let group = Group {
+                    cfg: group_cfg.clone(),
+                    members,
+                    next: AtomicUsize::new(0),
+                };
+                (name.clone(), Arc::new(group))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut checks = self.checks.lock();
+        for check in checks.drain(..) {
+            check.abort();
+        }
+        for (name, group) in &new {
+            // an interval of 0 disables the check; see `Config::check`
+            let health_check = group.cfg.health_check.clone();
+            if let Some(health_check) = health_check.filter(|h| h.interval_ms > 0) {
+                checks.push(tokio::task::spawn(check_health(
+                    name.clone(),
+                    group.clone(),
+                    health_check,
+                )));
+            }
+        }
+        *groups = new;
+    }
+
+    /// Choose a member of `group` to serve a request with `headers`. If every member is out of
+    /// rotation, they're all considered, in case they've recovered.
+    pub fn select(&self, group: &str, headers: &HeaderMap) -> Option<Lease> {
+        let group = self.groups.read().unwrap().get(group)?.clone();
+        let now = Instant::now();
+        let available = group
+            .members
+            .iter()
+            .filter(|m| m.available(now))
+            .collect::<Vec<_>>();
+        let candidates = match available.is_empty() {
+            true => group.members.iter().collect(),
+            false => available,
+        };
+        if candidates.is_empty() {
+            return None;
+        }
+        let member = match &group.cfg.policy {
+            Policy::RoundRobin => group.round_robin(&candidates),
+            Policy::LeastConnections => candidates
+                .iter()
+                .min_by_key(|m| m.active.load(Ordering::Relaxed))
+                .unwrap(),
+            // rendezvous hashing, so that only requests for an unavailable member move
+            Policy::ConsistentHash(key) => match hash_key(key, headers) {
+                Some(key) => candidates
+                    .iter()
+                    .max_by_key(|m| {
+                        let mut hasher = DefaultHasher::new();
+                        key.hash(&mut hasher);
+                        m.upstream.hash(&mut hasher);
+                        hasher.finish()
+                    })
+                    .unwrap(),
+                None => group.round_robin(&candidates),
+            },
+        };
+        Some(Lease::new(member.clone(), group.cfg.passive.clone()))
+    }
+
+    /// The upstream to which to send a request with `headers`, along with its lease if it was
+    /// chosen from a group.
+    pub fn resolve(
+        &self,
+        target: &ProxyTarget,
+        headers: &HeaderMap,
+    ) -> Option<(Upstream, Option<Lease>)> {
+        match target {
+            ProxyTarget::Upstream(upstream) => Some((upstream.clone(), None)),
+            ProxyTarget::Group(group) => match self.select(group, headers) {
+                Some(lease) => Some((lease.upstream().clone(), Some(lease))),
+                None => {
+                    tracing::error!(group, "no such upstream group, or it has no members");
+                    None
+                }
+            },
+        }
+    }
+
+    pub fn status(&self) -> BTreeMap<String, Vec<MemberStatus>> {
+        let now = Instant::now();
+        self.groups
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, group)| {
+                (
+                    name.clone(),
+                    group.members.iter().map(|m| m.status(now)).collect(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// A connection to an upstream over which to speak a gateway protocol, like FastCGI.
+pub type Socket = Either<TcpStream, UnixStream>;
+
+pub async fn connect(upstream: &Upstream) -> std::io::Result<Socket> {
+    match upstream {
+        Upstream::Http { authority, .. } | Upstream::Tcp(authority) => {
+            Ok(Either::Left(TcpStream::connect(authority.as_str()).await?))
+        }
+        Upstream::Unix(path) => Ok(Either::Right(UnixStream::connect(path).await?)),
+    }
+}
+
+fn hash_key<'h>(key: &HashKey, headers: &'h HeaderMap) -> Option<&'h str> {
+    match key {
+        HashKey::Header(name) => headers.get(name.as_str())?.to_str().ok(),
+        HashKey::Cookie(name) => headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find_map(|(n, value)| (n == name).then_some(value)),
+    }
+}
+
+async fn check_health(name: String, group: Arc<Group>, cfg: HealthCheck) {
+    let timeout = Duration::from_millis(cfg.timeout_ms);
+    let mut interval = tokio::time::interval(Duration::from_millis(cfg.interval_ms));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        futures::future::join_all(group.members.iter().map(|member| async {
+            let healthy = proxy::probe(&member.upstream, &cfg.path, timeout).await;
+            tracing::trace!(group = name, upstream = %member.upstream, healthy, "probed upstream");
+            member.probed(healthy, &cfg);
+        }))
+        .await;
+    }
+}
@@ -0,0 +1,135 @@
+use super::reload::Reloader;
+use notify::{RecursiveMode, Watcher};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// How long to wait before retrying directories that couldn't be watched.
+const WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Watch the configuration sources for changes, reloading after changes have settled for
+/// `debounce`.
+///
+/// Parent directories are watched instead of the files themselves, so that editors which replace
+/// files by renaming over them are still noticed.
+#[tracing::instrument(skip(reloader))]
+pub async fn watch_config(reloader: Reloader, debounce: Duration) -> notify::Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<notify::Event>();
+    let mut watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                let _ = tx.send(event);
+            }
+            Err(e) => tracing::warn!(error = ?e, "config watcher error"),
+        })?;
+
+    let mut sources = sources(&reloader);
+    let mut watched = HashSet::<PathBuf>::new();
+    // whether some directories couldn't be watched, and should be retried
+    let mut incomplete = !update_watches_or_log(&mut watcher, &mut watched, &sources);
+    tracing::info!(?sources, "watching configuration for changes");
+
+    loop {
+        let event = match incomplete {
+            true => match tokio::time::timeout(WATCH_RETRY_INTERVAL, rx.recv()).await {
+                Ok(event) => event,
+                Err(_) => {
+                    incomplete = !update_watches_or_log(&mut watcher, &mut watched, &sources);
+                    continue;
+                }
+            },
+            false => rx.recv().await,
+        };
+        let Some(event) = event else {
+            break;
+        };
+        if !is_relevant(&event, &sources) {
+            continue;
+        }
+        // wait until there haven't been any relevant changes for `debounce`; other files in the
+        // watched directories may change all the time, so they mustn't put off reloading
+        let settled = tokio::time::sleep(debounce);
+        tokio::pin!(settled);
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) if is_relevant(&event, &sources) => {
+                        settled.as_mut().reset(tokio::time::Instant::now() + debounce);
+                    }
+                    Some(_) => {}
+                    None => return Ok(()),
+                },
+                () = &mut settled => break,
+            }
+        }
+        tracing::info!("configuration changed; reloading");
+        if reloader.reload_blocking_or_log().await || incomplete {
+            sources = self::sources(&reloader);
+            incomplete = !update_watches_or_log(&mut watcher, &mut watched, &sources);
+        }
+    }
+    Ok(())
+}
+
+fn sources(reloader: &Reloader) -> HashSet<PathBuf> {
+    reloader
+        .config()
+        .read()
+        .unwrap()
+        .sources
+        .iter()
+        .map(|p| absolute(p))
+        .collect()
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_owned())
+}
+
+fn is_relevant(event: &notify::Event, sources: &HashSet<PathBuf>) -> bool {
+    use notify::EventKind;
+    matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    ) && event.paths.iter().any(|p| sources.contains(&absolute(p)))
+}
+
+/// Watch the directories containing `sources`, returning whether all of them are now watched.
+fn update_watches_or_log(
+    watcher: &mut impl Watcher,
+    watched: &mut HashSet<PathBuf>,
+    sources: &HashSet<PathBuf>,
+) -> bool {
+    match update_watches(watcher, watched, sources) {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!(error = ?e, retry_in = ?WATCH_RETRY_INTERVAL, "failed to update configuration watches");
+            false
+        }
+    }
+}
+
+fn update_watches(
+    watcher: &mut impl Watcher,
+    watched: &mut HashSet<PathBuf>,
+    sources: &HashSet<PathBuf>,
+) -> notify::Result<()> {
+    let dirs = sources
+        .iter()
+        .filter_map(|p| p.parent())
+        .map(Path::to_owned)
+        .collect::<HashSet<_>>();
+    // `watched` is updated as we go, so that a failure part way through can be retried
+    for stale in watched.difference(&dirs).cloned().collect::<Vec<_>>() {
+        watched.remove(&stale);
+        watcher.unwatch(&stale)?;
+    }
+    for new in dirs.difference(watched).cloned().collect::<Vec<_>>() {
+        watcher.watch(&new, RecursiveMode::NonRecursive)?;
+        watched.insert(new);
+    }
+    Ok(())
+}
@@ -0,0 +1,533 @@
+//! Verifying & storing received Webmentions.
+
+use super::handler::unescape;
+use crate::config::routes::Webmention;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::{header, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+/// The most redirects followed when fetching a source.
+const MAX_REDIRECTS: usize = 5;
+/// The most sources fetched at once.
+const MAX_VERIFYING: usize = 4;
+/// The most mentions waiting to be verified; more are refused until some have been.
+const MAX_QUEUED: usize = 256;
+
+lazy_static::lazy_static! {
+    static ref TLS: TlsConnector = {
+        let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let cfg = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("default protocol versions are supported")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(cfg))
+    };
+    /// Held while changing stored mentions, so that concurrent updates aren't lost.
+    static ref STORE: tokio::sync::Mutex<()> = Default::default();
+    /// Mentions waiting to be (or being) verified, by source & target.
+    static ref QUEUED: parking_lot::Mutex<HashSet<(String, String)>> = Default::default();
+    static ref VERIFYING: tokio::sync::Semaphore = tokio::sync::Semaphore::new(MAX_VERIFYING);
+}
+
+/// The result of [enqueue]ing a mention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Queued {
+    Added,
+    /// The same mention is already waiting to be verified.
+    Duplicate,
+    /// Too many mentions are waiting to be verified.
+    Full,
+}
+
+/// Queue the mention of `target` by `source` to be verified in the background, a few at a time.
+pub fn enqueue(cfg: &Webmention, state_dir: &Path, source: Url, target: Url) -> Queued {
+    let key = (source.to_string(), target.to_string());
+    {
+        let mut queued = QUEUED.lock();
+        if queued.contains(&key) {
+            return Queued::Duplicate;
+        }
+        if queued.len() >= MAX_QUEUED {
+            return Queued::Full;
+        }
+        queued.insert(key.clone());
+    }
+    let (cfg, state_dir) = (cfg.clone(), state_dir.to_owned());
+    tokio::task::spawn(async move {
+        let _permit = VERIFYING.acquire().await;
+        if let Err(e) = verify(&cfg, &state_dir, &source, target.as_str()).await {
+            tracing::info!(%source, %target, error = %e, "failed to verify webmention");
+        }
+        QUEUED.lock().remove(&key);
+    });
+    Queued::Added
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Hyper(#[from] hyper::Error),
+    #[error(transparent)]
+    Http(#[from] hyper::http::Error),
+    #[error("invalid source URL: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("source URLs must be `http` or `https` URLs with a host")]
+    UnsupportedUrl,
+    #[error("source is on a private network")]
+    Private,
+    #[error("source redirected too many times")]
+    TooManyRedirects,
+    #[error("source is larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("timed out fetching source")]
+    Timeout,
+}
+
+/// A verified mention of a page.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Mention {
+    pub source: String,
+    /// The source's `<title>`, if it's an HTML page with one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// When the mention was last verified, in RFC 3339 format.
+    pub verified: String,
+}
+
+/// The mentions of one page, as stored.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Mentions {
+    target: String,
+    mentions: Vec<Mention>,
+}
+
+fn store_path(state_dir: &Path, target: &str) -> PathBuf {
+    state_dir
+        .join("webmentions")
+        .join(format!("{}.json", blake3::hash(target.as_bytes()).to_hex()))
+}
+
+/// The stored mentions of `target`, oldest first.
+pub fn mentions_of(state_dir: &Path, target: &str) -> std::io::Result<Vec<Mention>> {
+    match std::fs::read(store_path(state_dir, target)) {
+        Ok(json) => Ok(serde_json::from_slice::<Mentions>(&json)?.mentions),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Add, update, or (if `mention` is `None`) remove the mention of `target` by `source`.
+async fn store(
+    state_dir: &Path,
+    target: &str,
+    source: &str,
+    mention: Option<Mention>,
+) -> std::io::Result<()> {
+    let _guard = STORE.lock().await;
+    let path = store_path(state_dir, target);
+    let mut stored = match tokio::fs::read(&path).await {
+        Ok(json) => serde_json::from_slice::<Mentions>(&json)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Mentions {
+            target: target.to_owned(),
+            mentions: Vec::new(),
+        },
+        Err(e) => return Err(e),
+    };
+    let existing = stored.mentions.iter().position(|m| m.source == source);
+    match (existing, mention) {
+        (Some(i), Some(mention)) => stored.mentions[i] = mention,
+        (None, Some(mention)) => stored.mentions.push(mention),
+        (Some(i), None) => {
+            stored.mentions.remove(i);
+        }
+        (None, None) => return Ok(()),
+    }
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    // write to a temporary file first, so that readers never see a partially-written file
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(&stored)?).await?;
+    tokio::fs::rename(&tmp, &path).await
+}
+
+/// Verify that `source` links to `target`, then store (or, if the source has gone away, remove)
+/// the mention.
+#[tracing::instrument(skip_all, fields(%source, target))]
+pub async fn verify(
+    cfg: &Webmention,
+    state_dir: &Path,
+    source: &Url,
+    target: &str,
+) -> Result<(), VerifyError> {
+    let deadline = Duration::from_millis(cfg.timeout_ms);
+    let (status, content_type, body) = tokio::time::timeout(deadline, fetch(cfg, source))
+        .await
+        .map_err(|_| VerifyError::Timeout)??;
+    if status == StatusCode::GONE || status == StatusCode::NOT_FOUND {
+        tracing::info!(%status, "webmention source is gone; removing mention");
+        return Ok(store(state_dir, target, source.as_str(), None).await?);
+    }
+    if !status.is_success() {
+        tracing::info!(%status, "failed to fetch webmention source");
+        return Ok(());
+    }
+
+    let body = String::from_utf8_lossy(&body);
+    let html = content_type.is_some_and(|t| t.starts_with("text/html"));
+    let links = match html {
+        // only count links in attributes (ex. `href`), rather than mere mentions in text
+        true => [format!("\"{target}\""), format!("'{target}'")]
+            .into_iter()
+            .chain([format!("\"{}\"", target.replace('&', "&amp;"))])
+            .any(|quoted| body.contains(&quoted)),
+        false => body.contains(target),
+    };
+    if !links {
+        tracing::info!("webmention source doesn't link to target; removing mention");
+        return Ok(store(state_dir, target, source.as_str(), None).await?);
+    }
+
+    let title = html.then(|| title(&body)).flatten();
+    let mention = Mention {
+        source: source.to_string(),
+        title,
+        verified: OffsetDateTime::now_utc()
+            .replace_nanosecond(0)
+            .unwrap()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+    };
+    store(state_dir, target, source.as_str(), Some(mention)).await?;
+    tracing::info!("verified webmention");
+    Ok(())
+}
+
+/// The text of an HTML page's `<title>`.
+fn title(html: &str) -> Option<String> {
+    // ASCII lowercasing keeps offsets
+    let lower = html.to_ascii_lowercase();
+    let start = lower.find("<title")?;
+    let start = start + lower[start..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    let title = html[start..end].trim();
+    (!title.is_empty()).then(|| unescape(title))
+}
+
+/// `GET` `url`, following redirects, and returning the final status, content type, and body.
+async fn fetch(
+    cfg: &Webmention,
+    url: &Url,
+) -> Result<(StatusCode, Option<String>, Bytes), VerifyError> {
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(VerifyError::UnsupportedUrl);
+        }
+        let host = url
+            .host_str()
+            .ok_or(VerifyError::UnsupportedUrl)?
+            .to_owned();
+        let port = url
+            .port_or_known_default()
+            .ok_or(VerifyError::UnsupportedUrl)?;
+        // connect only to the addresses checked, so that a second lookup can't return others
+        let addrs = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+            .await?
+            .collect::<Vec<SocketAddr>>();
+        if !cfg.allow_private_sources && !addrs.iter().all(|a| is_public(a.ip())) {
+            return Err(VerifyError::Private);
+        }
+        let tcp = TcpStream::connect(&addrs[..]).await?;
+        let req = Request::get(&url[url::Position::BeforePath..url::Position::AfterQuery])
+            .header(
+                header::HOST,
+                &url[url::Position::BeforeHost..url::Position::AfterPort],
+            )
+            .header(
+                header::USER_AGENT,
+                concat!("melia/", env!("CARGO_PKG_VERSION")),
+            )
+            .header(header::ACCEPT, "text/html, */*;q=0.5")
+            .body(Empty::<Bytes>::new())?;
+        let res = match url.scheme() {
+            "https" => {
+                let name = ServerName::try_from(host.trim_matches(['[', ']']).to_owned())
+                    .map_err(|_| VerifyError::UnsupportedUrl)?;
+                request(TLS.connect(name, tcp).await?, req).await?
+            }
+            _ => request(tcp, req).await?,
+        };
+
+        let status = res.status();
+        if status.is_redirection() {
+            if let Some(location) = res
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|l| l.to_str().ok())
+            {
+                url = url.join(location)?;
+                continue;
+            }
+        }
+        let content_type = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .map(str::to_ascii_lowercase);
+        let body = match Limited::new(res.into_body(), cfg.max_source_size)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(e) => match e.downcast::<hyper::Error>() {
+                Ok(e) => return Err((*e).into()),
+                Err(_) => return Err(VerifyError::TooLarge(cfg.max_source_size)),
+            },
+        };
+        return Ok((status, content_type, body));
+    }
+    Err(VerifyError::TooManyRedirects)
+}
+
+async fn request<S>(
+    io: S,
+    req: Request<Empty<Bytes>>,
+) -> Result<hyper::Response<hyper::body::Incoming>, hyper::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
+    tokio::task::spawn(async move {
+        if let Err(e) = conn.await {
+            tracing::debug!(error = ?e, "source connection failed");
+        }
+    });
+    sender.send_request(req).await
+}
+
+/// Whether `ip` is reachable from the internet, rather than being a loopback, private, or
+/// otherwise special address.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "this network" (0.0.0.0/8), & reserved (240.0.0.0/4)
+                || a == 0
+                || a >= 240
+                // shared address space (RFC 6598)
+                || (a == 100 && b & 0xc0 == 64)
+                // IETF protocol assignments (192.0.0.0/24)
+                || (a == 192 && b == 0 && c == 0)
+                // benchmarking (198.18.0.0/15)
+                || (a == 198 && b & 0xfe == 18))
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            let embedded = |hi: u16, lo: u16| {
+                is_public(IpAddr::V4(Ipv4Addr::from(
+                    (u32::from(hi) << 16) | u32::from(lo),
+                )))
+            };
+            // addresses which reach IPv4 addresses: IPv4-mapped (::ffff:0:0/96) & -compatible
+            // (::/96), NAT64 (64:ff9b::/96), & 6to4 (2002::/16)
+            if let Some(ip) = ip.to_ipv4() {
+                return is_public(IpAddr::V4(ip));
+            }
+            if s[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return embedded(s[6], s[7]);
+            }
+            if s[0] == 0x2002 {
+                return embedded(s[1], s[2]);
+            }
+            !(ip.is_loopback()
+                || ip.is_multicast()
+                // unique local (fc00::/7) & link-local (fe80::/10)
+                || s[0] & 0xfe00 == 0xfc00
+                || s[0] & 0xffc0 == 0xfe80
+                // local-use NAT64 (64:ff9b:1::/48), whose IPv4 addresses may be anywhere
+                || s[..3] == [0x64, 0xff9b, 1]
+                // Teredo (2001::/32), which hides its IPv4 address, & documentation (2001:db8::/32)
+                || s[..2] == [0x2001, 0]
+                || s[..2] == [0x2001, 0xdb8]
+                // discard-only (100::/64)
+                || s[..4] == [0x100, 0, 0, 0])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const TARGET: &str = "https://example.com/post";
+
+    /// Serve each of `responses` (whole HTTP responses) to one connection, in order, on a local
+    /// port, returning the URL to fetch them from.
+    async fn stand_in(responses: Vec<String>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            for res in responses {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    let n = conn.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                }
+                conn.write_all(res.as_bytes()).await.unwrap();
+                conn.shutdown().await.unwrap();
+            }
+        });
+        Url::parse(&format!("http://{addr}/reply")).unwrap()
+    }
+
+    fn response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\ncontent-type: text/html\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn local() -> Webmention {
+        Webmention {
+            allow_private_sources: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_adds_and_removes() {
+        let state = tempfile::tempdir().unwrap();
+        let source = stand_in(vec![
+            response(
+                "200 OK",
+                &format!("<title>A &amp; B</title><a href=\"{TARGET}\">post</a>"),
+            ),
+            response("200 OK", "<title>A &amp; B</title>"),
+            response("200 OK", &format!("<a href='{TARGET}'>post</a>")),
+            response("410 Gone", ""),
+        ])
+        .await;
+
+        verify(&local(), state.path(), &source, TARGET)
+            .await
+            .unwrap();
+        let mentions = mentions_of(state.path(), TARGET).unwrap();
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(mentions[0].source, source.as_str());
+        assert_eq!(mentions[0].title.as_deref(), Some("A & B"));
+
+        // the source no longer links to the target
+        verify(&local(), state.path(), &source, TARGET)
+            .await
+            .unwrap();
+        assert!(mentions_of(state.path(), TARGET).unwrap().is_empty());
+
+        verify(&local(), state.path(), &source, TARGET)
+            .await
+            .unwrap();
+        assert_eq!(mentions_of(state.path(), TARGET).unwrap().len(), 1);
+
+        // the source is gone
+        verify(&local(), state.path(), &source, TARGET)
+            .await
+            .unwrap();
+        assert!(mentions_of(state.path(), TARGET).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn verify_refuses_private_sources() {
+        let state = tempfile::tempdir().unwrap();
+        let source = stand_in(Vec::new()).await;
+        let res = verify(&Webmention::default(), state.path(), &source, TARGET).await;
+        assert!(matches!(res, Err(VerifyError::Private)));
+        assert!(mentions_of(state.path(), TARGET).unwrap().is_empty());
+    }
+
+    #[test]
+    fn public_addresses() {
+        let public = [
+            "1.1.1.1",
+            "100.128.0.1",
+            "198.20.0.1",
+            "2606:4700::1111",
+            "::ffff:1.1.1.1",
+            "64:ff9b::101:101",
+            "2002:101:101::1",
+        ];
+        for ip in public {
+            assert!(is_public(ip.parse().unwrap()), "{ip} is public");
+        }
+        let private = [
+            "0.1.2.3",
+            "10.0.0.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "172.16.0.1",
+            "192.0.0.8",
+            "192.0.2.1",
+            "192.168.1.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::127.0.0.1",
+            "::ffff:10.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a00:1",
+            "64:ff9b:1::1",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+            "2001::1",
+            "2001:db8::1",
+            "100::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "ff02::1",
+            "ff0e::1",
+        ];
+        for ip in private {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} isn't public");
+        }
+    }
+}
@@ -13,9 +13,15 @@ pub(crate) fn collect_systemd_fds() -> Result<Vec<SystemdSocket>, SystemdError>
         // `listen_fds()` works as expected)
         env::set_var("LISTEN_PID", unsafe { libc::getpid() }.to_string());
     }
+    // `listen_fds` unsets the environment, so the names have to be read first
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
     let mut res = vec![];
     for fd in daemon::listen_fds(true)?.iter() {
-        res.push(SystemdSocket::from_fd(fd)?);
+        res.push(SystemdSocket::from_fd(
+            fd,
+            names.next().unwrap_or_default(),
+        )?);
     }
     Ok(res)
 }
@@ -163,13 +169,20 @@ pub struct SystemdSocket {
 }
 
 impl SystemdSocket {
-    fn from_fd(fd: RawFd) -> Result<Self, SystemdError> {
+    /// `name` is the socket's `FileDescriptorName=`, which selects the protocol served on it
+    /// (ex. `https`, `gemini`, `gopher`, or `finger`); sockets without one serve HTTP.
+    fn from_fd(fd: RawFd, name: &str) -> Result<Self, SystemdError> {
+        // systemd names sockets after their unit by default
+        let name = match name {
+            name if name.is_empty() || name.ends_with(".socket") => "http",
+            name => name,
+        };
         if daemon::is_socket_inet(fd, None, None, daemon::Listening::NoListeningCheck, None)? {
             Ok(SystemdSocket {
                 fd,
                 ty: SystemdSocketType::INet,
                 format: SocketFormat::from_fd_inet(fd)?,
-                name: "http".into(),
+                name: name.into(),
             })
         } else if daemon::is_socket_unix(
             fd,
@@ -181,7 +194,7 @@ impl SystemdSocket {
                 fd,
                 ty: SystemdSocketType::Unix,
                 format: SocketFormat::from_fd_unix(fd)?,
-                name: "http".into(),
+                name: name.into(),
             })
         } else if daemon::is_fifo(fd, None::<&str>)? {
             Err(SystemdError::UnsupportedSocketType(SystemdSocketType::Fifo))
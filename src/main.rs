@@ -1,5 +1,3 @@
-#![feature(impl_trait_in_assoc_type)]
-
 use clap::Parser;
 
 pub mod cli;
@@ -39,7 +37,13 @@ fn main() -> Result<(), std::io::Error> {
 
     tracing::debug!("cli argument values: {:?}", &args);
 
-    let cfg = config::Config::from_args(&args).unwrap();
+    let cfg = match config::Config::from_args(&args) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to load configuration");
+            std::process::exit(1);
+        }
+    };
 
     tracing::debug!("config values: {:?}", &cfg);
 
@@ -48,11 +52,53 @@ fn main() -> Result<(), std::io::Error> {
         .build()
         .unwrap();
 
-    match args.command.unwrap_or_default() {
-        cli::Command::Ctl { socket, command } => {
-            todo!()
-            //runtime.block_on(ctl::run(cfg, socket, command.unwrap_or_default()))
+    match args.command.clone().unwrap_or_default() {
+        cli::Command::Ctl { socket, command } => runtime
+            .block_on(ctl::run(socket, command.unwrap_or_default()))
+            .map_err(std::io::Error::other),
+        cli::Command::Config {
+            command: cli::ConfigCommand::Check,
+        } => {
+            let problems = cfg.check();
+            for problem in &problems {
+                println!("{problem}");
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+            println!("no problems found");
+            Ok(())
+        }
+        cli::Command::Config {
+            command: cli::ConfigCommand::Migrate { dry_run },
+        } => {
+            if let Err(e) = config::migrate::migrate_files(&cfg, dry_run) {
+                tracing::error!(error = %e, "failed to migrate configuration");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        cli::Command::Config {
+            command: cli::ConfigCommand::HashPassword,
+        } => {
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            let password = password.trim_end_matches(['\r', '\n']);
+            if password.is_empty() {
+                tracing::error!("no password given");
+                std::process::exit(1);
+            }
+            match daemon::indieauth::hash_password(password) {
+                Ok(hash) => println!("{hash}"),
+                Err(e) => {
+                    tracing::error!(error = %e, "failed to hash password");
+                    std::process::exit(1);
+                }
+            }
+            Ok(())
+        }
+        cli::Command::Daemon { .. } => {
+            runtime.block_on(daemon::run(cfg, std::sync::Arc::new(args)))
         }
-        cli::Command::Daemon { .. } => runtime.block_on(daemon::run(cfg)),
     }
 }